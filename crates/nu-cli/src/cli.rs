@@ -24,7 +24,7 @@ use rustyline::{
 };
 use std::error::Error;
 use std::iter::Iterator;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 pub fn search_paths() -> Vec<std::path::PathBuf> {
     use std::env;
@@ -60,6 +60,20 @@ pub fn search_paths() -> Vec<std::path::PathBuf> {
 pub async fn run_script_file(
     file_contents: String,
     redirect_stdin: bool,
+) -> Result<(), Box<dyn Error>> {
+    run_script(file_contents, None, redirect_stdin).await
+}
+
+/// Run the script at `path`, so that the scripts it sources are found relative to it
+pub async fn run_script_path(path: &Path, redirect_stdin: bool) -> Result<(), Box<dyn Error>> {
+    let file_contents = std::fs::read_to_string(path)?;
+    run_script(file_contents, Some(path), redirect_stdin).await
+}
+
+async fn run_script(
+    file_contents: String,
+    path: Option<&Path>,
+    redirect_stdin: bool,
 ) -> Result<(), Box<dyn Error>> {
     let mut syncer = EnvironmentSyncer::new();
     let mut context = create_default_context(false)?;
@@ -80,6 +94,9 @@ pub async fn run_script_file(
 
     let _ = run_startup_commands(&mut context, &config).await;
 
+    if let Some(path) = path {
+        context.enter_script(path);
+    }
     run_script_standalone(file_contents, redirect_stdin, &context, true).await?;
 
     Ok(())
@@ -99,9 +116,29 @@ fn convert_rustyline_result_to_string(input: Result<String, ReadlineError>) -> L
     }
 }
 
-/// The entry point for the CLI. Will register all known internal commands, load experimental commands, load plugins, then prepare the prompt and line reader for input.
+/// How an interactive session ended, so that whoever is running it can decide what comes next
+#[cfg(feature = "rustyline-support")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEnd {
+    Exit,
+    /// A command asked for a fresh session, with config and plugins loaded again
+    Restart,
+}
+
+/// The entry point for the CLI. Runs sessions until one ends without asking for a restart.
 #[cfg(feature = "rustyline-support")]
 pub async fn cli(mut context: EvaluationContext) -> Result<(), Box<dyn Error>> {
+    while cli_session(context).await? == SessionEnd::Restart {
+        context = create_default_context(true)?;
+        let _ = register_plugins(&mut context);
+    }
+
+    Ok(())
+}
+
+/// Run a single interactive session. Will register all known internal commands, load experimental commands, load plugins, then prepare the prompt and line reader for input.
+#[cfg(feature = "rustyline-support")]
+pub async fn cli_session(mut context: EvaluationContext) -> Result<SessionEnd, Box<dyn Error>> {
     let mut syncer = EnvironmentSyncer::new();
     let configuration = syncer.get_config();
 
@@ -158,14 +195,22 @@ pub async fn cli(mut context: EvaluationContext) -> Result<(), Box<dyn Error>> {
             continue;
         }
 
-        let cwd = context.shell_manager.path();
+        let cwd = context.shell_manager.location();
 
         let colored_prompt = {
-            if let Some(prompt) = configuration.var("prompt") {
-                let prompt_line = prompt.as_string()?;
-
+            if let Some(prompt) = context.prompt().or_else(|| configuration.var("prompt")) {
                 context.scope.enter_scope();
-                let (prompt_block, err) = nu_parser::parse(&prompt_line, 0, &context.scope);
+                let (prompt_line, prompt_block, err) = match prompt.value {
+                    UntaggedValue::Block(captured) => {
+                        context.scope.add_vars(&captured.captured.entries);
+                        (String::new(), captured.block, None)
+                    }
+                    _ => {
+                        let prompt_line = prompt.as_string()?;
+                        let (prompt_block, err) = nu_parser::parse(&prompt_line, 0, &context.scope);
+                        (prompt_line, prompt_block, err)
+                    }
+                };
 
                 if err.is_some() {
                     use crate::git::current_branch;
@@ -249,7 +294,8 @@ pub async fn cli(mut context: EvaluationContext) -> Result<(), Box<dyn Error>> {
         }
 
         let line = match convert_rustyline_result_to_string(readline) {
-            LineResult::Success(_) => {
+            LineResult::Success(line) => {
+                context.set_pending_history(history_path.clone(), line);
                 process_script(
                     &session_text[line_start..],
                     &context,
@@ -330,6 +376,13 @@ pub async fn cli(mut context: EvaluationContext) -> Result<(), Box<dyn Error>> {
                 }
             }
 
+            LineResult::Restart(line) => {
+                rl.add_history_entry(&line);
+                let _ = rl.save_history(&history_path);
+                context.maybe_print_errors(Text::from(session_text.clone()));
+                return Ok(SessionEnd::Restart);
+            }
+
             LineResult::Break => {
                 break;
             }
@@ -340,7 +393,7 @@ pub async fn cli(mut context: EvaluationContext) -> Result<(), Box<dyn Error>> {
     // we are ok if we can not save history
     let _ = rl.save_history(&history_path);
 
-    Ok(())
+    Ok(SessionEnd::Exit)
 }
 
 pub fn register_plugins(context: &mut EvaluationContext) -> Result<(), ShellError> {
@@ -599,7 +652,10 @@ pub async fn parse_and_eval(line: &str, ctx: &EvaluationContext) -> Result<Strin
 
     let input_stream = InputStream::empty();
     let env = ctx.get_env();
-    ctx.scope.add_env(env);
+    if let Err(err) = ctx.scope.add_env(env) {
+        ctx.scope.exit_scope();
+        return Err(err);
+    }
 
     let result = run_block(&classified_block, ctx, input_stream).await;
     ctx.scope.exit_scope();
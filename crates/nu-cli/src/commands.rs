@@ -42,6 +42,7 @@ pub(crate) mod enter;
 pub(crate) mod every;
 pub(crate) mod exec;
 pub(crate) mod exit;
+pub(crate) mod export;
 pub(crate) mod first;
 pub(crate) mod flatten;
 pub(crate) mod format;
@@ -94,6 +95,7 @@ pub(crate) mod range;
 pub(crate) mod reduce;
 pub(crate) mod reject;
 pub(crate) mod rename;
+pub(crate) mod repl;
 pub(crate) mod reverse;
 pub(crate) mod rm;
 pub(crate) mod run_external;
@@ -181,6 +183,7 @@ pub(crate) use enter::Enter;
 pub(crate) use every::Every;
 pub(crate) use exec::Exec;
 pub(crate) use exit::Exit;
+pub(crate) use export::Export;
 pub(crate) use first::First;
 pub(crate) use flatten::Command as Flatten;
 pub(crate) use format::{FileSize, Format};
@@ -242,6 +245,7 @@ pub(crate) use range::Range;
 pub(crate) use reduce::Reduce;
 pub(crate) use reject::Reject;
 pub(crate) use rename::Rename;
+pub(crate) use repl::Repl;
 pub(crate) use reverse::Reverse;
 pub(crate) use rm::Remove;
 pub(crate) use run_external::RunExternalCommand;
@@ -34,6 +34,7 @@ impl WholeStreamCommand for Command {
             host: args.host,
             ctrl_c: args.ctrl_c,
             current_errors: args.current_errors,
+            session: args.session,
             name: args.call_info.name_tag,
         })
         .await
@@ -61,6 +62,7 @@ pub struct RunnableContextWithoutInput {
     pub current_errors: Arc<Mutex<Vec<ShellError>>>,
     pub ctrl_c: Arc<AtomicBool>,
     pub scope: Scope,
+    pub session: Session,
     pub name: Tag,
 }
 
@@ -72,6 +74,7 @@ impl RunnableContextWithoutInput {
             ctrl_c: context.ctrl_c,
             current_errors: context.current_errors,
             scope: context.scope,
+            session: context.session,
             name: context.name,
         };
         (context.input, new_context)
@@ -88,7 +91,10 @@ pub async fn autoview(context: RunnableContext) -> Result<OutputStream, ShellErr
     let pivot_mode = configuration.pivot_mode();
 
     let (mut input_stream, context) = RunnableContextWithoutInput::convert(context);
-    let term_width = context.host.lock().width();
+    let term_width = context
+        .scope
+        .render_width()
+        .unwrap_or_else(|| context.host.lock().width());
     let color_hm = get_color_config();
 
     if let Some(x) = input_stream.next().await {
@@ -320,6 +326,7 @@ fn create_default_command_args(context: &RunnableContextWithoutInput) -> RawComm
             name_tag: context.name.clone(),
         },
         scope: Scope::new(),
+        session: context.session.clone(),
     }
 }
 
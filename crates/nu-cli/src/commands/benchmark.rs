@@ -77,7 +77,7 @@ async fn benchmark(raw_args: CommandArgs) -> Result<OutputStream, ShellError> {
     let env = scope.get_env_vars();
     let name = generate_free_name(&env);
 
-    scope.add_env_var(name, generate_random_env_value());
+    scope.add_env_var(name, generate_random_env_value())?;
 
     let start_time = Instant::now();
 
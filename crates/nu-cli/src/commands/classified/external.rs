@@ -171,7 +171,7 @@ fn spawn(
     trace!(target: "nu::run::external", "cwd = {:?}", &path);
 
     process.env_clear();
-    process.envs(scope.get_env_vars());
+    process.envs(scope.env_for_process());
 
     // We want stdout regardless of what
     // we are doing ($it case or pipe stdin)
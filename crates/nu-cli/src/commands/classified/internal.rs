@@ -1,11 +1,27 @@
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Stdio};
 use std::sync::atomic::Ordering;
+use std::time::Instant;
 
-use crate::commands::UnevaluatedCallInfo;
+use crate::commands::classified::block::run_block;
+use crate::commands::sleep::SleepFuture;
+use crate::commands::{Command, UnevaluatedCallInfo};
+use crate::evaluate::scope::{ENV_PATH_SEPARATOR, LAST_EXIT_CODE};
 use crate::prelude::*;
+use crate::shell::shell::Shell;
 use log::{log_enabled, trace};
 use nu_errors::ShellError;
-use nu_protocol::hir::{ExternalRedirection, InternalCommand};
-use nu_protocol::{CommandAction, Primitive, ReturnSuccess, UntaggedValue, Value};
+use nu_plugin::jsonrpc::PLUGIN_PROTOCOL_VERSION;
+use nu_protocol::hir::{Block, ExternalRedirection, InternalCommand, SpannedExpression};
+use nu_protocol::{
+    CommandAction, Primitive, ReturnSuccess, ShellErrorValue, ShellTypeName, TaggedDictBuilder,
+    Type, UntaggedValue, Value,
+};
+use nu_source::Tagged;
+
+/// The label of the frames started by `PushEnvFrame`, so `PopEnvFrame` only leaves those
+const ENV_FRAME_LABEL: &str = "env frame";
 
 pub(crate) async fn run_internal_command(
     command: InternalCommand,
@@ -18,8 +34,40 @@ pub(crate) async fn run_internal_command(
     }
 
     let objects: InputStream = trace_stream!(target: "nu::trace_stream::internal", "input" = input);
+    let objects = if context.trace.load(Ordering::SeqCst) {
+        let host = context.host.clone();
+        let name = command.name.clone();
+        InputStream::from_stream(objects.inspect(move |item| {
+            let line = format!("{} input = {}", name, item.plain_string(70));
+            host.lock().stderr(&line);
+        }))
+    } else {
+        objects
+    };
+
+    // Remember where the input came from so errors can point back at it. Only the first item is looked at.
+    let origin: Arc<parking_lot::Mutex<Option<AnchorLocation>>> =
+        Arc::new(parking_lot::Mutex::new(None));
+    let objects = {
+        let origin = origin.clone();
+        let mut first = true;
+        InputStream::from_stream(objects.map(move |item| {
+            if first {
+                first = false;
+                *origin.lock() = item.tag.anchor();
+            }
+            item
+        }))
+    };
 
-    let internal_command = context.scope.expect_command(&command.name);
+    let internal_command = context
+        .scope
+        .expect_command_spanned(&command.name, command.name_span)?;
+    let objects = if context.check_input_types.load(Ordering::SeqCst) {
+        check_input_type(&internal_command, objects, command.name_span).await?
+    } else {
+        objects
+    };
 
     if command.name == "autoenv untrust" {
         context
@@ -27,103 +75,178 @@ pub(crate) async fn run_internal_command(
             .store(true, Ordering::SeqCst);
     }
 
+    let started = Instant::now();
+    // A panicking command (often a buggy plugin) is reported by name instead of taking the shell down
+    let run = context.run_command(
+        internal_command,
+        Tag::unknown_anchor(command.name_span),
+        command.args.clone(),
+        objects,
+    );
+    let result = match futures::FutureExt::catch_unwind(AssertUnwindSafe(run)).await {
+        Ok(result) => result?,
+        Err(payload) => {
+            let err = panic_error(&command.name, command.name_span, payload);
+            return Ok(catch_or_report(context, err, command.name_span));
+        }
+    };
     let result = {
-        context
-            .run_command(
-                internal_command?,
-                Tag::unknown_anchor(command.name_span),
-                command.args.clone(),
-                objects,
-            )
-            .await?
+        let name = command.name.clone();
+        let span = command.name_span;
+        OutputStream::new(
+            AssertUnwindSafe(result)
+                .catch_unwind()
+                .map(move |item| match item {
+                    Ok(item) => item,
+                    Err(payload) => Err(panic_error(&name, span, payload)),
+                }),
+        )
     };
 
     let head = Arc::new(command.args.head.clone());
+    let profiler = if context.is_profiling() {
+        Some((context.clone(), command.name.clone()))
+    } else {
+        None
+    };
+    let buffered = context.buffer_output.load(Ordering::SeqCst);
+    let exit_requested = context.exit_requested.clone();
     let context = context.clone();
     let command = Arc::new(command);
 
-    Ok(InputStream::from_stream(
+    let stream = InputStream::from_stream(
         result
             .then(move |item| {
                 let head = head.clone();
                 let command = command.clone();
                 let context = context.clone();
+                let origin = origin.clone();
                 async move {
+                    let dry_run_description = match &item {
+                        Ok(ReturnSuccess::Action(action))
+                            if context.dry_run.load(Ordering::SeqCst) =>
+                        {
+                            describe_side_effect(action)
+                        }
+                        _ => None,
+                    };
+                    if let Some(description) = dry_run_description {
+                        return InputStream::one(
+                            UntaggedValue::string(description)
+                                .into_value(Tag::unknown_anchor(command.name_span)),
+                        );
+                    }
+
                     match item {
                         Ok(ReturnSuccess::Action(action)) => match action {
                             CommandAction::ChangePath(path) => {
-                                context.shell_manager.set_path(path);
+                                match context.change_path(path) {
+                                    Ok(()) => InputStream::empty(),
+                                    Err(err) => catch_or_report(&context, err, command.name_span),
+                                }
+                            }
+                            CommandAction::Exit => {
+                                context.exit();
                                 InputStream::empty()
                             }
-                            CommandAction::Exit => std::process::exit(0), // TODO: save history.txt
                             CommandAction::Error(err) => {
                                 context.error(err);
                                 InputStream::empty()
                             }
+                            CommandAction::AutoConvert(tagged_contents, _)
+                            | CommandAction::AutoConvertWith {
+                                value: tagged_contents,
+                                ..
+                            }
+                            | CommandAction::AutoConvertCandidates(tagged_contents, _)
+                                if !context.auto_convert.load(Ordering::SeqCst) =>
+                            {
+                                InputStream::one(tagged_contents)
+                            }
+                            CommandAction::ToggleAutoConvert(enabled) => {
+                                context.auto_convert.store(enabled, Ordering::SeqCst);
+                                InputStream::empty()
+                            }
+                            CommandAction::SetErrorVerbosity(verbosity) => {
+                                context.set_error_verbosity(verbosity);
+                                InputStream::empty()
+                            }
+                            CommandAction::SetTrace(enabled) => {
+                                context.trace.store(enabled, Ordering::SeqCst);
+                                InputStream::empty()
+                            }
                             CommandAction::AutoConvert(tagged_contents, extension) => {
-                                let contents_tag = tagged_contents.tag.clone();
-                                let command_name = format!("from {}", extension);
-                                let command = command.clone();
-                                if let Some(converter) = context.scope.get_command(&command_name) {
-                                    let new_args = RawCommandArgs {
-                                        host: context.host.clone(),
-                                        ctrl_c: context.ctrl_c.clone(),
-                                        current_errors: context.current_errors.clone(),
-                                        shell_manager: context.shell_manager.clone(),
-                                        call_info: UnevaluatedCallInfo {
-                                            args: nu_protocol::hir::Call {
-                                                head: (&*head).clone(),
-                                                positional: None,
-                                                named: None,
-                                                span: Span::unknown(),
-                                                external_redirection: ExternalRedirection::Stdout,
-                                            },
-                                            name_tag: Tag::unknown_anchor(command.name_span),
-                                        },
-                                        scope: context.scope.clone(),
+                                match context.scope.get_command(&format!("from {}", extension)) {
+                                    Some(converter) => {
+                                        auto_convert(
+                                            converter,
+                                            &context,
+                                            (*head).clone(),
+                                            command.name_span,
+                                            tagged_contents,
+                                        )
+                                        .await
+                                    }
+                                    None => InputStream::one(tagged_contents),
+                                }
+                            }
+                            CommandAction::AutoConvertWith { value, converter } => {
+                                match context.scope.get_command(&converter) {
+                                    Some(converter) => {
+                                        auto_convert(
+                                            converter,
+                                            &context,
+                                            (*head).clone(),
+                                            command.name_span,
+                                            value,
+                                        )
+                                        .await
+                                    }
+                                    None => catch_or_report(
+                                        &context,
+                                        ShellError::labeled_error(
+                                            format!("Can't find the converter '{}'", converter),
+                                            "converter not found",
+                                            command.name_span,
+                                        ),
+                                        command.name_span,
+                                    ),
+                                }
+                            }
+                            CommandAction::AutoConvertCandidates(tagged_contents, extensions) => {
+                                let mut failures = vec![];
+                                for extension in extensions {
+                                    let command_name = format!("from {}", extension);
+                                    let converter = match context.scope.get_command(&command_name) {
+                                        Some(converter) => converter,
+                                        None => continue,
                                     };
-                                    let result = converter
-                                        .run(new_args.with_input(vec![tagged_contents]))
-                                        .await;
-
-                                    match result {
-                                        Ok(mut result) => {
-                                            let result_vec: Vec<Result<ReturnSuccess, ShellError>> =
-                                                result.drain_vec().await;
-
-                                            let mut output = vec![];
-                                            for res in result_vec {
-                                                match res {
-                                                    Ok(ReturnSuccess::Value(Value {
-                                                        value: UntaggedValue::Table(list),
-                                                        ..
-                                                    })) => {
-                                                        for l in list {
-                                                            output.push(Ok(l));
-                                                        }
-                                                    }
-                                                    Ok(ReturnSuccess::Value(Value {
-                                                        value,
-                                                        ..
-                                                    })) => {
-                                                        output
-                                                            .push(Ok(value
-                                                                .into_value(contents_tag.clone())));
-                                                    }
-                                                    Err(e) => output.push(Err(e)),
-                                                    _ => {}
-                                                }
-                                            }
 
-                                            futures::stream::iter(output).to_input_stream()
-                                        }
-                                        Err(err) => {
-                                            context.error(err);
-                                            InputStream::empty()
+                                    match run_converter(
+                                        converter,
+                                        &context,
+                                        (*head).clone(),
+                                        command.name_span,
+                                        tagged_contents.clone(),
+                                    )
+                                    .await
+                                    {
+                                        Ok(output) if output.iter().all(Result::is_ok) => {
+                                            return futures::stream::iter(output).to_input_stream();
                                         }
+                                        Ok(output) => failures
+                                            .extend(output.into_iter().filter_map(Result::err)),
+                                        Err(err) => failures.push(err),
                                     }
-                                } else {
+                                }
+
+                                if failures.is_empty() {
                                     InputStream::one(tagged_contents)
+                                } else {
+                                    for failure in failures {
+                                        context.error(failure);
+                                    }
+                                    InputStream::empty()
                                 }
                             }
                             CommandAction::EnterHelpShell(value) => match value {
@@ -143,6 +266,7 @@ pub(crate) async fn run_internal_command(
                                             }
                                         },
                                     ));
+                                    context.enter_shell_frame();
                                     InputStream::from_stream(futures::stream::iter(vec![]))
                                 }
                                 _ => {
@@ -155,6 +279,7 @@ pub(crate) async fn run_internal_command(
                                             }
                                         },
                                     ));
+                                    context.enter_shell_frame();
                                     InputStream::from_stream(futures::stream::iter(vec![]))
                                 }
                             },
@@ -162,9 +287,35 @@ pub(crate) async fn run_internal_command(
                                 context
                                     .shell_manager
                                     .insert_at_current(Box::new(ValueShell::new(value)));
+                                context.enter_shell_frame();
                                 InputStream::from_stream(futures::stream::iter(vec![]))
                             }
-                            CommandAction::EnterShell(location) => {
+                            CommandAction::PushValueOntoShellStack(value) => {
+                                let from = context.shell_manager.current_id();
+                                context
+                                    .shell_manager
+                                    .insert_at_current(Box::new(ValueShell::new_inspect(value, from)));
+                                context.enter_shell_frame();
+                                InputStream::empty()
+                            }
+                            CommandAction::EnterShell {
+                                location,
+                                isolate_env,
+                            } => {
+                                let location = if Path::new(&location).is_relative() {
+                                    // Inside a value shell, the path is into the value, not a directory
+                                    context
+                                        .shell_manager
+                                        .filesystem_path()
+                                        .map(PathBuf::from)
+                                        .or_else(|| std::env::current_dir().ok())
+                                        .unwrap_or_default()
+                                        .join(location)
+                                        .display()
+                                        .to_string()
+                                } else {
+                                    location
+                                };
                                 context.shell_manager.insert_at_current(Box::new(
                                     match FilesystemShell::with_location(location) {
                                         Ok(v) => v,
@@ -174,29 +325,188 @@ pub(crate) async fn run_internal_command(
                                         }
                                     },
                                 ));
+                                if isolate_env {
+                                    context.enter_isolated_shell_frame();
+                                } else {
+                                    context.enter_shell_frame();
+                                }
                                 InputStream::from_stream(futures::stream::iter(vec![]))
                             }
+                            CommandAction::EnterShellAt { root, subpath } => {
+                                match filesystem_shell_at(
+                                    &context,
+                                    &root,
+                                    &subpath,
+                                    command.name_span,
+                                ) {
+                                    Ok(shell) => {
+                                        context.shell_manager.insert_at_current(Box::new(shell));
+                                        context.enter_shell_frame();
+                                    }
+                                    Err(err) => context.error(err),
+                                }
+                                InputStream::empty()
+                            }
+                            CommandAction::EnterShellFromValue(value) => {
+                                match filesystem_shell_from_value(&context, &value, command.name_span)
+                                {
+                                    Ok(shell) => {
+                                        context.shell_manager.insert_at_current(Box::new(shell));
+                                        context.enter_shell_frame();
+                                    }
+                                    Err(err) => context.error(err),
+                                }
+                                InputStream::empty()
+                            }
                             CommandAction::AddVariable(name, value) => {
                                 context.scope.add_var(name, value);
                                 InputStream::from_stream(futures::stream::iter(vec![]))
                             }
-                            CommandAction::AddEnvVariable(name, value) => {
-                                context.scope.add_env_var(name, value);
+                            CommandAction::AddVariables(vars) => {
+                                context.scope.add_vars(&vars.into_iter().collect());
                                 InputStream::from_stream(futures::stream::iter(vec![]))
                             }
-                            CommandAction::SourceScript(filename) => {
-                                let contents = std::fs::read_to_string(&filename.item);
+                            CommandAction::AddEnvVariable(name, value) => {
+                                match context.scope.add_env_var(name, value) {
+                                    Ok(()) => InputStream::empty(),
+                                    Err(err) => catch_or_report(&context, err, command.name_span),
+                                }
+                            }
+                            CommandAction::SetLastExitCode(code) => {
+                                match context
+                                    .scope
+                                    .add_global_env_var(LAST_EXIT_CODE, code.to_string())
+                                {
+                                    Ok(()) => InputStream::empty(),
+                                    Err(err) => catch_or_report(&context, err, command.name_span),
+                                }
+                            }
+                            CommandAction::SetVarFromEnv { var, env } => {
+                                match context.scope.get_env_var(&env) {
+                                    Some(text) => {
+                                        let value = value_from_env_text(
+                                            &text,
+                                            Tag::unknown_anchor(command.name_span),
+                                        );
+                                        context.scope.add_var(var, value);
+                                        InputStream::empty()
+                                    }
+                                    None => catch_or_report(
+                                        &context,
+                                        ShellError::labeled_error(
+                                            format!("Missing environment variable {}", env),
+                                            "environment variable not set",
+                                            command.name_span,
+                                        ),
+                                        command.name_span,
+                                    ),
+                                }
+                            }
+                            CommandAction::SetEnvFromVar { env, var } => {
+                                match env_text_from_var(&context, &var, command.name_span) {
+                                    Ok(text) => match context.scope.add_env_var(env, text) {
+                                        Ok(()) => InputStream::empty(),
+                                        Err(err) => {
+                                            catch_or_report(&context, err, command.name_span)
+                                        }
+                                    },
+                                    Err(err) => catch_or_report(&context, err, command.name_span),
+                                }
+                            }
+                            CommandAction::SetEnvFromFile(file) => {
+                                match set_env_from_file(&context, &file) {
+                                    Ok(()) => InputStream::empty(),
+                                    Err(err) => catch_or_report(&context, err, command.name_span),
+                                }
+                            }
+                            CommandAction::SetConfigValue {
+                                path,
+                                value,
+                                persist,
+                            } => match context.set_config_value(
+                                &path,
+                                value,
+                                persist,
+                                command.name_span,
+                            ) {
+                                Ok(()) => InputStream::empty(),
+                                Err(err) => catch_or_report(&context, err, command.name_span),
+                            },
+                            CommandAction::AddEnvVariableTemplate(name, template) => {
+                                context.scope.add_env_template(name, template);
+                                InputStream::empty()
+                            }
+                            CommandAction::AppendEnvVariable {
+                                name,
+                                value,
+                                separator,
+                            } => {
+                                match context.scope.append_env_var(
+                                    &name,
+                                    &value,
+                                    separator.as_deref().unwrap_or(ENV_PATH_SEPARATOR),
+                                ) {
+                                    Ok(()) => InputStream::empty(),
+                                    Err(err) => catch_or_report(&context, err, command.name_span),
+                                }
+                            }
+                            CommandAction::SourceScript { filename, summary } => {
+                                let path = match context.locate_script(&filename.item) {
+                                    Ok(path) => path,
+                                    Err(tried) => {
+                                        let tried: Vec<String> = tried
+                                            .iter()
+                                            .map(|path| path.display().to_string())
+                                            .collect();
+                                        context.error(ShellError::labeled_error(
+                                            format!(
+                                                "Can't find {} to source, looked for: {}",
+                                                filename.item,
+                                                tried.join(", ")
+                                            ),
+                                            "script not found",
+                                            filename.span(),
+                                        ));
+                                        return InputStream::empty();
+                                    }
+                                };
+                                let contents = std::fs::read_to_string(&path);
                                 match contents {
                                     Ok(contents) => {
+                                        let before = context.scope.stats().visible;
+                                        context.enter_script(&path);
                                         let result = crate::script::run_script_standalone(
                                             contents, true, &context, false,
                                         )
                                         .await;
+                                        context.exit_script();
 
                                         if let Err(err) = result {
                                             context.error(err.into());
                                         }
-                                        InputStream::empty()
+                                        if !summary {
+                                            return InputStream::empty();
+                                        }
+
+                                        // Tell the user what the script brought into scope
+                                        let after = context.scope.stats().visible;
+                                        let tag = Tag::unknown_anchor(command.name_span);
+                                        InputStream::one(TaggedDictBuilder::build(&tag, |row| {
+                                            row.insert_untagged(
+                                                "commands",
+                                                UntaggedValue::int(
+                                                    after.commands.saturating_sub(before.commands)
+                                                        as i64,
+                                                ),
+                                            );
+                                            row.insert_untagged(
+                                                "aliases",
+                                                UntaggedValue::int(
+                                                    after.aliases.saturating_sub(before.aliases)
+                                                        as i64,
+                                                ),
+                                            );
+                                        }))
                                     }
                                     Err(_) => {
                                         context.error(ShellError::labeled_error(
@@ -212,14 +522,25 @@ pub(crate) async fn run_internal_command(
                             CommandAction::AddPlugins(path) => {
                                 match crate::plugin::scan(vec![std::path::PathBuf::from(path)]) {
                                     Ok(plugins) => {
-                                        context.add_commands(
-                                            plugins
-                                                .into_iter()
-                                                .filter(|p| {
-                                                    !context.is_command_registered(p.name())
-                                                })
-                                                .collect(),
-                                        );
+                                        let mut compatible = vec![];
+                                        for plugin in plugins {
+                                            match plugin.plugin_protocol_version() {
+                                                Some(version)
+                                                    if version != PLUGIN_PROTOCOL_VERSION =>
+                                                {
+                                                    let warning = format!(
+                                                        "warning: skipped plugin '{}': it speaks plugin protocol version {}, but only version {} is supported",
+                                                        plugin.name(),
+                                                        version,
+                                                        PLUGIN_PROTOCOL_VERSION
+                                                    );
+                                                    context.with_host(|host| host.stderr(&warning));
+                                                }
+                                                _ if context.is_command_registered(plugin.name()) => {}
+                                                _ => compatible.push(plugin),
+                                            }
+                                        }
+                                        context.add_commands(compatible);
 
                                         InputStream::empty()
                                     }
@@ -237,10 +558,205 @@ pub(crate) async fn run_internal_command(
                                 context.shell_manager.next();
                                 InputStream::empty()
                             }
-                            CommandAction::LeaveShell => {
+                            CommandAction::GoToShell(index) => {
+                                if let Err(err) =
+                                    context.shell_manager.set_current(index, command.name_span)
+                                {
+                                    context.error(err);
+                                }
+                                InputStream::empty()
+                            }
+                            CommandAction::LeaveShell { keep_env } => {
+                                if let Err(err) =
+                                    context.exit_shell_frame(keep_env, command.name_span)
+                                {
+                                    return catch_or_report(&context, err, command.name_span);
+                                }
+                                // The last shell is kept when nothing may exit, so it can go on being used
+                                if context.no_exit.load(Ordering::SeqCst)
+                                    && context.shell_manager.len() == 1
+                                {
+                                    context.exit();
+                                    return InputStream::empty();
+                                }
+                                let inspected_from = context.shell_manager.inspected_from();
                                 context.shell_manager.remove_at_current();
                                 if context.shell_manager.is_empty() {
-                                    std::process::exit(0); // TODO: save history.txt
+                                    context.exit();
+                                } else if let Some(from) = inspected_from {
+                                    let returned = match context.shell_manager.index_of(from) {
+                                        Some(index) => context
+                                            .shell_manager
+                                            .set_current(index, command.name_span),
+                                        None => Err(ShellError::labeled_error(
+                                            "Can't return to the shell this was inspected from",
+                                            "that shell has been left",
+                                            command.name_span,
+                                        )),
+                                    };
+                                    if let Err(err) = returned {
+                                        context.error(err);
+                                    }
+                                }
+                                InputStream::empty()
+                            }
+                            CommandAction::PrintRaw(text) => {
+                                match command.args.external_redirection {
+                                    ExternalRedirection::Stdout
+                                    | ExternalRedirection::StdoutAndStderr => InputStream::one(
+                                        UntaggedValue::string(text).into_untagged_value(),
+                                    ),
+                                    _ => {
+                                        context.with_host(|host| host.stdout_raw(&text));
+                                        InputStream::empty()
+                                    }
+                                }
+                            }
+                            CommandAction::DefineAlias { name, replacement } => {
+                                context.scope.add_alias(&name, replacement);
+                                InputStream::empty()
+                            }
+                            CommandAction::RemoveAlias(name) => {
+                                if !context.scope.remove_alias(&name) {
+                                    context.error(ShellError::labeled_error(
+                                        format!("Can't remove alias '{}'", name),
+                                        "no such alias, or it is in a read-only scope",
+                                        command.name_span,
+                                    ));
+                                }
+                                InputStream::empty()
+                            }
+                            CommandAction::RunExternalInBackground {
+                                command: name,
+                                args,
+                            } => match spawn_in_background(&context, &name, &args) {
+                                Ok(child) => InputStream::one(
+                                    UntaggedValue::int(context.add_job(child))
+                                        .into_value(Tag::unknown_anchor(command.name_span)),
+                                ),
+                                Err(err) => {
+                                    context.error(ShellError::labeled_error(
+                                        format!("Could not start '{}': {}", name, err),
+                                        "failed to start in the background",
+                                        command.name_span,
+                                    ));
+                                    InputStream::empty()
+                                }
+                            },
+                            CommandAction::DumpScope => InputStream::one(
+                                context
+                                    .scope
+                                    .dump(Tag::unknown_anchor(command.name_span)),
+                            ),
+                            CommandAction::PushEnvFrame => {
+                                context.scope.enter_named_scope(ENV_FRAME_LABEL);
+                                InputStream::empty()
+                            }
+                            CommandAction::PopEnvFrame => {
+                                match context.scope.exit_labeled_scope(
+                                    ENV_FRAME_LABEL,
+                                    false,
+                                    command.name_span,
+                                ) {
+                                    Ok(true) => InputStream::empty(),
+                                    Ok(false) => catch_or_report(
+                                        &context,
+                                        ShellError::labeled_error(
+                                            "No env frame to pop",
+                                            "no env frame was pushed",
+                                            command.name_span,
+                                        ),
+                                        command.name_span,
+                                    ),
+                                    Err(err) => catch_or_report(&context, err, command.name_span),
+                                }
+                            }
+                            CommandAction::Restart => {
+                                context.request_restart();
+                                InputStream::empty()
+                            }
+                            CommandAction::WarnDeprecated {
+                                name,
+                                replacement,
+                                since,
+                            } => {
+                                if context.mark_deprecation_warned(&name) {
+                                    let mut warning = format!("warning: '{}' is deprecated", name);
+                                    if let Some(since) = since {
+                                        warning.push_str(&format!(" since {}", since));
+                                    }
+                                    if let Some(replacement) = replacement {
+                                        warning.push_str(&format!(", use '{}' instead", replacement));
+                                    }
+                                    context.with_host(|host| host.stderr(&warning));
+                                }
+                                InputStream::empty()
+                            }
+                            CommandAction::ListShells => InputStream::from_stream(
+                                futures::stream::iter(crate::commands::shells::shell_rows(
+                                    &context.shell_manager,
+                                    &Tag::unknown_anchor(command.name_span),
+                                )),
+                            ),
+                            CommandAction::ImportModule { path, prefix } => {
+                                match import_module(&context, &path, prefix, command.name_span)
+                                    .await
+                                {
+                                    Ok(()) => InputStream::empty(),
+                                    Err(err) => catch_or_report(&context, err, command.name_span),
+                                }
+                            }
+                            CommandAction::Benchmark(block) => {
+                                match benchmark_block(&context, &block, command.name_span).await {
+                                    Ok(elapsed) => InputStream::one(elapsed),
+                                    Err(err) => catch_or_report(&context, err, command.name_span),
+                                }
+                            }
+                            CommandAction::EnterReplSubshell => {
+                                repl_subshell(&context).await;
+                                InputStream::empty()
+                            }
+                            CommandAction::ReexecLast => {
+                                match reexec_last(&context, command.name_span).await {
+                                    Ok(output) => InputStream::from_stream(futures::stream::iter(output)),
+                                    Err(err) => catch_or_report(&context, err, command.name_span),
+                                }
+                            }
+                            CommandAction::EchoToStderr(text) => {
+                                context.with_host(|host| host.stderr(&text));
+                                InputStream::empty()
+                            }
+                            CommandAction::Redirect { target } => {
+                                context.set_redirect(target);
+                                InputStream::empty()
+                            }
+                            CommandAction::SetRenderMargin(margin) => {
+                                context.set_render_margin(margin);
+                                InputStream::empty()
+                            }
+                            CommandAction::SetRenderWidth(width) => {
+                                context.scope.set_render_width(width);
+                                InputStream::empty()
+                            }
+                            CommandAction::Sleep(duration) => {
+                                SleepFuture::new(duration, context.ctrl_c.clone()).await;
+                                InputStream::empty()
+                            }
+                            CommandAction::SaveHistoryNow => {
+                                if let Err(err) = context.flush_history() {
+                                    context.error(err);
+                                }
+                                InputStream::empty()
+                            }
+                            CommandAction::SetPrompt(value) => {
+                                match value.value {
+                                    UntaggedValue::Primitive(Primitive::String(_))
+                                    | UntaggedValue::Block(_) => context.set_prompt(value),
+                                    _ => context.error(ShellError::labeled_error(
+                                        "Expected a string or block for the prompt",
+                                        format!("found {}", value.type_name()),
+                                        value.tag.span,
+                                    )),
                                 }
                                 InputStream::empty()
                             }
@@ -249,19 +765,27 @@ pub(crate) async fn run_internal_command(
                         Ok(ReturnSuccess::Value(Value {
                             value: UntaggedValue::Error(err),
                             ..
-                        })) => {
-                            context.error(err);
-                            InputStream::empty()
-                        }
+                        })) => catch_or_report(&context, err, command.name_span),
 
-                        Ok(ReturnSuccess::Value(v)) => InputStream::one(v),
+                        Ok(ReturnSuccess::Value(mut v)) => {
+                            // Point values without a span of their own at the command that made them
+                            if v.tag.span.is_unknown() {
+                                v.tag.span = command.name_span;
+                            }
+                            InputStream::one(v)
+                        }
 
                         Ok(ReturnSuccess::DebugValue(v)) => {
                             let doc = PrettyDebug::pretty_doc(&v);
-                            let mut buffer = termcolor::Buffer::ansi();
+                            // Only embed color codes when they'll reach a terminal
+                            let mut buffer = if context.with_host(|host| host.stdout_is_tty()) {
+                                termcolor::Buffer::ansi()
+                            } else {
+                                termcolor::Buffer::no_color()
+                            };
 
                             let _ = doc.render_raw(
-                                context.with_host(|host| host.width() - 5),
+                                context.debug_render_width(),
                                 &mut nu_source::TermColored::new(&mut buffer),
                             );
 
@@ -271,13 +795,3020 @@ pub(crate) async fn run_internal_command(
                         }
 
                         Err(err) => {
-                            context.error(err);
-                            InputStream::empty()
+                            let err = match origin.lock().as_ref().and_then(describe_origin) {
+                                Some(note) => err.with_note(note),
+                                None => err,
+                            };
+                            catch_or_report(&context, err, command.name_span)
                         }
                     }
                 }
             })
             .flatten()
-            .take_while(|x| futures::future::ready(!x.is_error())),
-    ))
+            // Nothing more is passed on once an exit was asked for, so an embedder can stop
+            .take_while(move |x| {
+                futures::future::ready(!x.is_error() && !exit_requested.load(Ordering::SeqCst))
+            }),
+    );
+
+    let stream = match profiler {
+        // Record once the stream has been fully driven, so lazy commands are measured too
+        Some((context, name)) => InputStream::from_stream(
+            stream.chain(
+                futures::stream::once(async move {
+                    context.record_timing(name, started.elapsed());
+                    None
+                })
+                .filter_map(futures::future::ready),
+            ),
+        ),
+        None => stream,
+    };
+
+    if buffered {
+        let values: Vec<Value> = stream.collect().await;
+        Ok(InputStream::from_stream(futures::stream::iter(values)))
+    } else {
+        Ok(stream)
+    }
+}
+
+/// Convert the contents for an auto-convert action, reporting a failed conversion
+async fn auto_convert(
+    converter: Command,
+    context: &EvaluationContext,
+    head: Box<SpannedExpression>,
+    name_span: Span,
+    contents: Value,
+) -> InputStream {
+    match run_converter(converter, context, head, name_span, contents).await {
+        Ok(output) => futures::stream::iter(output).to_input_stream(),
+        Err(err) => {
+            context.error(err);
+            InputStream::empty()
+        }
+    }
+}
+
+/// Run a `from ...` converter over the given contents, flattening any tables it returns into rows.
+/// Everything converted keeps the anchor of the contents, so later errors can name the source.
+async fn run_converter(
+    converter: Command,
+    context: &EvaluationContext,
+    head: Box<SpannedExpression>,
+    name_span: Span,
+    contents: Value,
+) -> Result<Vec<Result<Value, ShellError>>, ShellError> {
+    let contents_tag = contents.tag.clone();
+    let new_args = RawCommandArgs {
+        host: context.host.clone(),
+        ctrl_c: context.ctrl_c.clone(),
+        current_errors: context.current_errors.clone(),
+        shell_manager: context.shell_manager.clone(),
+        call_info: UnevaluatedCallInfo {
+            args: nu_protocol::hir::Call {
+                head,
+                positional: None,
+                named: None,
+                span: Span::unknown(),
+                external_redirection: ExternalRedirection::Stdout,
+            },
+            name_tag: Tag::unknown_anchor(name_span),
+        },
+        scope: context.scope.clone(),
+        session: context.session(),
+    };
+
+    let result = converter.run(new_args.with_input(vec![contents])).await?;
+    let result_vec: Vec<Result<ReturnSuccess, ShellError>> =
+        result.interruptible(context.ctrl_c.clone()).collect().await;
+
+    // Don't hand on a partial conversion
+    if context.ctrl_c.load(Ordering::SeqCst) {
+        return Err(ShellError::labeled_error(
+            "Conversion cancelled",
+            "interrupted by ctrl-c",
+            name_span,
+        ));
+    }
+
+    let mut output = vec![];
+    for res in result_vec {
+        match res {
+            Ok(ReturnSuccess::Value(Value {
+                value: UntaggedValue::Table(list),
+                ..
+            })) => {
+                for mut row in list {
+                    if row.tag.anchor.is_none() {
+                        row.tag.anchor = contents_tag.anchor.clone();
+                    }
+                    output.push(Ok(row));
+                }
+            }
+            Ok(ReturnSuccess::Value(Value { value, .. })) => {
+                output.push(Ok(value.into_value(contents_tag.clone())));
+            }
+            Err(e) => output.push(Err(e)),
+            _ => {}
+        }
+    }
+
+    Ok(output)
+}
+
+/// Create a filesystem shell rooted at `root` (relative to the current shell) and moved to `subpath` beneath it
+fn filesystem_shell_at(
+    context: &EvaluationContext,
+    root: &str,
+    subpath: &str,
+    name_span: Span,
+) -> Result<FilesystemShell, ShellError> {
+    let root = PathBuf::from(context.shell_manager.path()).join(root);
+    if !root.is_dir() {
+        return Err(ShellError::labeled_error(
+            format!("Can not enter shell at {}", root.display()),
+            "not a directory",
+            name_span,
+        ));
+    }
+
+    let target = root.join(subpath);
+    if !target.is_dir() {
+        return Err(ShellError::labeled_error(
+            format!("Can not move to {} inside the new shell", subpath),
+            "not a directory",
+            name_span,
+        ));
+    }
+
+    let mut shell = FilesystemShell::with_location(root.display().to_string())?;
+    shell.set_path(target.display().to_string());
+    Ok(shell)
+}
+
+/// Read an env var's text as the value it spells, eg. `42` as an int, or else as a string
+fn value_from_env_text(text: &str, tag: Tag) -> Value {
+    let trimmed = text.trim();
+    if let Ok(int) = trimmed.parse::<i64>() {
+        UntaggedValue::int(int).into_value(tag)
+    } else if let Ok(decimal) = trimmed.parse::<BigDecimal>() {
+        UntaggedValue::decimal(decimal).into_value(tag)
+    } else if let Ok(boolean) = trimmed.parse::<bool>() {
+        UntaggedValue::boolean(boolean).into_value(tag)
+    } else {
+        UntaggedValue::string(text).into_value(tag)
+    }
+}
+
+/// Read a dotenv file, relative to the current directory, into env vars of the current frame.
+/// Nothing is set if any line of the file is malformed or any of its values is rejected.
+fn set_env_from_file(context: &EvaluationContext, file: &Tagged<String>) -> Result<(), ShellError> {
+    let path = PathBuf::from(context.shell_manager.path()).join(&file.item);
+    let contents = std::fs::read_to_string(&path).map_err(|_| {
+        ShellError::labeled_error(
+            format!("Can't load environment file {}", file.item),
+            "can't load file",
+            file.span(),
+        )
+    })?;
+
+    context
+        .scope
+        .add_env(parse_dotenv(&contents, file)?.into_iter().collect())
+}
+
+/// Parse `KEY=VALUE` lines, skipping blank lines and `#` comments. Values may be single quoted,
+/// taken as-is, or double quoted, with `\n`, `\"` and `\\` escapes. Only a comment may follow a
+/// quoted value. Unquoted values end at a ` #` comment.
+fn parse_dotenv(
+    contents: &str,
+    file: &Tagged<String>,
+) -> Result<Vec<(String, String)>, ShellError> {
+    // Errors give the line and column in the file, and quote the line with a caret under the column
+    let malformed = |number: usize, line: &str, column: usize, reason: &str| {
+        ShellError::labeled_error(
+            format!("{}:{}:{}: {}", file.item, number, column + 1, reason),
+            format!("malformed line {} in this file", number),
+            file.span(),
+        )
+        .with_note(format!("{}\n{}^", line, " ".repeat(column)))
+    };
+
+    let mut vars = vec![];
+    for (index, line) in contents.lines().enumerate() {
+        let number = index + 1;
+        let line = line.trim_end();
+        let mut offset = line.len() - line.trim_start().len();
+        let mut rest = &line[offset..];
+        if rest.is_empty() || rest.starts_with('#') {
+            continue;
+        }
+        if let Some(exported) = rest.strip_prefix("export ") {
+            offset += rest.len() - exported.len();
+            rest = exported;
+        }
+
+        let eq = match rest.find('=') {
+            Some(eq) => eq,
+            None => return Err(malformed(number, line, offset, "expected KEY=VALUE")),
+        };
+        let name = rest[..eq].trim();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(malformed(number, line, offset, "invalid variable name"));
+        }
+
+        let after_eq = &rest[eq + 1..];
+        let value_offset = offset + eq + 1 + (after_eq.len() - after_eq.trim_start().len());
+        let value = after_eq.trim();
+
+        let (unquoted, consumed) = if let Some(quoted) = value.strip_prefix('"') {
+            let mut unescaped = String::new();
+            let mut chars = quoted.char_indices();
+            let mut closed = None;
+            while let Some((at, c)) = chars.next() {
+                match c {
+                    '"' => {
+                        closed = Some(at);
+                        break;
+                    }
+                    '\\' => match chars.next() {
+                        Some((_, 'n')) => unescaped.push('\n'),
+                        Some((_, other)) => unescaped.push(other),
+                        None => break,
+                    },
+                    c => unescaped.push(c),
+                }
+            }
+            match closed {
+                Some(at) => (unescaped, at + 2),
+                None => {
+                    return Err(malformed(
+                        number,
+                        line,
+                        value_offset,
+                        "unterminated double quote",
+                    ))
+                }
+            }
+        } else if let Some(quoted) = value.strip_prefix('\'') {
+            match quoted.find('\'') {
+                Some(end) => (quoted[..end].to_string(), end + 2),
+                None => {
+                    return Err(malformed(
+                        number,
+                        line,
+                        value_offset,
+                        "unterminated single quote",
+                    ))
+                }
+            }
+        } else {
+            let unquoted = match value.find(" #") {
+                Some(comment) => value[..comment].trim_end().to_string(),
+                None => value.to_string(),
+            };
+            (unquoted, value.len())
+        };
+
+        let trailing = &value[consumed..];
+        let text = trailing.trim_start();
+        if !text.is_empty() && !text.starts_with('#') {
+            let column = value_offset + consumed + (trailing.len() - text.len());
+            return Err(malformed(
+                number,
+                line,
+                column,
+                "unexpected text after the closing quote",
+            ));
+        }
+
+        vars.push((name.to_string(), unquoted));
+    }
+    Ok(vars)
+}
+
+/// The text to store in an env var for a variable's value, which has to be a single primitive
+fn env_text_from_var(
+    context: &EvaluationContext,
+    var: &str,
+    name_span: Span,
+) -> Result<String, ShellError> {
+    let value = context.scope.get_var(var).ok_or_else(|| {
+        ShellError::labeled_error(
+            format!("Missing variable {}", var),
+            "variable not set",
+            name_span,
+        )
+    })?;
+
+    match &value.value {
+        UntaggedValue::Primitive(Primitive::Nothing)
+        | UntaggedValue::Primitive(Primitive::Binary(_)) => Err(ShellError::labeled_error(
+            format!(
+                "Can not store {} in an environment variable",
+                value.type_name()
+            ),
+            "expected a string, number or boolean",
+            name_span,
+        )),
+        UntaggedValue::Primitive(_) => Ok(value.convert_to_string()),
+        _ => Err(ShellError::labeled_error(
+            format!(
+                "Can not store a {} in an environment variable",
+                value.type_name()
+            ),
+            "expected a string, number or boolean",
+            name_span,
+        )),
+    }
+}
+
+/// A filesystem shell at the directory named by the row's `path` column, or else its `name` column
+fn filesystem_shell_from_value(
+    context: &EvaluationContext,
+    value: &Value,
+    name_span: Span,
+) -> Result<FilesystemShell, ShellError> {
+    let column = ["path", "name"]
+        .iter()
+        .find_map(|column| value.get_data_by_key((*column).spanned_unknown()))
+        .ok_or_else(|| {
+            ShellError::labeled_error(
+                "Expected a row with a path or name column",
+                "no path column",
+                name_span,
+            )
+        })?;
+
+    let target = PathBuf::from(context.shell_manager.path()).join(column.as_string()?);
+    if !target.is_dir() {
+        return Err(ShellError::labeled_error(
+            format!("Can not enter shell at {}", target.display()),
+            "not a directory",
+            name_span,
+        ));
+    }
+
+    Ok(FilesystemShell::with_location(
+        target.display().to_string(),
+    )?)
+}
+
+/// Start an external command in the current directory and environment without waiting on it
+fn spawn_in_background(
+    context: &EvaluationContext,
+    name: &str,
+    args: &[String],
+) -> std::io::Result<Child> {
+    let mut process = std::process::Command::new(name);
+    process
+        .args(args)
+        .envs(context.scope.env_for_process())
+        .stdin(Stdio::null());
+
+    // Value and help shells don't have a real directory to run in
+    let cwd = PathBuf::from(context.shell_manager.path());
+    if cwd.is_dir() {
+        process.current_dir(cwd);
+    }
+
+    process.spawn()
+}
+
+/// Run a module in a frame of its own and bind what it exported in the current frame
+async fn import_module(
+    context: &EvaluationContext,
+    filename: &str,
+    prefix: Option<String>,
+    name_span: Span,
+) -> Result<(), ShellError> {
+    let path = context.locate_script(filename).map_err(|_| {
+        ShellError::labeled_error(
+            format!("Can't find module {}", filename),
+            "module not found",
+            name_span,
+        )
+    })?;
+    let contents = std::fs::read_to_string(&path).map_err(|_| {
+        ShellError::labeled_error("Can't load module", "can't load file", name_span)
+    })?;
+
+    let label = format!("module {}", path.display());
+    context.scope.enter_named_scope(&label);
+    context.enter_script(&path);
+    let result = crate::script::run_script_standalone(contents, false, context, false).await;
+    context.exit_script();
+    let exports = context.scope.current_exports();
+    if !context.scope.exit_labeled_scope(&label, false, name_span)? {
+        return Err(ShellError::labeled_error(
+            "Can't import module",
+            "the module left its own scope",
+            name_span,
+        ));
+    }
+
+    if let Err(err) = result {
+        context.error(err.into());
+    }
+
+    // Env vars are shared with external commands, so they keep their names. They go first, so
+    // nothing is imported if one is rejected.
+    context.scope.add_env(exports.env)?;
+
+    let namespaced = |name: &str| match &prefix {
+        Some(prefix) => format!("{}::{}", prefix, name),
+        None => name.to_string(),
+    };
+    for mut definition in exports.definitions {
+        definition.params.name = namespaced(&definition.params.name);
+        context.scope.add_definition(definition);
+    }
+    for (name, value) in exports.vars {
+        context
+            .scope
+            .add_var(format!("${}", namespaced(&name[1..])), value);
+    }
+
+    Ok(())
+}
+
+/// Read lines from the host and run them against the current context, as the prompt would, until
+/// the input runs out or `exit` is entered
+async fn repl_subshell(context: &EvaluationContext) {
+    // An exit asked for by a line, eg. `exit` or `exit --now`, only ends the subshell
+    let no_exit = context.no_exit.swap(true, Ordering::SeqCst);
+    while let Some(line) = read_line_in_background(context, "repl> ").await {
+        if let Err(err) = crate::script::run_script_standalone(line, false, context, false).await {
+            context.with_host(|host| host.stderr(&err.to_string()));
+        }
+        if context.take_exit_request() {
+            break;
+        }
+    }
+    context.no_exit.store(no_exit, Ordering::SeqCst);
+}
+
+/// Read a line from the host on a thread of its own, so waiting for input doesn't hold up the executor
+async fn read_line_in_background(
+    context: &EvaluationContext,
+    prompt: &'static str,
+) -> Option<String> {
+    let host = context.host.clone();
+    let (sender, receiver) = futures::channel::oneshot::channel();
+    std::thread::spawn(move || {
+        let _ = sender.send(host.lock().read_line(prompt));
+    });
+    receiver.await.ok().flatten()
+}
+
+/// Ends a re-run of the last line when dropped, however the re-run ends
+struct ReexecGuard<'a>(&'a EvaluationContext);
+
+impl Drop for ReexecGuard<'_> {
+    fn drop(&mut self) {
+        self.0.reexecuting.store(false, Ordering::SeqCst);
+        self.0.reexecuted_last.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Parse and run the last line entered at the prompt again, collecting its output
+async fn reexec_last(
+    context: &EvaluationContext,
+    name_span: Span,
+) -> Result<Vec<Value>, ShellError> {
+    let line = context.last_pipeline().ok_or_else(|| {
+        ShellError::labeled_error(
+            "There is no previous command to re-run",
+            "nothing to re-run",
+            name_span,
+        )
+    })?;
+
+    if context.reexecuting.swap(true, Ordering::SeqCst) {
+        return Err(ShellError::labeled_error(
+            "Can not re-run the last command while already re-running it",
+            "already re-running",
+            name_span,
+        ));
+    }
+    let _guard = ReexecGuard(context);
+
+    let (block, err) = nu_parser::parse(&line, 0, &context.scope);
+    if let Some(err) = err {
+        return Err(err.into());
+    }
+
+    let output = run_block(&block, context, InputStream::empty()).await?;
+    Ok(output.collect().await)
+}
+
+/// Error, before the command is run, if the first value of its input isn't of the input type its
+/// signature declares. The input is handed back whole otherwise.
+async fn check_input_type(
+    command: &Command,
+    mut input: InputStream,
+    name_span: Span,
+) -> Result<InputStream, ShellError> {
+    let expected = match command.signature().input {
+        Some(expected) => expected,
+        None => return Ok(input),
+    };
+    let first = match input.next().await {
+        Some(first) => first,
+        None => return Ok(input),
+    };
+
+    let given = Type::from_value(&first.value);
+    let matches = match &expected {
+        // An error is passed on for the command to report, whatever it expects
+        _ if first.is_error() => true,
+        // A table arrives as a stream of rows
+        Type::Table(_) => matches!(given, Type::Row(_) | Type::Table(_)),
+        // Externals give text as lines
+        Type::Line | Type::String => matches!(given, Type::Line | Type::String),
+        expected => std::mem::discriminant(expected) == std::mem::discriminant(&given),
+    };
+    if !matches {
+        let expected = match expected {
+            Type::Table(_) => "table".to_string(),
+            Type::Row(_) => "row".to_string(),
+            other => other.pretty().plain_string(70),
+        };
+        return Err(ShellError::labeled_error(
+            format!(
+                "{} expects {} input, but was given a {}",
+                command.name(),
+                expected,
+                first.type_name()
+            ),
+            format!("expects {} input", expected),
+            name_span,
+        ));
+    }
+
+    Ok(InputStream::from_stream(
+        futures::stream::iter(vec![first]).chain(input),
+    ))
+}
+
+/// Run a block in a frame of its own, draining all of its output, and time how long that took.
+/// Fails with the first error the block gave back or reported while it ran.
+async fn benchmark_block(
+    context: &EvaluationContext,
+    block: &Block,
+    name_span: Span,
+) -> Result<Value, ShellError> {
+    let errors_before = context.get_errors().len();
+    context.scope.enter_named_scope("benchmark");
+    let started = Instant::now();
+    let drained = match run_block(block, context, InputStream::empty()).await {
+        Ok(mut output) => {
+            let mut drained = Ok(());
+            while let Some(value) = output.next().await {
+                if let UntaggedValue::Error(err) = value.value {
+                    drained = Err(err);
+                    break;
+                }
+            }
+            drained
+        }
+        Err(err) => Err(err),
+    };
+    let elapsed = started.elapsed();
+    context.scope.exit_scope();
+    drained?;
+
+    // Taken back out, so the caller reports them only once
+    let reported = context.with_errors(|errors| {
+        if errors.len() > errors_before {
+            errors.split_off(errors_before).into_iter().next()
+        } else {
+            None
+        }
+    });
+    if let Some(err) = reported {
+        return Err(err);
+    }
+
+    Ok(UntaggedValue::duration(elapsed.as_nanos()).into_value(Tag::unknown_anchor(name_span)))
+}
+
+/// A note naming the file or url that a command's input was loaded from
+fn describe_origin(anchor: &AnchorLocation) -> Option<String> {
+    match anchor {
+        AnchorLocation::File(file) => Some(format!("value originally from file {}", file)),
+        AnchorLocation::Url(url) => Some(format!("value originally from url {}", url)),
+        AnchorLocation::Source(_) => None,
+    }
+}
+
+/// The error a command's panic is reported as, naming the command and what it panicked with
+fn panic_error(name: &str, span: Span, payload: Box<dyn std::any::Any + Send>) -> ShellError {
+    let message = if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown cause".to_string()
+    };
+
+    ShellError::labeled_error(
+        format!("{} panicked: {}", name, message),
+        format!("{} panicked here", name),
+        span,
+    )
+}
+
+/// Either hand the error on as a structured value, when errors are being caught, or report it
+fn catch_or_report(context: &EvaluationContext, err: ShellError, name_span: Span) -> InputStream {
+    if context.catch_errors.load(Ordering::SeqCst) {
+        InputStream::one(err.into_value(Tag::unknown_anchor(name_span)))
+    } else {
+        context.error(err);
+        InputStream::empty()
+    }
+}
+
+/// Describe what an action would do to the session, for actions that dry-run mode suppresses
+fn describe_side_effect(action: &CommandAction) -> Option<String> {
+    match action {
+        CommandAction::ChangePath(path) => Some(format!("would change path to {}", path)),
+        CommandAction::AddEnvVariable(name, value) => Some(format!(
+            "would set environment variable {} to {}",
+            name, value
+        )),
+        CommandAction::SetLastExitCode(code) => Some(format!(
+            "would set environment variable {} to {}",
+            LAST_EXIT_CODE, code
+        )),
+        CommandAction::AddEnvVariableTemplate(name, template) => Some(format!(
+            "would set environment variable {} to the template {}",
+            name, template
+        )),
+        CommandAction::AppendEnvVariable { name, value, .. } => Some(format!(
+            "would append {} to environment variable {}",
+            value, name
+        )),
+        CommandAction::SetEnvFromVar { env, var } => Some(format!(
+            "would set environment variable {} from variable {}",
+            env, var
+        )),
+        CommandAction::SetEnvFromFile(file) => Some(format!(
+            "would set environment variables from {}",
+            file.item
+        )),
+        CommandAction::SourceScript { filename, .. } => {
+            Some(format!("would source script {}", filename.item))
+        }
+        CommandAction::SetConfigValue {
+            path,
+            persist: true,
+            ..
+        } => Some(format!(
+            "would write config value {}",
+            path.iter()
+                .map(|member| member.as_string())
+                .collect::<Vec<_>>()
+                .join(".")
+        )),
+        CommandAction::AddPlugins(path) => Some(format!("would add plugins from {}", path)),
+        CommandAction::RunExternalInBackground { command, .. } => {
+            Some(format!("would run {} in the background", command))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::run_internal_command;
+    use crate::commands::{whole_stream_command, Command, WholeStreamCommand};
+    use crate::evaluate::scope::{ENV_PATH_SEPARATOR, LAST_EXIT_CODE};
+    use crate::prelude::*;
+    use futures::executor::block_on;
+    use nu_protocol::hir::{ExternalRedirection, InternalCommand};
+    use nu_protocol::{
+        ColumnPath, CommandAction, PathMember, Primitive, RedirectTarget, ReturnSuccess,
+        ReturnValue, Signature, TaggedDictBuilder, Type, UntaggedValue, Value, Verbosity,
+    };
+    use nu_test_support::fs::Stub::FileWithContent;
+    use nu_test_support::playground::Playground;
+    use std::ffi::OsString;
+    use std::time::{Duration, Instant};
+
+    struct Emit {
+        name: &'static str,
+        output: ReturnValue,
+    }
+
+    #[async_trait]
+    impl WholeStreamCommand for Emit {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn usage(&self) -> &str {
+            "Emit a fixed return value."
+        }
+
+        async fn run(&self, _args: CommandArgs) -> Result<OutputStream, ShellError> {
+            Ok(OutputStream::one(self.output.clone()))
+        }
+    }
+
+    /// Moving a filesystem shell moves the process's directory, which is shared by every test and
+    /// removed with the playground. Tests that move it hold this guard, so they take turns, and
+    /// the directory is put back when the guard drops, even if the test panics.
+    struct CwdGuard {
+        cwd: std::path::PathBuf,
+        _lock: parking_lot::MutexGuard<'static, ()>,
+    }
+
+    static CWD_LOCK: parking_lot::Mutex<()> = parking_lot::const_mutex(());
+
+    impl CwdGuard {
+        fn new() -> CwdGuard {
+            let lock = CWD_LOCK.lock();
+            CwdGuard {
+                cwd: std::env::current_dir().expect("could not get the current directory"),
+                _lock: lock,
+            }
+        }
+    }
+
+    impl Drop for CwdGuard {
+        fn drop(&mut self) {
+            let _ = std::env::set_current_dir(&self.cwd);
+        }
+    }
+
+    #[derive(Clone, Debug, Default)]
+    struct CaptureHost {
+        out: Arc<parking_lot::Mutex<String>>,
+        err: Arc<parking_lot::Mutex<String>>,
+        input: Arc<parking_lot::Mutex<VecDeque<String>>>,
+    }
+
+    impl Host for CaptureHost {
+        fn out_termcolor(&self) -> termcolor::StandardStream {
+            termcolor::StandardStream::stdout(termcolor::ColorChoice::Never)
+        }
+
+        fn err_termcolor(&self) -> termcolor::StandardStream {
+            termcolor::StandardStream::stderr(termcolor::ColorChoice::Never)
+        }
+
+        fn stdout(&mut self, out: &str) {
+            self.out.lock().push_str(&format!("{}\n", out));
+        }
+
+        fn stderr(&mut self, out: &str) {
+            self.err.lock().push_str(&format!("{}\n", out));
+        }
+
+        fn stdout_raw(&mut self, out: &str) {
+            self.out.lock().push_str(out);
+        }
+
+        fn vars(&mut self) -> Vec<(String, String)> {
+            vec![]
+        }
+
+        fn env_get(&mut self, _key: OsString) -> Option<OsString> {
+            None
+        }
+
+        fn env_set(&mut self, _key: OsString, _value: OsString) {}
+
+        fn env_rm(&mut self, _key: OsString) {}
+
+        fn width(&self) -> usize {
+            80
+        }
+
+        fn stdout_is_tty(&self) -> bool {
+            false
+        }
+
+        fn read_line(&mut self, _prompt: &str) -> Option<String> {
+            self.input.lock().pop_front()
+        }
+    }
+
+    struct FailOnInput;
+
+    #[async_trait]
+    impl WholeStreamCommand for FailOnInput {
+        fn name(&self) -> &str {
+            "fail-on-input"
+        }
+
+        fn usage(&self) -> &str {
+            "Fail once the input has been read."
+        }
+
+        async fn run(&self, args: CommandArgs) -> Result<OutputStream, ShellError> {
+            let name_span = args.call_info.name_tag.span;
+            let input: Vec<Value> = args.input.collect().await;
+            Ok(OutputStream::one(Err(ShellError::labeled_error(
+                format!("Could not use {} values", input.len()),
+                "failed here",
+                name_span,
+            ))))
+        }
+    }
+
+    struct Panics {
+        lazily: bool,
+    }
+
+    #[async_trait]
+    impl WholeStreamCommand for Panics {
+        fn name(&self) -> &str {
+            "panics"
+        }
+
+        fn usage(&self) -> &str {
+            "Panic, either straight away or while producing output."
+        }
+
+        async fn run(&self, _args: CommandArgs) -> Result<OutputStream, ShellError> {
+            if !self.lazily {
+                panic!("boom");
+            }
+            Ok(OutputStream::new(futures::stream::iter(0..2).map(|n| {
+                if n == 1 {
+                    panic!("boom");
+                }
+                ReturnSuccess::value(UntaggedValue::int(n).into_untagged_value())
+            })))
+        }
+    }
+
+    struct CountRuns {
+        runs: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl WholeStreamCommand for CountRuns {
+        fn name(&self) -> &str {
+            "count-runs"
+        }
+
+        fn usage(&self) -> &str {
+            "Count how many times it has been run."
+        }
+
+        async fn run(&self, _args: CommandArgs) -> Result<OutputStream, ShellError> {
+            self.runs.fetch_add(1, Ordering::SeqCst);
+            Ok(OutputStream::empty())
+        }
+    }
+
+    /// Fails unless the context made for it is in no-exit mode, so a test can stop short of exiting
+    struct ExpectNoExit;
+
+    #[async_trait]
+    impl WholeStreamCommand for ExpectNoExit {
+        fn name(&self) -> &str {
+            "expect-no-exit"
+        }
+
+        fn usage(&self) -> &str {
+            "Fail unless exiting would only be recorded."
+        }
+
+        async fn run(&self, args: CommandArgs) -> Result<OutputStream, ShellError> {
+            if EvaluationContext::from_args(&args)
+                .no_exit
+                .load(Ordering::SeqCst)
+            {
+                Ok(OutputStream::empty())
+            } else {
+                Err(ShellError::untagged_runtime_error("would exit"))
+            }
+        }
+    }
+
+    /// Records the render width it is given, the way table and autoview read it
+    struct SeenWidth {
+        seen: Arc<parking_lot::Mutex<Option<usize>>>,
+    }
+
+    #[async_trait]
+    impl WholeStreamCommand for SeenWidth {
+        fn name(&self) -> &str {
+            "seen-width"
+        }
+
+        fn usage(&self) -> &str {
+            "Record the width output is rendered at."
+        }
+
+        async fn run(&self, args: CommandArgs) -> Result<OutputStream, ShellError> {
+            *self.seen.lock() = args.scope.render_width();
+            Ok(OutputStream::empty())
+        }
+    }
+
+    /// Emits the numbers 1 to 3, counting how many of them have been produced so far
+    struct Numbers {
+        produced: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl WholeStreamCommand for Numbers {
+        fn name(&self) -> &str {
+            "numbers"
+        }
+
+        fn usage(&self) -> &str {
+            "Emit the numbers 1 to 3."
+        }
+
+        async fn run(&self, _args: CommandArgs) -> Result<OutputStream, ShellError> {
+            let produced = self.produced.clone();
+            Ok(futures::stream::iter(1..=3)
+                .map(move |n| {
+                    produced.fetch_add(1, Ordering::SeqCst);
+                    ReturnSuccess::value(UntaggedValue::int(n))
+                })
+                .to_output_stream())
+        }
+    }
+
+    /// Takes a table as its input
+    struct TableSink;
+
+    #[async_trait]
+    impl WholeStreamCommand for TableSink {
+        fn name(&self) -> &str {
+            "table-sink"
+        }
+
+        fn signature(&self) -> Signature {
+            Signature::build("table-sink").input(Type::Table(vec![]))
+        }
+
+        fn usage(&self) -> &str {
+            "Take a table and drop it."
+        }
+
+        async fn run(&self, args: CommandArgs) -> Result<OutputStream, ShellError> {
+            let _: Vec<Value> = args.input.collect().await;
+            Ok(OutputStream::empty())
+        }
+    }
+
+    struct StringSink;
+
+    #[async_trait]
+    impl WholeStreamCommand for StringSink {
+        fn name(&self) -> &str {
+            "string-sink"
+        }
+
+        fn signature(&self) -> Signature {
+            Signature::build("string-sink").input(Type::String)
+        }
+
+        fn usage(&self) -> &str {
+            "Take a string and drop it."
+        }
+
+        async fn run(&self, args: CommandArgs) -> Result<OutputStream, ShellError> {
+            let _: Vec<Value> = args.input.collect().await;
+            Ok(OutputStream::empty())
+        }
+    }
+
+    /// Swap the context's host for a `CaptureHost`, returning a handle on what it captures
+    fn capture_host(context: &mut EvaluationContext) -> CaptureHost {
+        let host = CaptureHost::default();
+        context.host = Arc::new(parking_lot::Mutex::new(Box::new(host.clone())));
+        host
+    }
+
+    /// Run a registered command as if it were typed at the start of a line
+    fn run_command(
+        context: &EvaluationContext,
+        name: &str,
+        input: impl Into<InputStream>,
+        redirection: ExternalRedirection,
+    ) -> Result<Vec<Value>, ShellError> {
+        let span = Span::new(0, name.len());
+        let mut command = InternalCommand::new(name.to_string(), span, span);
+        command.args.external_redirection = redirection;
+
+        block_on(async {
+            Ok(run_internal_command(command, context, input.into())
+                .await?
+                .collect()
+                .await)
+        })
+    }
+
+    fn emit(context: &EvaluationContext, output: ReturnSuccess) -> Vec<Value> {
+        context.add_commands(vec![whole_stream_command(Emit {
+            name: "emit",
+            output: Ok(output),
+        })]);
+        run_command(
+            context,
+            "emit",
+            InputStream::empty(),
+            ExternalRedirection::Stdout,
+        )
+        .expect("could not run the internal command")
+    }
+
+    fn run_action(context: &EvaluationContext, action: CommandAction) -> Vec<Value> {
+        emit(context, ReturnSuccess::Action(action))
+    }
+
+    #[test]
+    fn debug_values_render_at_the_set_width() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        let row = TaggedDictBuilder::build(Tag::unknown(), |row| {
+            for column in &["first", "second", "third", "fourth", "fifth"] {
+                row.insert_untagged(*column, UntaggedValue::string("a value"));
+            }
+        });
+        let render = |context: &EvaluationContext| {
+            let values = emit(context, ReturnSuccess::DebugValue(row.clone()));
+            values[0]
+                .as_string()
+                .expect("rendered value should be a string")
+        };
+
+        run_action(&context, CommandAction::SetRenderWidth(Some(200)));
+        let wide = render(&context);
+        run_action(&context, CommandAction::SetRenderWidth(Some(30)));
+        let narrow = render(&context);
+
+        assert_eq!(wide.lines().count(), 1);
+        assert!(narrow.lines().count() > 1);
+        assert_eq!(context.scope.render_width(), Some(30));
+    }
+
+    #[test]
+    fn commands_see_the_set_render_width() {
+        use crate::script::process_script;
+
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        let seen = Arc::new(parking_lot::Mutex::new(None));
+        context.add_commands(vec![whole_stream_command(SeenWidth { seen: seen.clone() })]);
+
+        run_action(&context, CommandAction::SetRenderWidth(Some(42)));
+        block_on(process_script("seen-width", &context, false, 0, false));
+        assert_eq!(*seen.lock(), Some(42));
+
+        run_action(&context, CommandAction::SetRenderWidth(None));
+        block_on(process_script("seen-width", &context, false, 0, false));
+        assert_eq!(*seen.lock(), None);
+    }
+
+    #[test]
+    fn verbose_errors_quote_the_lines_their_labels_point_at() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        let source = Text::from("ls\necho 1 | bad arg");
+        let error = |span| ShellError::labeled_error("Bad input", "this one", span);
+        let notes = |error: ShellError| {
+            context
+                .error_for_source(error, &source)
+                .into_diagnostic()
+                .expect("expected a diagnostic error")
+                .notes
+        };
+
+        assert!(notes(error(Span::new(12, 15))).is_empty());
+
+        run_action(
+            &context,
+            CommandAction::SetErrorVerbosity(Verbosity::Verbose),
+        );
+
+        assert_eq!(
+            notes(error(Span::new(12, 15))),
+            vec!["'this one' points at line 2: echo 1 | bad arg".to_string()]
+        );
+        assert_eq!(
+            notes(error(Span::new(40, 45))),
+            vec!["'this one' points at 40..45".to_string()]
+        );
+    }
+
+    #[test]
+    fn set_trace_writes_command_input_to_stderr() {
+        let mut context = EvaluationContext::basic().expect("could not create a basic context");
+        let err = capture_host(&mut context).err;
+        context.add_commands(vec![whole_stream_command(FailOnInput)]);
+
+        let run = |context: &EvaluationContext| {
+            let input = UntaggedValue::string("traced").into_untagged_value();
+            run_command(
+                context,
+                "fail-on-input",
+                InputStream::one(input),
+                ExternalRedirection::Stdout,
+            )
+            .expect("could not run the internal command");
+        };
+
+        run(&context);
+        assert!(err.lock().is_empty());
+
+        run_action(&context, CommandAction::SetTrace(true));
+        run(&context);
+        assert!(err.lock().starts_with("fail-on-input input = "));
+        assert!(err.lock().contains("traced"));
+
+        err.lock().clear();
+        run_action(&context, CommandAction::SetTrace(false));
+        run(&context);
+        assert!(err.lock().is_empty());
+    }
+
+    #[test]
+    fn errors_note_where_the_input_came_from() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        context.add_commands(vec![whole_stream_command(FailOnInput)]);
+
+        let input = UntaggedValue::string("contents").into_value(
+            Tag::unknown().anchored(Some(AnchorLocation::File("data.txt".to_string()))),
+        );
+        run_command(
+            &context,
+            "fail-on-input",
+            InputStream::one(input),
+            ExternalRedirection::Stdout,
+        )
+        .expect("could not run the internal command");
+
+        let errors = context.get_errors();
+        assert_eq!(errors.len(), 1);
+        let diagnostic = errors[0]
+            .clone()
+            .into_diagnostic()
+            .expect("expected a diagnostic error");
+        assert_eq!(
+            diagnostic.notes,
+            vec!["value originally from file data.txt".to_string()]
+        );
+    }
+
+    #[test]
+    fn panicking_commands_are_reported_by_name() {
+        for lazily in &[false, true] {
+            let context = EvaluationContext::basic().expect("could not create a basic context");
+            context.add_commands(vec![whole_stream_command(Panics { lazily: *lazily })]);
+            let values = run_command(
+                &context,
+                "panics",
+                InputStream::empty(),
+                ExternalRedirection::Stdout,
+            )
+            .expect("a panic should not surface as a failed run");
+
+            let produced = values.iter().filter(|value| !value.value.is_none()).count();
+            assert_eq!(produced, if *lazily { 1 } else { 0 });
+            let errors = context.get_errors();
+            assert_eq!(errors.len(), 1);
+            let diagnostic = errors[0]
+                .clone()
+                .into_diagnostic()
+                .expect("expected a diagnostic error");
+            assert_eq!(diagnostic.message, "panics panicked: boom");
+        }
+    }
+
+    #[test]
+    fn values_round_trip_through_env() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        context
+            .scope
+            .add_var("$count", UntaggedValue::int(42).into_untagged_value());
+
+        run_action(
+            &context,
+            CommandAction::SetEnvFromVar {
+                env: "COUNT".to_string(),
+                var: "$count".to_string(),
+            },
+        );
+        assert_eq!(context.scope.get_env_var("COUNT"), Some("42".to_string()));
+
+        run_action(
+            &context,
+            CommandAction::SetVarFromEnv {
+                var: "$parsed".to_string(),
+                env: "COUNT".to_string(),
+            },
+        );
+        assert_eq!(
+            context.scope.get_var("$parsed").map(|value| value.value),
+            Some(UntaggedValue::int(42))
+        );
+        assert!(context.get_errors().is_empty());
+    }
+
+    #[test]
+    fn tables_can_not_be_stored_in_env() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        context.scope.add_var(
+            "$rows",
+            UntaggedValue::table(&[UntaggedValue::int(1).into_untagged_value()])
+                .into_untagged_value(),
+        );
+
+        run_action(
+            &context,
+            CommandAction::SetEnvFromVar {
+                env: "ROWS".to_string(),
+                var: "$rows".to_string(),
+            },
+        );
+
+        assert_eq!(context.get_errors().len(), 1);
+        assert_eq!(context.scope.get_env_var("ROWS"), None);
+    }
+
+    #[test]
+    fn last_exit_code_is_readable_from_env() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+
+        run_action(&context, CommandAction::SetLastExitCode(0));
+        context.scope.enter_scope();
+        run_action(&context, CommandAction::SetLastExitCode(127));
+        context.scope.exit_scope();
+
+        assert_eq!(
+            context.scope.get_env_var(LAST_EXIT_CODE),
+            Some("127".to_string())
+        );
+        assert!(context.get_errors().is_empty());
+    }
+
+    #[test]
+    fn popping_env_frame_discards_what_was_set_in_it() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+
+        run_action(&context, CommandAction::PushEnvFrame);
+        run_action(
+            &context,
+            CommandAction::AddEnvVariable("TEMPORARY".to_string(), "yes".to_string()),
+        );
+        assert_eq!(
+            context.scope.get_env_var("TEMPORARY"),
+            Some("yes".to_string())
+        );
+
+        run_action(&context, CommandAction::PopEnvFrame);
+        assert_eq!(context.scope.get_env_var("TEMPORARY"), None);
+        assert!(context.get_errors().is_empty());
+
+        run_action(&context, CommandAction::PopEnvFrame);
+        assert_eq!(context.get_errors().len(), 1);
+    }
+
+    #[test]
+    fn popping_env_frame_leaves_frames_it_did_not_push() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        run_action(
+            &context,
+            CommandAction::EnterValueShell(UntaggedValue::nothing().into_untagged_value()),
+        );
+        run_action(
+            &context,
+            CommandAction::AddEnvVariable("IN_SHELL".to_string(), "yes".to_string()),
+        );
+
+        run_action(&context, CommandAction::PopEnvFrame);
+
+        assert_eq!(context.get_errors().len(), 1);
+        assert_eq!(
+            context.scope.get_env_var("IN_SHELL"),
+            Some("yes".to_string())
+        );
+    }
+
+    #[test]
+    fn restart_is_passed_out_of_the_line() {
+        use crate::script::{process_script, LineResult};
+
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        context.add_commands(vec![whole_stream_command(Emit {
+            name: "restart",
+            output: Ok(ReturnSuccess::Action(CommandAction::Restart)),
+        })]);
+
+        let result = block_on(process_script("restart", &context, false, 0, false));
+
+        assert!(matches!(result, LineResult::Restart(line) if line == "restart"));
+        assert!(!context.take_restart_request());
+    }
+
+    #[test]
+    fn repl_subshell_runs_lines_until_exit() {
+        let mut context =
+            crate::create_default_context(false).expect("could not create the default context");
+        let runs = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        context.add_commands(vec![whole_stream_command(CountRuns { runs: runs.clone() })]);
+        let input = capture_host(&mut context).input;
+        input.lock().extend(vec![
+            "set-env FROM_SUBSHELL = yes".to_string(),
+            "count-runs".to_string(),
+            "exit".to_string(),
+            "count-runs".to_string(),
+        ]);
+
+        run_action(&context, CommandAction::EnterReplSubshell);
+
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(input.lock().len(), 1);
+        assert_eq!(
+            context.scope.get_env_var("FROM_SUBSHELL"),
+            Some("yes".to_string())
+        );
+    }
+
+    #[test]
+    fn repl_subshell_returns_on_exit_now() {
+        let mut context =
+            crate::create_default_context(false).expect("could not create the default context");
+        let input = capture_host(&mut context).input;
+        input
+            .lock()
+            .extend(vec!["exit --now".to_string(), "echo after".to_string()]);
+
+        run_action(&context, CommandAction::EnterReplSubshell);
+
+        assert_eq!(input.lock().len(), 1);
+        assert!(!context.no_exit.load(Ordering::SeqCst));
+        assert!(!context.take_exit_request());
+        assert!(!context.shell_manager.is_empty());
+    }
+
+    #[test]
+    fn reexec_last_runs_the_previous_line_again() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        let runs = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        context.add_commands(vec![whole_stream_command(CountRuns { runs: runs.clone() })]);
+
+        run_action(&context, CommandAction::ReexecLast);
+        assert_eq!(context.get_errors().len(), 1);
+        context.clear_errors();
+
+        block_on(crate::script::process_script(
+            "count-runs",
+            &context,
+            false,
+            0,
+            true,
+        ));
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        run_action(&context, CommandAction::ReexecLast);
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert!(context.get_errors().is_empty());
+    }
+
+    #[test]
+    fn reexec_last_outside_the_prompt_does_not_hide_the_next_line() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        let runs = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        context.add_commands(vec![whole_stream_command(CountRuns { runs: runs.clone() })]);
+        block_on(crate::script::process_script(
+            "count-runs",
+            &context,
+            false,
+            0,
+            true,
+        ));
+
+        run_action(&context, CommandAction::ReexecLast);
+        assert!(!context.reexecuting.load(Ordering::SeqCst));
+
+        block_on(crate::script::process_script(
+            "count-runs | count-runs",
+            &context,
+            false,
+            0,
+            true,
+        ));
+        assert_eq!(
+            context.last_pipeline(),
+            Some("count-runs | count-runs".to_string())
+        );
+    }
+
+    #[test]
+    fn echo_to_stderr_leaves_stdout_clean() {
+        let mut context = EvaluationContext::basic().expect("could not create a basic context");
+        let host = capture_host(&mut context);
+
+        run_action(
+            &context,
+            CommandAction::EchoToStderr("50% done".to_string()),
+        );
+
+        assert_eq!(*host.err.lock(), "50% done\n");
+        assert!(host.out.lock().is_empty());
+    }
+
+    #[test]
+    fn deprecation_warning_is_given_once_per_name() {
+        let mut context = EvaluationContext::basic().expect("could not create a basic context");
+        let err = capture_host(&mut context).err;
+        let warn = || CommandAction::WarnDeprecated {
+            name: "old-cmd".to_string(),
+            replacement: Some("new-cmd".to_string()),
+            since: Some("0.25".to_string()),
+        };
+
+        run_action(&context, warn());
+        run_action(&context, warn());
+
+        assert_eq!(
+            *err.lock(),
+            "warning: 'old-cmd' is deprecated since 0.25, use 'new-cmd' instead\n"
+        );
+    }
+
+    #[test]
+    fn debug_render_width_stays_positive_when_narrower_than_margin() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+
+        run_action(&context, CommandAction::SetRenderWidth(Some(3)));
+        let values = emit(
+            &context,
+            ReturnSuccess::DebugValue(UntaggedValue::int(42).into_untagged_value()),
+        );
+
+        assert_eq!(values.len(), 1);
+        assert_eq!(context.debug_render_width(), 1);
+
+        run_action(&context, CommandAction::SetRenderWidth(Some(30)));
+        run_action(&context, CommandAction::SetRenderMargin(0));
+        assert_eq!(context.debug_render_width(), 30);
+    }
+
+    #[test]
+    fn sleep_completes() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+
+        let started = Instant::now();
+        run_action(&context, CommandAction::Sleep(Duration::from_millis(10)));
+
+        assert!(started.elapsed() >= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn ctrl_c_cuts_sleep_short() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        context.ctrl_c.store(true, Ordering::SeqCst);
+
+        let started = Instant::now();
+        run_action(&context, CommandAction::Sleep(Duration::from_secs(10)));
+
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn untagged_values_point_at_the_command() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        let values = emit(
+            &context,
+            ReturnSuccess::Value(UntaggedValue::int(5).into_untagged_value()),
+        );
+
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0].tag.span, Span::new(0, 4));
+    }
+
+    #[test]
+    fn tagged_values_keep_their_tags() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+
+        let values = emit(
+            &context,
+            ReturnSuccess::Value(UntaggedValue::int(5).into_value(Span::new(10, 12))),
+        );
+
+        assert_eq!(values[0].tag.span, Span::new(10, 12));
+    }
+
+    #[test]
+    fn print_raw_writes_verbatim_to_host() {
+        let mut context = EvaluationContext::basic().expect("could not create a basic context");
+        let out = capture_host(&mut context).out;
+        context.add_commands(vec![whole_stream_command(Emit {
+            name: "print-raw",
+            output: Ok(ReturnSuccess::Action(CommandAction::PrintRaw(
+                "\x1b[2J raw\ttext".to_string(),
+            ))),
+        })]);
+
+        let output = run_command(
+            &context,
+            "print-raw",
+            InputStream::empty(),
+            ExternalRedirection::None,
+        )
+        .expect("could not run the internal command");
+
+        assert!(output
+            .iter()
+            .all(|value| value.value == UntaggedValue::nothing()));
+        assert_eq!(*out.lock(), "\x1b[2J raw\ttext");
+    }
+
+    #[test]
+    fn print_raw_is_captured_when_piped() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+
+        let output = run_action(&context, CommandAction::PrintRaw("raw".to_string()));
+
+        assert_eq!(
+            output,
+            vec![UntaggedValue::string("raw").into_untagged_value()]
+        );
+    }
+
+    #[test]
+    fn define_and_remove_alias_round_trip() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        let replacement = vec!["ls".to_string().spanned_unknown()];
+
+        run_action(
+            &context,
+            CommandAction::DefineAlias {
+                name: "l".to_string(),
+                replacement: replacement.clone(),
+            },
+        );
+        assert_eq!(context.scope.get_alias("l"), Some(replacement));
+
+        run_action(&context, CommandAction::RemoveAlias("l".to_string()));
+        assert_eq!(context.scope.get_alias("l"), None);
+        assert!(context.get_errors().is_empty());
+    }
+
+    #[test]
+    fn remove_missing_alias_reports_error() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+
+        run_action(&context, CommandAction::RemoveAlias("nope".to_string()));
+
+        assert_eq!(context.get_errors().len(), 1);
+    }
+
+    #[test]
+    fn profiling_records_each_command() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        context.enable_profiling();
+
+        run_action(&context, CommandAction::PrintRaw("profiled".to_string()));
+
+        let profile = context.drain_profile();
+        assert_eq!(profile.len(), 1);
+        assert_eq!(profile[0].0, "emit");
+        assert!(context.drain_profile().is_empty());
+    }
+
+    #[test]
+    fn profiling_records_commands_run_inside_blocks() {
+        use crate::commands::Do;
+
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        let runs = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        context.add_commands(vec![
+            whole_stream_command(Do),
+            whole_stream_command(CountRuns { runs }),
+        ]);
+        context.enable_profiling();
+
+        block_on(crate::script::process_script(
+            "do { count-runs }",
+            &context,
+            false,
+            0,
+            false,
+        ));
+
+        let profile = context.drain_profile();
+        assert!(profile.iter().any(|(name, _)| name == "count-runs"));
+        assert!(profile.iter().any(|(name, _)| name == "do"));
+    }
+
+    #[test]
+    fn enter_shell_resolves_relative_paths_against_current_shell() {
+        Playground::setup("enter_shell_relative_test", |dirs, sandbox| {
+            let _cwd = CwdGuard::new();
+            sandbox.mkdir("a/b/c");
+
+            let context = EvaluationContext::basic().expect("could not create a basic context");
+            context
+                .shell_manager
+                .set_path(dirs.test().join("a").display().to_string());
+            let enter = |location: &str| {
+                run_action(
+                    &context,
+                    CommandAction::EnterShell {
+                        location: location.to_string(),
+                        isolate_env: false,
+                    },
+                )
+            };
+
+            enter("b");
+            assert_eq!(
+                context.shell_manager.path(),
+                dirs.test().join("a").join("b").display().to_string()
+            );
+
+            // From inside a value shell, against the filesystem shell it was entered from
+            run_action(
+                &context,
+                CommandAction::EnterValueShell(UntaggedValue::nothing().into_untagged_value()),
+            );
+            enter("c");
+            assert!(context.get_errors().is_empty());
+            assert_eq!(
+                context.shell_manager.path(),
+                dirs.test()
+                    .join("a")
+                    .join("b")
+                    .join("c")
+                    .display()
+                    .to_string()
+            );
+        })
+    }
+
+    #[test]
+    fn isolated_shell_sees_only_allowlisted_env() {
+        Playground::setup("enter_shell_isolated_test", |dirs, _| {
+            let _cwd = CwdGuard::new();
+            let context = EvaluationContext::basic().expect("could not create a basic context");
+            context
+                .scope
+                .add_env_var("HOME", "/home/nu".to_string())
+                .expect("env var should be accepted");
+            context
+                .scope
+                .add_env_var("SECRET", "hunter2".to_string())
+                .expect("env var should be accepted");
+
+            run_action(
+                &context,
+                CommandAction::EnterShell {
+                    location: dirs.test().display().to_string(),
+                    isolate_env: true,
+                },
+            );
+
+            assert_eq!(
+                context.scope.get_env_var("HOME"),
+                Some("/home/nu".to_string())
+            );
+            assert_eq!(context.scope.get_env_var("SECRET"), None);
+            assert!(!context.scope.get_env_vars().contains_key("SECRET"));
+
+            run_action(&context, CommandAction::LeaveShell { keep_env: false });
+            assert_eq!(
+                context.scope.get_env_var("SECRET"),
+                Some("hunter2".to_string())
+            );
+        })
+    }
+
+    #[test]
+    fn leaving_a_shell_drops_its_own_frame_after_switching_shells() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        for name in &["FIRST", "SECOND"] {
+            run_action(
+                &context,
+                CommandAction::EnterValueShell(UntaggedValue::nothing().into_untagged_value()),
+            );
+            run_action(
+                &context,
+                CommandAction::AddEnvVariable(name.to_string(), "set".to_string()),
+            );
+        }
+
+        run_action(&context, CommandAction::PreviousShell);
+        run_action(&context, CommandAction::LeaveShell { keep_env: false });
+
+        assert!(context.get_errors().is_empty());
+        assert_eq!(context.scope.get_env_var("FIRST"), None);
+        assert_eq!(context.scope.get_env_var("SECOND"), Some("set".to_string()));
+    }
+
+    #[test]
+    fn exit_only_records_the_request_in_no_exit_mode() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        context.no_exit.store(true, Ordering::SeqCst);
+
+        run_action(&context, CommandAction::Exit);
+        assert!(context.take_exit_request());
+        assert!(!context.take_exit_request());
+
+        run_action(&context, CommandAction::LeaveShell { keep_env: false });
+        assert!(!context.shell_manager.is_empty());
+        assert!(context.take_exit_request());
+    }
+
+    #[test]
+    fn exit_inside_a_block_keeps_to_no_exit_mode() {
+        use crate::commands::{Do, Exit};
+
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        context.add_commands(vec![
+            whole_stream_command(Do),
+            whole_stream_command(Exit),
+            whole_stream_command(ExpectNoExit),
+        ]);
+        context.no_exit.store(true, Ordering::SeqCst);
+
+        block_on(crate::script::process_script(
+            "do { expect-no-exit; exit }",
+            &context,
+            false,
+            0,
+            false,
+        ));
+        assert!(context.get_errors().is_empty());
+        assert!(context.take_exit_request());
+    }
+
+    #[test]
+    fn list_shells_marks_the_active_one() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        for _ in 0..2 {
+            run_action(
+                &context,
+                CommandAction::EnterValueShell(UntaggedValue::nothing().into_untagged_value()),
+            );
+        }
+        run_action(&context, CommandAction::PreviousShell);
+
+        let rows = run_action(&context, CommandAction::ListShells);
+        assert_eq!(rows.len(), 3);
+
+        let active: Vec<bool> = rows
+            .iter()
+            .map(|row| {
+                row.get_data_by_key("active".spanned_unknown())
+                    .expect("row has no active column")
+                    .as_bool()
+                    .expect("active is not a boolean")
+            })
+            .collect();
+        assert_eq!(active, vec![false, true, false]);
+    }
+
+    fn context_with_shells() -> EvaluationContext {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        for _ in 0..3 {
+            run_action(
+                &context,
+                CommandAction::EnterValueShell(UntaggedValue::nothing().into_untagged_value()),
+            );
+        }
+        context
+    }
+
+    #[test]
+    fn inspect_shells_are_flagged_and_left_back_to_where_they_started() {
+        let context = context_with_shells();
+        run_action(&context, CommandAction::GoToShell(1));
+
+        run_action(
+            &context,
+            CommandAction::PushValueOntoShellStack(UntaggedValue::int(42).into_untagged_value()),
+        );
+        let rows = run_action(&context, CommandAction::ListShells);
+        let inspect: Vec<bool> = rows
+            .iter()
+            .map(|row| {
+                row.get_data_by_key("inspect".spanned_unknown())
+                    .and_then(|value| value.as_bool().ok())
+                    .unwrap_or_default()
+            })
+            .collect();
+        assert_eq!(inspect, vec![false, false, false, false, true]);
+
+        run_action(&context, CommandAction::LeaveShell { keep_env: false });
+        assert!(context.get_errors().is_empty());
+        assert_eq!(context.shell_manager.current_shell(), 1);
+    }
+
+    #[test]
+    fn inspect_shells_return_to_their_shell_after_shells_before_it_are_left() {
+        let context = context_with_shells();
+        run_action(&context, CommandAction::GoToShell(2));
+        run_action(
+            &context,
+            CommandAction::PushValueOntoShellStack(UntaggedValue::int(42).into_untagged_value()),
+        );
+
+        run_action(&context, CommandAction::GoToShell(1));
+        run_action(&context, CommandAction::LeaveShell { keep_env: false });
+        run_action(&context, CommandAction::GoToShell(3));
+        run_action(&context, CommandAction::LeaveShell { keep_env: false });
+
+        assert!(context.get_errors().is_empty());
+        assert_eq!(context.shell_manager.current_shell(), 1);
+        assert_eq!(context.shell_manager.current_id(), 2);
+    }
+
+    #[test]
+    fn go_to_shell_jumps_directly() {
+        let context = context_with_shells();
+        assert_eq!(context.shell_manager.current_shell(), 3);
+
+        run_action(&context, CommandAction::GoToShell(0));
+
+        assert!(context.get_errors().is_empty());
+        assert_eq!(context.shell_manager.current_shell(), 0);
+    }
+
+    #[test]
+    fn go_to_shell_rejects_out_of_range_index() {
+        let context = context_with_shells();
+
+        run_action(&context, CommandAction::GoToShell(4));
+
+        assert_eq!(context.get_errors().len(), 1);
+        assert_eq!(context.shell_manager.current_shell(), 3);
+    }
+
+    #[test]
+    fn enter_shell_from_value_uses_path_column() {
+        Playground::setup("enter_shell_from_value_test", |dirs, sandbox| {
+            let _cwd = CwdGuard::new();
+            sandbox.mkdir("projects");
+
+            let context = EvaluationContext::basic().expect("could not create a basic context");
+            let row = TaggedDictBuilder::build(Tag::unknown(), |row| {
+                row.insert_untagged(
+                    "name",
+                    UntaggedValue::string(dirs.test().join("projects").display().to_string()),
+                );
+                row.insert_untagged("type", UntaggedValue::string("Dir"));
+            });
+
+            run_action(&context, CommandAction::EnterShellFromValue(row));
+
+            assert!(context.get_errors().is_empty());
+            assert_eq!(context.shell_manager.current_shell(), 1);
+            assert_eq!(
+                std::path::PathBuf::from(context.shell_manager.path()),
+                dunce::canonicalize(dirs.test().join("projects"))
+                    .expect("could not canonicalize the target")
+            );
+
+            run_action(
+                &context,
+                CommandAction::EnterShellFromValue(UntaggedValue::int(1).into_untagged_value()),
+            );
+
+            assert_eq!(context.get_errors().len(), 1);
+            assert_eq!(context.shell_manager.current_shell(), 1);
+        })
+    }
+
+    #[test]
+    fn change_path_hooks_see_old_and_new_paths() {
+        Playground::setup("change_path_hooks_test", |dirs, sandbox| {
+            sandbox.mkdir("next");
+            let _cwd = CwdGuard::new();
+
+            let context = EvaluationContext::basic().expect("could not create a basic context");
+            let seen = Arc::new(parking_lot::Mutex::new(vec![]));
+            let recorded = seen.clone();
+            context.on_change_path(Arc::new(move |old: &str, new: &str| {
+                recorded.lock().push((old.to_string(), new.to_string()));
+                Ok(())
+            }));
+            context.on_change_path(Arc::new(|_: &str, _: &str| {
+                Err(ShellError::untagged_runtime_error("hook failed"))
+            }));
+
+            let old = context.shell_manager.path();
+            let target = dunce::canonicalize(dirs.test().join("next"))
+                .expect("could not canonicalize the target")
+                .display()
+                .to_string();
+            run_action(&context, CommandAction::ChangePath(target.clone()));
+
+            assert_eq!(*seen.lock(), vec![(old, target.clone())]);
+            assert_eq!(context.get_errors().len(), 1);
+            assert_eq!(context.shell_manager.path(), target);
+
+            // Neither staying put nor failing to move counts as a change
+            run_action(&context, CommandAction::ChangePath(target.clone()));
+            let missing = dirs.test().join("missing").display().to_string();
+            run_action(&context, CommandAction::ChangePath(missing));
+            assert_eq!(seen.lock().len(), 1);
+            assert_eq!(context.get_errors().len(), 2);
+            assert_eq!(context.shell_manager.path(), target);
+        })
+    }
+
+    #[test]
+    fn enter_shell_at_positions_new_shell_below_root() {
+        Playground::setup("enter_shell_at_test", |dirs, sandbox| {
+            sandbox.mkdir("a/b/c");
+            let _cwd = CwdGuard::new();
+
+            let context = EvaluationContext::basic().expect("could not create a basic context");
+            let root = dirs.test().join("a").display().to_string();
+
+            run_action(
+                &context,
+                CommandAction::EnterShellAt {
+                    root: root.clone(),
+                    subpath: "b/c".to_string(),
+                },
+            );
+
+            assert!(context.get_errors().is_empty());
+            assert_eq!(context.shell_manager.current_shell(), 1);
+            assert_eq!(
+                std::path::PathBuf::from(context.shell_manager.path()),
+                dunce::canonicalize(dirs.test().join("a").join("b").join("c"))
+                    .expect("could not canonicalize the target")
+            );
+
+            run_action(
+                &context,
+                CommandAction::EnterShellAt {
+                    root,
+                    subpath: "missing".to_string(),
+                },
+            );
+
+            assert_eq!(context.get_errors().len(), 1);
+            assert_eq!(context.shell_manager.current_shell(), 1);
+        })
+    }
+
+    #[test]
+    fn leave_shell_keeps_env_only_when_asked() {
+        for keep_env in [true, false].iter().copied() {
+            let context = EvaluationContext::basic().expect("could not create a basic context");
+
+            run_action(
+                &context,
+                CommandAction::EnterValueShell(UntaggedValue::nothing().into_untagged_value()),
+            );
+            run_action(
+                &context,
+                CommandAction::AddEnvVariable("INSIDE".to_string(), "shell".to_string()),
+            );
+            run_action(&context, CommandAction::LeaveShell { keep_env });
+
+            let expected = if keep_env {
+                Some("shell".to_string())
+            } else {
+                None
+            };
+            assert_eq!(context.scope.get_env_var("INSIDE"), expected);
+        }
+    }
+
+    #[test]
+    fn dry_run_does_not_change_path() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        context.dry_run.store(true, Ordering::SeqCst);
+        let before = context.shell_manager.path();
+
+        let output = run_action(
+            &context,
+            CommandAction::ChangePath("/elsewhere".to_string()),
+        );
+
+        assert_eq!(
+            output,
+            vec![UntaggedValue::string("would change path to /elsewhere").into_untagged_value()]
+        );
+        assert_eq!(context.shell_manager.path(), before);
+    }
+
+    #[test]
+    fn dry_run_holds_inside_blocks() {
+        use crate::commands::{Cd, Do};
+
+        let _cwd = CwdGuard::new();
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        context.dry_run.store(true, Ordering::SeqCst);
+        context.add_commands(vec![whole_stream_command(Do), whole_stream_command(Cd)]);
+        let before = context.shell_manager.path();
+
+        block_on(crate::script::process_script(
+            "do { cd / }",
+            &context,
+            false,
+            0,
+            false,
+        ));
+
+        assert_eq!(context.shell_manager.path(), before);
+    }
+
+    #[test]
+    fn append_env_variable_joins_with_the_separator() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        context
+            .scope
+            .add_env_var("PATH", "/bin".to_string())
+            .expect("env var should be accepted");
+        let append = |name: &str, value: &str, separator: Option<&str>| {
+            run_action(
+                &context,
+                CommandAction::AppendEnvVariable {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                    separator: separator.map(String::from),
+                },
+            )
+        };
+
+        append("PATH", "/usr/bin", None);
+        append("FLAGS", "-a", Some(" "));
+        append("FLAGS", "-b", Some(" "));
+
+        assert_eq!(
+            context.scope.get_env_var("PATH"),
+            Some(format!("/bin{}/usr/bin", ENV_PATH_SEPARATOR))
+        );
+        assert_eq!(
+            context.scope.get_env_var("FLAGS"),
+            Some("-a -b".to_string())
+        );
+    }
+
+    #[test]
+    fn append_env_variable_reports_a_rejected_value() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        let valid = std::env::temp_dir().display().to_string();
+        context
+            .scope
+            .add_env_var("NU_LIB_DIRS", valid.clone())
+            .expect("an existing directory should be accepted");
+
+        run_action(
+            &context,
+            CommandAction::AppendEnvVariable {
+                name: "NU_LIB_DIRS".to_string(),
+                value: "/nu/missing/lib/dir".to_string(),
+                separator: None,
+            },
+        );
+
+        assert_eq!(context.get_errors().len(), 1);
+        assert_eq!(context.scope.get_env_var("NU_LIB_DIRS"), Some(valid));
+    }
+
+    #[test]
+    fn debug_values_render_without_color_for_non_tty_hosts() {
+        let mut context = EvaluationContext::basic().expect("could not create a basic context");
+        capture_host(&mut context);
+
+        let output = emit(
+            &context,
+            ReturnSuccess::DebugValue(UntaggedValue::int(42).into_untagged_value()),
+        );
+
+        let rendered = output[0]
+            .as_string()
+            .expect("debug value should render to a string");
+        assert!(rendered.contains("42"));
+        assert!(!rendered.contains('\x1b'));
+    }
+
+    fn converter(name: &'static str, output: ReturnValue) -> Command {
+        whole_stream_command(Emit { name, output })
+    }
+
+    /// A converter with a long output that presses ctrl-c after its first row
+    struct Interrupted;
+
+    #[async_trait]
+    impl WholeStreamCommand for Interrupted {
+        fn name(&self) -> &str {
+            "from interrupted"
+        }
+
+        fn usage(&self) -> &str {
+            ""
+        }
+
+        async fn run(&self, args: CommandArgs) -> Result<OutputStream, ShellError> {
+            let ctrl_c = args.ctrl_c.clone();
+            Ok(futures::stream::iter(0..10_000)
+                .map(move |row| {
+                    ctrl_c.store(true, Ordering::SeqCst);
+                    ReturnSuccess::value(UntaggedValue::int(row))
+                })
+                .to_output_stream())
+        }
+    }
+
+    #[test]
+    fn auto_convert_stops_when_interrupted() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        context.add_commands(vec![whole_stream_command(Interrupted)]);
+
+        let output = run_action(
+            &context,
+            CommandAction::AutoConvert(
+                UntaggedValue::string("raw").into_untagged_value(),
+                "interrupted".to_string(),
+            ),
+        );
+
+        assert!(output
+            .iter()
+            .all(|value| value.value == UntaggedValue::nothing()));
+        assert_eq!(context.get_errors().len(), 1);
+    }
+
+    #[test]
+    fn auto_convert_uses_the_converter_it_is_given() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        context.add_commands(vec![
+            converter(
+                "from json",
+                ReturnSuccess::value(UntaggedValue::string("by extension")),
+            ),
+            converter(
+                "my-special-format",
+                ReturnSuccess::value(UntaggedValue::string("by name")),
+            ),
+        ]);
+        let raw = UntaggedValue::string("{}").into_untagged_value();
+
+        let by_name = run_action(
+            &context,
+            CommandAction::AutoConvertWith {
+                value: raw.clone(),
+                converter: "my-special-format".to_string(),
+            },
+        );
+        let by_extension = run_action(
+            &context,
+            CommandAction::AutoConvert(raw.clone(), "json".to_string()),
+        );
+
+        assert_eq!(by_name[0].as_string().ok(), Some("by name".to_string()));
+        assert_eq!(
+            by_extension[0].as_string().ok(),
+            Some("by extension".to_string())
+        );
+        assert!(context.get_errors().is_empty());
+
+        run_action(
+            &context,
+            CommandAction::AutoConvertWith {
+                value: raw,
+                converter: "missing-format".to_string(),
+            },
+        );
+        let errors = context.get_errors();
+        assert_eq!(errors.len(), 1);
+        assert!(format!("{:?}", errors[0]).contains("Can't find the converter 'missing-format'"));
+    }
+
+    #[test]
+    fn converted_rows_keep_the_anchor_of_their_file() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        context.add_commands(vec![
+            converter(
+                "from rows",
+                ReturnSuccess::value(UntaggedValue::table(&[
+                    UntaggedValue::int(1).into_untagged_value(),
+                    UntaggedValue::int(2).into_untagged_value(),
+                ])),
+            ),
+            whole_stream_command(FailOnInput),
+        ]);
+        let anchor = AnchorLocation::File("data.rows".to_string());
+
+        let rows = run_action(
+            &context,
+            CommandAction::AutoConvert(
+                UntaggedValue::string("1\n2")
+                    .into_value(Tag::unknown().anchored(Some(anchor.clone()))),
+                "rows".to_string(),
+            ),
+        );
+        assert_eq!(rows.len(), 2);
+        assert!(rows
+            .iter()
+            .all(|row| row.tag.anchor() == Some(anchor.clone())));
+
+        run_command(&context, "fail-on-input", rows, ExternalRedirection::Stdout)
+            .expect("could not run the internal command");
+
+        let errors = context.get_errors();
+        assert_eq!(errors.len(), 1);
+        let diagnostic = errors[0]
+            .clone()
+            .into_diagnostic()
+            .expect("expected a diagnostic error");
+        assert_eq!(
+            diagnostic.notes,
+            vec!["value originally from file data.rows".to_string()]
+        );
+    }
+
+    #[test]
+    fn auto_convert_candidates_fall_through_to_working_converter() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        context.add_commands(vec![
+            converter(
+                "from first",
+                Err(ShellError::untagged_runtime_error("not first")),
+            ),
+            converter(
+                "from second",
+                ReturnSuccess::value(UntaggedValue::string("converted")),
+            ),
+        ]);
+
+        let output = run_action(
+            &context,
+            CommandAction::AutoConvertCandidates(
+                UntaggedValue::string("raw").into_untagged_value(),
+                vec!["first".to_string(), "second".to_string()],
+            ),
+        );
+
+        assert_eq!(
+            output,
+            vec![UntaggedValue::string("converted").into_untagged_value()]
+        );
+        assert!(context.get_errors().is_empty());
+    }
+
+    #[test]
+    fn disabled_auto_convert_passes_contents_through() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        context.add_commands(vec![converter(
+            "from json",
+            ReturnSuccess::value(UntaggedValue::string("converted")),
+        )]);
+        let raw = UntaggedValue::string("{\"a\": 1}").into_untagged_value();
+
+        run_action(&context, CommandAction::ToggleAutoConvert(false));
+        let output = run_action(
+            &context,
+            CommandAction::AutoConvert(raw.clone(), "json".to_string()),
+        );
+
+        assert_eq!(output, vec![raw]);
+    }
+
+    #[test]
+    fn auto_convert_candidates_report_every_failure() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        context.add_commands(vec![
+            converter(
+                "from first",
+                Err(ShellError::untagged_runtime_error("not first")),
+            ),
+            converter(
+                "from second",
+                Err(ShellError::untagged_runtime_error("not second")),
+            ),
+        ]);
+
+        run_action(
+            &context,
+            CommandAction::AutoConvertCandidates(
+                UntaggedValue::string("raw").into_untagged_value(),
+                vec!["first".to_string(), "second".to_string()],
+            ),
+        );
+
+        assert_eq!(context.get_errors().len(), 2);
+    }
+
+    #[test]
+    fn source_script_reports_what_it_loaded() {
+        Playground::setup("source_summary_test", |dirs, sandbox| {
+            sandbox.with_files(vec![
+                FileWithContent("quiet.nu", "def hush [] { echo shh }"),
+                FileWithContent(
+                    "lib.nu",
+                    "def greet [] { echo hello }\ndef part [] { echo bye }\nalias g = greet",
+                ),
+            ]);
+
+            let context =
+                crate::create_default_context(false).expect("could not create the default context");
+            let source = |name: &str, summary: bool| {
+                run_action(
+                    &context,
+                    CommandAction::SourceScript {
+                        filename: dirs
+                            .test()
+                            .join(name)
+                            .display()
+                            .to_string()
+                            .tagged_unknown(),
+                        summary,
+                    },
+                )
+            };
+
+            let output = source("quiet.nu", false);
+            assert!(output.iter().all(|value| value.value.is_none()));
+
+            let output = source("lib.nu", true);
+            assert!(context.get_errors().is_empty());
+            let summary = |column: &str| {
+                output[0]
+                    .get_data_by_key(column.spanned_unknown())
+                    .and_then(|count| count.as_i64().ok())
+            };
+            assert_eq!(summary("commands"), Some(2));
+            assert_eq!(summary("aliases"), Some(1));
+        })
+    }
+
+    #[test]
+    fn input_types_are_checked_before_the_command_runs() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        context.add_commands(vec![whole_stream_command(TableSink)]);
+        context.check_input_types.store(true, Ordering::SeqCst);
+        let run = |input: Value| {
+            run_command(
+                &context,
+                "table-sink",
+                InputStream::one(input),
+                ExternalRedirection::Stdout,
+            )
+        };
+
+        let row = TaggedDictBuilder::build(Tag::unknown(), |row| {
+            row.insert_untagged("name", UntaggedValue::string("nu"));
+        });
+        assert!(run(row).is_ok());
+
+        let err = run(UntaggedValue::string("not a table").into_untagged_value())
+            .expect_err("a string should be refused");
+        let diagnostic = err.into_diagnostic().expect("no diagnostic");
+        assert_eq!(
+            diagnostic.message,
+            "table-sink expects table input, but was given a string"
+        );
+        assert_eq!(diagnostic.labels[0].range, 0..10);
+    }
+
+    #[test]
+    fn input_checks_take_lines_as_strings_and_pass_errors_on() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        context.add_commands(vec![
+            whole_stream_command(TableSink),
+            whole_stream_command(StringSink),
+        ]);
+        context.check_input_types.store(true, Ordering::SeqCst);
+        let run = |name: &str, input: Value| {
+            run_command(
+                &context,
+                name,
+                InputStream::one(input),
+                ExternalRedirection::Stdout,
+            )
+        };
+
+        assert!(run(
+            "string-sink",
+            UntaggedValue::line("from an external").into_untagged_value()
+        )
+        .is_ok());
+        assert!(run("string-sink", UntaggedValue::int(1).into_untagged_value()).is_err());
+        assert!(run(
+            "table-sink",
+            UntaggedValue::Error(ShellError::untagged_runtime_error("upstream failed"))
+                .into_untagged_value()
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn buffered_output_is_collected_before_it_is_passed_on() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        let produced = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        context.add_commands(vec![whole_stream_command(Numbers {
+            produced: produced.clone(),
+        })]);
+        let run = |context: &EvaluationContext| {
+            let command =
+                InternalCommand::new("numbers".to_string(), Span::unknown(), Span::unknown());
+            block_on(async {
+                let stream = run_internal_command(command, context, InputStream::empty())
+                    .await
+                    .expect("could not run the internal command");
+                let produced_before_reading = produced.load(Ordering::SeqCst);
+                let values = stream.collect::<Vec<_>>().await;
+                produced.store(0, Ordering::SeqCst);
+                (produced_before_reading, values)
+            })
+        };
+
+        let (streamed_early, streamed) = run(&context);
+        context.buffer_output.store(true, Ordering::SeqCst);
+        let (buffered_early, buffered) = run(&context);
+
+        assert_eq!(streamed_early, 0);
+        assert_eq!(buffered_early, 3);
+        assert_eq!(buffered, streamed);
+        assert_eq!(buffered.len(), 3);
+    }
+
+    #[test]
+    fn set_env_from_file_loads_a_dotenv_file() {
+        Playground::setup("set_env_from_file_test", |dirs, sandbox| {
+            sandbox.with_files(vec![
+                FileWithContent(
+                    "project.env",
+                    r#"
+                        # project settings
+                        DB_HOST=localhost # the local database
+                        export DB_PORT=5432
+                        GREETING="hello \"world\""
+                        RAW='not # a comment'
+                    "#,
+                ),
+                FileWithContent("broken.env", "DB_HOST=localhost\nnot a var\n"),
+                FileWithContent("trailing.env", "DB_HOST=localhost\nGREETING=\"hi\" junk\n"),
+                FileWithContent(
+                    "rejected.env",
+                    "DB_HOST=localhost\nNU_LIB_DIRS=/no/such/directory\n",
+                ),
+            ]);
+
+            let context = EvaluationContext::basic().expect("could not create a basic context");
+            let file = |name: &str| {
+                dirs.test()
+                    .join(name)
+                    .display()
+                    .to_string()
+                    .tagged_unknown()
+            };
+
+            run_action(&context, CommandAction::SetEnvFromFile(file("project.env")));
+            assert!(context.get_errors().is_empty());
+            assert_eq!(
+                context.scope.get_env_var("DB_HOST"),
+                Some("localhost".to_string())
+            );
+            assert_eq!(
+                context.scope.get_env_var("DB_PORT"),
+                Some("5432".to_string())
+            );
+            assert_eq!(
+                context.scope.get_env_var("GREETING"),
+                Some("hello \"world\"".to_string())
+            );
+            assert_eq!(
+                context.scope.get_env_var("RAW"),
+                Some("not # a comment".to_string())
+            );
+
+            let context = EvaluationContext::basic().expect("could not create a basic context");
+            run_action(&context, CommandAction::SetEnvFromFile(file("broken.env")));
+            let errors = context.get_errors();
+            assert_eq!(errors.len(), 1);
+            assert!(format!("{:?}", errors[0]).contains("broken.env:2:1: expected KEY=VALUE"));
+            assert_eq!(context.scope.get_env_var("DB_HOST"), None);
+
+            let context = EvaluationContext::basic().expect("could not create a basic context");
+            run_action(
+                &context,
+                CommandAction::SetEnvFromFile(file("trailing.env")),
+            );
+            let diagnostic = context
+                .get_errors()
+                .pop()
+                .expect("trailing text should be an error")
+                .into_diagnostic()
+                .expect("expected a diagnostic error");
+            assert!(diagnostic
+                .message
+                .ends_with("trailing.env:2:15: unexpected text after the closing quote"));
+            assert_eq!(
+                diagnostic.notes,
+                vec!["GREETING=\"hi\" junk\n              ^"]
+            );
+            assert_eq!(context.scope.get_env_var("DB_HOST"), None);
+
+            let context = EvaluationContext::basic().expect("could not create a basic context");
+            run_action(
+                &context,
+                CommandAction::SetEnvFromFile(file("rejected.env")),
+            );
+            assert_eq!(context.get_errors().len(), 1);
+            assert_eq!(context.scope.get_env_var("DB_HOST"), None);
+        })
+    }
+
+    #[test]
+    fn set_config_value_is_seen_by_config_consumers() {
+        use nu_data::config::NuConfig;
+
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        let path = |text: &str| {
+            ColumnPath::new(
+                text.split('.')
+                    .map(|member| PathMember::string(member, Span::unknown()))
+                    .collect(),
+            )
+        };
+
+        run_action(
+            &context,
+            CommandAction::SetConfigValue {
+                path: path("session_only_test.line_color"),
+                value: UntaggedValue::string("green").into_untagged_value(),
+                persist: false,
+            },
+        );
+        assert!(context.get_errors().is_empty());
+        // What table rendering reads its settings from
+        let line_color = NuConfig::new()
+            .var("session_only_test")
+            .expect("config value should be set")
+            .get_data_by_column_path(&path("line_color"), Box::new(|_, _, e| e))
+            .expect("config value should be set")
+            .as_string()
+            .expect("config value should be a string");
+        assert_eq!(line_color, "green");
+        assert!(!nu_data::config::read(Tag::unknown(), &None)
+            .expect("could not read the config file")
+            .contains_key("session_only_test"));
+
+        run_action(
+            &context,
+            CommandAction::SetConfigValue {
+                path: path("session_only_test.line_color"),
+                value: UntaggedValue::int(3).into_untagged_value(),
+                persist: false,
+            },
+        );
+        let errors = context.get_errors();
+        assert_eq!(errors.len(), 1);
+        assert!(format!("{:?}", errors[0])
+            .contains("Can't set config session_only_test.line_color to a integer"));
+    }
+
+    #[test]
+    fn benchmark_times_a_block() {
+        let context =
+            crate::create_default_context(false).expect("could not create the default context");
+        let (block, err) = nu_parser::parse("echo 1 2 3", 0, &context.scope);
+        assert!(err.is_none());
+
+        let values = run_action(&context, CommandAction::Benchmark(block));
+
+        assert!(context.get_errors().is_empty());
+        assert_eq!(values.len(), 1);
+        assert!(matches!(
+            &values[0].value,
+            UntaggedValue::Primitive(Primitive::Duration(nanos)) if *nanos >= BigInt::from(0)
+        ));
+    }
+
+    #[test]
+    fn benchmark_fails_with_errors_the_block_reports() {
+        let context =
+            crate::create_default_context(false).expect("could not create the default context");
+        context.add_commands(vec![whole_stream_command(FailOnInput)]);
+        let (block, err) = nu_parser::parse("echo 1 | fail-on-input", 0, &context.scope);
+        assert!(err.is_none());
+
+        let values = run_action(&context, CommandAction::Benchmark(block));
+
+        assert!(values.iter().all(|value| value.value.is_none()));
+        let errors = context.get_errors();
+        assert_eq!(errors.len(), 1);
+        let diagnostic = errors[0]
+            .clone()
+            .into_diagnostic()
+            .expect("expected a diagnostic error");
+        assert_eq!(diagnostic.message, "Could not use 1 values");
+    }
+
+    #[test]
+    fn import_module_brings_in_only_exports() {
+        Playground::setup("import_module_test", |dirs, sandbox| {
+            sandbox.with_files(vec![FileWithContent(
+                "greetings.nu",
+                "def hello [] { echo hello }\ndef helper [] { echo helper }\nexport hello",
+            )]);
+
+            let context =
+                crate::create_default_context(false).expect("could not create the default context");
+            let module = dirs.test().join("greetings.nu").display().to_string();
+
+            run_action(
+                &context,
+                CommandAction::ImportModule {
+                    path: module.clone(),
+                    prefix: None,
+                },
+            );
+            assert!(context.get_errors().is_empty());
+            assert!(context.scope.has_command("hello"));
+            assert!(!context.scope.has_command("helper"));
+
+            run_action(
+                &context,
+                CommandAction::ImportModule {
+                    path: module,
+                    prefix: Some("greet".to_string()),
+                },
+            );
+            assert!(context.scope.has_command("greet::hello"));
+            assert!(!context.scope.has_command("greet::helper"));
+        })
+    }
+
+    #[test]
+    fn imported_commands_can_call_private_helpers() {
+        Playground::setup("import_module_helpers_test", |dirs, sandbox| {
+            sandbox.with_files(vec![FileWithContent(
+                "greetings.nu",
+                "def helper [] { echo hi }\ndef hello [] { helper }\nexport hello",
+            )]);
+
+            let context =
+                crate::create_default_context(false).expect("could not create the default context");
+            run_action(
+                &context,
+                CommandAction::ImportModule {
+                    path: dirs.test().join("greetings.nu").display().to_string(),
+                    prefix: Some("greet".to_string()),
+                },
+            );
+            assert!(!context.scope.has_command("helper"));
+
+            let (block, err) = nu_parser::parse("greet::hello", 0, &context.scope);
+            assert!(err.is_none());
+            let values: Vec<Value> = block_on(async {
+                super::run_block(&block, &context, InputStream::empty())
+                    .await
+                    .expect("could not run the imported command")
+                    .collect()
+                    .await
+            });
+
+            assert!(context.get_errors().is_empty());
+            assert_eq!(values.len(), 1);
+            assert_eq!(values[0].as_string().ok(), Some("hi".to_string()));
+        })
+    }
+
+    #[test]
+    fn source_script_searches_lib_dirs() {
+        Playground::setup("source_lib_dirs_test", |dirs, sandbox| {
+            sandbox.within("lib").with_files(vec![FileWithContent(
+                "helpers.nu",
+                "def helper [] { echo hi }",
+            )]);
+
+            let context =
+                crate::create_default_context(false).expect("could not create the default context");
+            context
+                .scope
+                .add_env_var("NU_LIB_DIRS", dirs.test().join("lib").display().to_string())
+                .expect("env var should be accepted");
+
+            run_action(
+                &context,
+                CommandAction::SourceScript {
+                    filename: "helpers".to_string().tagged_unknown(),
+                    summary: false,
+                },
+            );
+            assert!(context.get_errors().is_empty());
+            assert!(context.scope.has_command("helper"));
+
+            run_action(
+                &context,
+                CommandAction::SourceScript {
+                    filename: "missing".to_string().tagged_unknown(),
+                    summary: false,
+                },
+            );
+            let errors = context.get_errors();
+            assert_eq!(errors.len(), 1);
+            let message = errors[0]
+                .clone()
+                .into_diagnostic()
+                .expect("expected a diagnostic error")
+                .message;
+            assert!(message.contains(
+                &dirs
+                    .test()
+                    .join("lib")
+                    .join("missing.nu")
+                    .display()
+                    .to_string()
+            ));
+        })
+    }
+
+    #[test]
+    fn source_script_resolves_relative_to_sourcing_script() {
+        Playground::setup("source_relative_test", |dirs, sandbox| {
+            sandbox.within("scripts").with_files(vec![
+                FileWithContent("main.nu", "source lib.nu"),
+                FileWithContent("lib.nu", "set-env FROM_LIB = loaded"),
+            ]);
+
+            let context =
+                crate::create_default_context(false).expect("could not create the default context");
+            let main = dirs.test().join("scripts").join("main.nu");
+
+            run_action(
+                &context,
+                CommandAction::SourceScript {
+                    filename: main.display().to_string().tagged_unknown(),
+                    summary: false,
+                },
+            );
+
+            assert!(context.get_errors().is_empty());
+            assert_eq!(
+                context.scope.get_env_var("FROM_LIB"),
+                Some("loaded".to_string())
+            );
+        })
+    }
+
+    #[test]
+    fn script_dirs_stay_put_when_the_directory_changes() {
+        Playground::setup("script_dir_canonical_test", |dirs, sandbox| {
+            let _cwd = CwdGuard::new();
+            sandbox.within("scripts").with_files(vec![
+                FileWithContent("main.nu", ""),
+                FileWithContent("lib.nu", "set-env FROM_LIB = loaded"),
+            ]);
+
+            let context =
+                crate::create_default_context(false).expect("could not create the default context");
+            std::env::set_current_dir(dirs.test()).expect("could not enter the playground");
+            context.enter_script(std::path::Path::new("scripts/main.nu"));
+            std::env::set_current_dir(dirs.test().join("scripts"))
+                .expect("could not enter the scripts directory");
+
+            run_action(
+                &context,
+                CommandAction::SourceScript {
+                    filename: "lib.nu".to_string().tagged_unknown(),
+                    summary: false,
+                },
+            );
+            context.exit_script();
+
+            assert!(context.get_errors().is_empty());
+            assert_eq!(
+                context.scope.get_env_var("FROM_LIB"),
+                Some("loaded".to_string())
+            );
+        })
+    }
+
+    #[test]
+    fn redirect_sends_output_to_file() {
+        Playground::setup("redirect_file_test", |dirs, sandbox| {
+            sandbox.with_files(vec![FileWithContent("script.nu", "echo a b")]);
+
+            let context =
+                crate::create_default_context(false).expect("could not create the default context");
+            let out = dirs.test().join("out.txt");
+
+            run_action(
+                &context,
+                CommandAction::Redirect {
+                    target: RedirectTarget::File(out.clone()),
+                },
+            );
+            run_action(
+                &context,
+                CommandAction::SourceScript {
+                    filename: dirs
+                        .test()
+                        .join("script.nu")
+                        .display()
+                        .to_string()
+                        .tagged_unknown(),
+                    summary: false,
+                },
+            );
+
+            assert!(context.get_errors().is_empty());
+            assert_eq!(
+                std::fs::read_to_string(&out).expect("output file should exist"),
+                "a\nb\n"
+            );
+        })
+    }
+
+    #[test]
+    fn set_prompt_stores_string_prompt() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        let prompt = UntaggedValue::string("echo '> '").into_untagged_value();
+
+        run_action(&context, CommandAction::SetPrompt(prompt.clone()));
+
+        assert!(context.get_errors().is_empty());
+        assert_eq!(context.prompt(), Some(prompt));
+    }
+
+    #[test]
+    fn set_prompt_rejects_other_values() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+
+        run_action(
+            &context,
+            CommandAction::SetPrompt(UntaggedValue::int(1).into_untagged_value()),
+        );
+
+        assert_eq!(context.get_errors().len(), 1);
+        assert_eq!(context.prompt(), None);
+    }
+
+    #[test]
+    fn caught_errors_become_records() {
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        context.catch_errors.store(true, Ordering::SeqCst);
+
+        let output = emit(
+            &context,
+            ReturnSuccess::Value(
+                UntaggedValue::Error(ShellError::labeled_error(
+                    "boom",
+                    "went off here",
+                    Span::new(3, 7),
+                ))
+                .into_untagged_value(),
+            ),
+        );
+
+        assert!(context.get_errors().is_empty());
+        assert_eq!(output.len(), 1);
+
+        let record = &output[0];
+        let field = |name: &str| {
+            record
+                .get_data_by_key(name.spanned_unknown())
+                .expect("missing field")
+        };
+        assert_eq!(field("msg").as_string().expect("msg"), "boom");
+
+        let span = field("span");
+        let start = span.get_data_by_key("start".spanned_unknown());
+        assert_eq!(start.map(|v| v.as_i64().expect("start")), Some(3));
+
+        let labels = field("labels").table_entries().cloned().collect::<Vec<_>>();
+        assert_eq!(labels.len(), 1);
+        assert_eq!(
+            labels[0]
+                .get_data_by_key("msg".spanned_unknown())
+                .map(|v| v.as_string().expect("label")),
+            Some("went off here".to_string())
+        );
+    }
+
+    #[test]
+    fn errors_inside_blocks_are_caught_too() {
+        use crate::commands::Do;
+
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        context.add_commands(vec![
+            whole_stream_command(Do),
+            whole_stream_command(Emit {
+                name: "fail",
+                output: Err(ShellError::untagged_runtime_error("boom")),
+            }),
+        ]);
+        context.catch_errors.store(true, Ordering::SeqCst);
+
+        block_on(crate::script::process_script(
+            "do { fail }",
+            &context,
+            false,
+            0,
+            false,
+        ));
+
+        assert!(context.get_errors().is_empty());
+    }
+
+    #[test]
+    fn run_external_in_background_returns_job_id_and_reaps_finished_jobs() {
+        use crate::evaluation_context::JOBS;
+
+        let context = EvaluationContext::basic().expect("could not create a basic context");
+        let spawn = || {
+            let (command, args) = if cfg!(windows) {
+                ("cmd", vec!["/C".to_string(), "exit".to_string()])
+            } else {
+                ("true", vec![])
+            };
+            let output = run_action(
+                &context,
+                CommandAction::RunExternalInBackground {
+                    command: command.to_string(),
+                    args,
+                },
+            );
+            assert!(context.get_errors().is_empty());
+            output[0].as_u64().expect("job id is not a number") as usize
+        };
+
+        let first = spawn();
+        assert!(JOBS.lock().contains_key(&first));
+        while let Some(job) = JOBS.lock().get_mut(&first) {
+            if job.try_wait().expect("could not poll the job").is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let second = spawn();
+        assert!(second > first);
+        assert!(!JOBS.lock().contains_key(&first));
+    }
+
+    #[test]
+    fn save_history_now_writes_the_running_line() {
+        Playground::setup("save_history_now_test", |dirs, _| {
+            let context = EvaluationContext::basic().expect("could not create a basic context");
+            let history_path = dirs.test().join("history.txt");
+            context.set_pending_history(history_path.clone(), "rm -r stuff".to_string());
+
+            run_action(&context, CommandAction::SaveHistoryNow);
+            run_action(&context, CommandAction::SaveHistoryNow);
+
+            assert!(context.get_errors().is_empty());
+            assert_eq!(
+                std::fs::read_to_string(history_path).expect("history was not written"),
+                "#V2\nrm -r stuff\n"
+            );
+        })
+    }
+
+    #[cfg(unix)]
+    fn mock_plugin(dir: &std::path::Path, name: &str, protocol_version: u32) {
+        use nu_plugin::jsonrpc::JsonRpc;
+        use nu_protocol::Signature;
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut response = JsonRpc::new("response", Ok::<_, ShellError>(Signature::build(name)));
+        response.protocol_version = protocol_version;
+        let response = serde_json::to_string(&response).expect("could not serialize the config");
+
+        let path = dir.join(format!("nu_plugin_{}", name));
+        std::fs::write(
+            &path,
+            format!("#!/bin/sh\nread line\necho '{}'\n", response),
+        )
+        .expect("could not write the mock plugin");
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))
+            .expect("could not make the mock plugin executable");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn add_plugins_skips_unsupported_protocol_versions() {
+        use nu_plugin::jsonrpc::PLUGIN_PROTOCOL_VERSION;
+
+        Playground::setup("add_plugins_version_test", |dirs, _| {
+            let mut context = EvaluationContext::basic().expect("could not create a basic context");
+            let err = capture_host(&mut context).err;
+            mock_plugin(dirs.test(), "current", PLUGIN_PROTOCOL_VERSION);
+            mock_plugin(dirs.test(), "future", PLUGIN_PROTOCOL_VERSION + 1);
+
+            run_action(
+                &context,
+                CommandAction::AddPlugins(dirs.test().display().to_string()),
+            );
+
+            assert!(context.is_command_registered("current"));
+            assert!(!context.is_command_registered("future"));
+            assert!(context.get_errors().is_empty());
+            assert!(err.lock().starts_with(
+                "warning: skipped plugin 'future': it speaks plugin protocol version"
+            ));
+        })
+    }
 }
@@ -45,6 +45,7 @@ pub struct PluginCommandBuilder {
     name: String,
     path: String,
     config: Signature,
+    protocol_version: u32,
 }
 
 impl PluginCommandBuilder {
@@ -52,6 +53,7 @@ impl PluginCommandBuilder {
         name: impl Into<String>,
         path: impl Into<String>,
         config: impl Into<Signature>,
+        protocol_version: u32,
     ) -> Self {
         let config = config.into();
 
@@ -64,6 +66,7 @@ impl PluginCommandBuilder {
             name: name.into(),
             path: path.into(),
             config,
+            protocol_version,
         }
     }
 
@@ -73,10 +76,21 @@ impl PluginCommandBuilder {
         let name = self.name.clone();
         let path = self.path.clone();
         let config = self.config.clone();
+        let protocol_version = self.protocol_version;
 
         let cmd = match mode {
-            PluginMode::Filter => PluginCommand::Filter(PluginFilter { name, path, config }),
-            PluginMode::Sink => PluginCommand::Sink(PluginSink { name, path, config }),
+            PluginMode::Filter => PluginCommand::Filter(PluginFilter {
+                name,
+                path,
+                config,
+                protocol_version,
+            }),
+            PluginMode::Sink => PluginCommand::Sink(PluginSink {
+                name,
+                path,
+                config,
+                protocol_version,
+            }),
         };
 
         cmd.command()
@@ -88,6 +102,7 @@ pub struct PluginFilter {
     name: String,
     path: String,
     config: Signature,
+    protocol_version: u32,
 }
 
 #[async_trait]
@@ -104,6 +119,10 @@ impl WholeStreamCommand for PluginFilter {
         &self.config.usage
     }
 
+    fn plugin_protocol_version(&self) -> Option<u32> {
+        Some(self.protocol_version)
+    }
+
     async fn run(&self, args: CommandArgs) -> Result<OutputStream, ShellError> {
         run_filter(self.path.clone(), (args)).await
     }
@@ -363,6 +382,7 @@ pub struct PluginSink {
     name: String,
     path: String,
     config: Signature,
+    protocol_version: u32,
 }
 
 #[async_trait]
@@ -379,6 +399,10 @@ impl WholeStreamCommand for PluginSink {
         &self.config.usage
     }
 
+    fn plugin_protocol_version(&self) -> Option<u32> {
+        Some(self.protocol_version)
+    }
+
     async fn run(&self, args: CommandArgs) -> Result<OutputStream, ShellError> {
         run_sink(self.path.clone(), args).await
     }
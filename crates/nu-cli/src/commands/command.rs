@@ -42,6 +42,7 @@ pub struct CommandArgs {
     pub shell_manager: ShellManager,
     pub call_info: UnevaluatedCallInfo,
     pub scope: Scope,
+    pub session: Session,
     pub input: InputStream,
 }
 
@@ -53,6 +54,7 @@ pub struct RawCommandArgs {
     pub current_errors: Arc<Mutex<Vec<ShellError>>>,
     pub shell_manager: ShellManager,
     pub scope: Scope,
+    pub session: Session,
     pub call_info: UnevaluatedCallInfo,
 }
 
@@ -65,6 +67,7 @@ impl RawCommandArgs {
             shell_manager: self.shell_manager,
             call_info: self.call_info,
             scope: self.scope,
+            session: self.session,
             input: input.into(),
         }
     }
@@ -113,6 +116,7 @@ pub struct RunnableContext {
     pub ctrl_c: Arc<AtomicBool>,
     pub current_errors: Arc<Mutex<Vec<ShellError>>>,
     pub scope: Scope,
+    pub session: Session,
     pub name: Tag,
 }
 
@@ -231,6 +235,11 @@ pub trait WholeStreamCommand: Send + Sync {
         false
     }
 
+    // The plugin protocol version spoken by commands that come from plugins
+    fn plugin_protocol_version(&self) -> Option<u32> {
+        None
+    }
+
     fn examples(&self) -> Vec<Example> {
         Vec::new()
     }
@@ -259,23 +268,13 @@ impl WholeStreamCommand for Block {
         let mut block = self.clone();
         block.set_redirect(call_info.args.external_redirection);
 
+        let call_span = call_info.args.span;
         let ctx = EvaluationContext::from_args(&args);
         let evaluated = call_info.evaluate(&ctx).await?;
 
         let input = args.input;
-        ctx.scope.enter_scope();
-        if let Some(args) = evaluated.args.positional {
-            // FIXME: do not do this
-            for arg in args.into_iter().zip(self.params.positional.iter()) {
-                let name = arg.1 .0.name();
-
-                if name.starts_with('$') {
-                    ctx.scope.add_var(name, arg.0);
-                } else {
-                    ctx.scope.add_var(format!("${}", name), arg.0);
-                }
-            }
-        }
+        let positional = evaluated.args.positional.unwrap_or_default();
+        ctx.scope.bind_params(&block, &positional, call_span)?;
         if let Some(args) = evaluated.args.named {
             for named in &block.params.named {
                 let name = named.0;
@@ -385,6 +384,10 @@ impl Command {
         self.0.is_internal()
     }
 
+    pub fn plugin_protocol_version(&self) -> Option<u32> {
+        self.0.plugin_protocol_version()
+    }
+
     pub fn stream_command(&self) -> &dyn WholeStreamCommand {
         &*self.0
     }
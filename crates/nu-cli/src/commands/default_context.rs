@@ -14,6 +14,7 @@ pub fn create_default_context(interactive: bool) -> Result<EvaluationContext, Bo
             whole_stream_command(SetEnv),
             whole_stream_command(Def),
             whole_stream_command(Source),
+            whole_stream_command(Export),
             // System/file operations
             whole_stream_command(Exec),
             whole_stream_command(Pwd),
@@ -65,6 +66,7 @@ pub fn create_default_context(interactive: bool) -> Result<EvaluationContext, Bo
             whole_stream_command(Shells),
             whole_stream_command(Enter),
             whole_stream_command(Exit),
+            whole_stream_command(Repl),
             // Viz
             whole_stream_command(Chart),
             // Viewers
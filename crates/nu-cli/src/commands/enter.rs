@@ -15,6 +15,7 @@ pub struct Enter;
 pub struct EnterArgs {
     location: Tagged<PathBuf>,
     encoding: Option<Tagged<String>>,
+    isolate: bool,
 }
 
 #[async_trait]
@@ -36,6 +37,11 @@ impl WholeStreamCommand for Enter {
                 "encoding to use to open file",
                 Some('e'),
             )
+            .switch(
+                "isolate",
+                "start a directory shell with only PATH and HOME from the environment",
+                Some('i'),
+            )
     }
 
     fn usage(&self) -> &str {
@@ -65,6 +71,11 @@ documentation link at https://docs.rs/encoding_rs/0.8.23/encoding_rs/#statics"#
                 example: "enter package.json",
                 result: None,
             },
+            Example {
+                description: "Enter a path as a new shell that doesn't see the current environment",
+                example: "enter ../projectB --isolate",
+                result: None,
+            },
             Example {
                 description: "Enters file with iso-8859-1 encoding",
                 example: "enter file.csv --encoding iso-8859-1",
@@ -81,8 +92,16 @@ async fn enter(raw_args: CommandArgs) -> Result<OutputStream, ShellError> {
     let ctrl_c = raw_args.ctrl_c.clone();
     let current_errors = raw_args.current_errors.clone();
     let host = raw_args.host.clone();
+    let session = raw_args.session.clone();
     let tag = raw_args.call_info.name_tag.clone();
-    let (EnterArgs { location, encoding }, _) = raw_args.process().await?;
+    let (
+        EnterArgs {
+            location,
+            encoding,
+            isolate,
+        },
+        _,
+    ) = raw_args.process().await?;
     let location_string = location.display().to_string();
     let location_clone = location_string.clone();
 
@@ -105,7 +124,10 @@ async fn enter(raw_args: CommandArgs) -> Result<OutputStream, ShellError> {
         )))
     } else if location.is_dir() {
         Ok(OutputStream::one(ReturnSuccess::action(
-            CommandAction::EnterShell(location_clone),
+            CommandAction::EnterShell {
+                location: location_clone,
+                isolate_env: isolate,
+            },
         )))
     } else {
         // If it's a file, attempt to open the file as a value and enter it
@@ -143,6 +165,7 @@ async fn enter(raw_args: CommandArgs) -> Result<OutputStream, ShellError> {
                                 name_tag: tag.clone(),
                             },
                             scope: scope.clone(),
+                            session,
                         };
                         let tag = tagged_contents.tag.clone();
                         let mut result = converter
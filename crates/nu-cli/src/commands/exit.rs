@@ -12,7 +12,13 @@ impl WholeStreamCommand for Exit {
     }
 
     fn signature(&self) -> Signature {
-        Signature::build("exit").switch("now", "exit out of the shell immediately", Some('n'))
+        Signature::build("exit")
+            .switch("now", "exit out of the shell immediately", Some('n'))
+            .switch(
+                "keep-env",
+                "keep the environment variables set inside the shell",
+                Some('k'),
+            )
     }
 
     fn usage(&self) -> &str {
@@ -30,6 +36,11 @@ impl WholeStreamCommand for Exit {
                 example: "exit",
                 result: None,
             },
+            Example {
+                description: "Exit the current shell, keeping the environment variables set in it",
+                example: "exit --keep-env",
+                result: None,
+            },
             Example {
                 description: "Exit all shells (exiting Nu)",
                 example: "exit --now",
@@ -45,7 +56,9 @@ pub async fn exit(args: CommandArgs) -> Result<OutputStream, ShellError> {
     let command_action = if args.call_info.args.has("now") {
         CommandAction::Exit
     } else {
-        CommandAction::LeaveShell
+        CommandAction::LeaveShell {
+            keep_env: args.call_info.args.has("keep-env"),
+        }
     };
 
     Ok(OutputStream::one(ReturnSuccess::action(command_action)))
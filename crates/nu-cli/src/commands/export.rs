@@ -0,0 +1,54 @@
+use crate::commands::WholeStreamCommand;
+use crate::prelude::*;
+
+use nu_errors::ShellError;
+use nu_protocol::{Signature, SyntaxShape};
+use nu_source::Tagged;
+
+pub struct Export;
+
+#[derive(Deserialize)]
+pub struct ExportArgs {
+    pub rest: Vec<Tagged<String>>,
+}
+
+#[async_trait]
+impl WholeStreamCommand for Export {
+    fn name(&self) -> &str {
+        "export"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build("export").rest(
+            SyntaxShape::String,
+            "the names of the commands, variables and environment variables to export",
+        )
+    }
+
+    fn usage(&self) -> &str {
+        "Mark definitions of a module as visible to the scripts importing it."
+    }
+
+    async fn run(&self, args: CommandArgs) -> Result<OutputStream, ShellError> {
+        export(args).await
+    }
+
+    fn examples(&self) -> Vec<Example> {
+        vec![Example {
+            description: "Export the greet command and the $greeting variable",
+            example: "export greet greeting",
+            result: None,
+        }]
+    }
+}
+
+pub async fn export(args: CommandArgs) -> Result<OutputStream, ShellError> {
+    let scope = args.scope.clone();
+    let (ExportArgs { rest }, _) = args.process().await?;
+
+    for name in rest {
+        scope.mark_exported(name.item);
+    }
+
+    Ok(OutputStream::empty())
+}
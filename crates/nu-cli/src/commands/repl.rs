@@ -0,0 +1,35 @@
+use crate::commands::command::WholeStreamCommand;
+use crate::prelude::*;
+use nu_errors::ShellError;
+use nu_protocol::{CommandAction, ReturnSuccess, Signature};
+
+pub struct Repl;
+
+#[async_trait]
+impl WholeStreamCommand for Repl {
+    fn name(&self) -> &str {
+        "repl"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build("repl")
+    }
+
+    fn usage(&self) -> &str {
+        "Start a nested prompt sharing the current scope. Enter exit to return."
+    }
+
+    async fn run(&self, _args: CommandArgs) -> Result<OutputStream, ShellError> {
+        Ok(OutputStream::one(ReturnSuccess::action(
+            CommandAction::EnterReplSubshell,
+        )))
+    }
+
+    fn examples(&self) -> Vec<Example> {
+        vec![Example {
+            description: "Try commands out in a nested prompt, then carry on",
+            example: "repl; echo back",
+            result: None,
+        }]
+    }
+}
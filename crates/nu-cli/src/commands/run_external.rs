@@ -79,17 +79,14 @@ impl WholeStreamCommand for RunExternalCommand {
             })
             .and_then(spanned_expression_to_string)?;
 
-        let mut external_context = {
-            EvaluationContext {
-                scope: args.scope.clone(),
-                host: args.host.clone(),
-                user_recently_used_autoenv_untrust: Arc::new(AtomicBool::new(false)),
-                shell_manager: args.shell_manager.clone(),
-                ctrl_c: args.ctrl_c.clone(),
-                current_errors: Arc::new(Mutex::new(vec![])),
-                windows_drives_previous_cwd: Arc::new(Mutex::new(std::collections::HashMap::new())),
-            }
-        };
+        let mut external_context = EvaluationContext::in_session(
+            args.scope.clone(),
+            args.host.clone(),
+            Arc::new(Mutex::new(vec![])),
+            args.ctrl_c.clone(),
+            args.shell_manager.clone(),
+            args.session.clone(),
+        );
 
         let is_interactive = self.interactive;
 
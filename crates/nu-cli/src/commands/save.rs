@@ -166,6 +166,7 @@ async fn save(raw_args: CommandArgs) -> Result<OutputStream, ShellError> {
     let ctrl_c = raw_args.ctrl_c.clone();
     let current_errors = raw_args.current_errors.clone();
     let shell_manager = raw_args.shell_manager.clone();
+    let session = raw_args.session.clone();
 
     let head = raw_args.call_info.args.head.clone();
     let (
@@ -226,6 +227,7 @@ async fn save(raw_args: CommandArgs) -> Result<OutputStream, ShellError> {
                             name_tag: name_tag.clone(),
                         },
                         scope,
+                        session,
                     };
                     let mut result = converter.run(new_args.with_input(input)).await?;
                     let result_vec: Vec<Result<ReturnSuccess, ShellError>> =
@@ -1,8 +1,7 @@
 use crate::commands::WholeStreamCommand;
 use crate::prelude::*;
 use nu_errors::ShellError;
-use nu_protocol::{Signature, TaggedDictBuilder};
-use std::sync::atomic::Ordering;
+use nu_protocol::{Signature, TaggedDictBuilder, UntaggedValue, Value};
 
 pub struct Shells;
 
@@ -26,24 +25,28 @@ impl WholeStreamCommand for Shells {
 }
 
 fn shells(args: CommandArgs) -> Result<OutputStream, ShellError> {
-    let mut shells_out = VecDeque::new();
-    let tag = args.call_info.name_tag;
-
-    for (index, shell) in args.shell_manager.shells.lock().iter().enumerate() {
-        let mut dict = TaggedDictBuilder::new(&tag);
-
-        if index == (*args.shell_manager.current_shell).load(Ordering::SeqCst) {
-            dict.insert_untagged("active", true);
-        } else {
-            dict.insert_untagged("active", false);
-        }
-        dict.insert_untagged("name", shell.name());
-        dict.insert_untagged("path", shell.path());
-
-        shells_out.push_back(dict.into_value());
-    }
+    Ok(shell_rows(&args.shell_manager, &args.call_info.name_tag).into())
+}
 
-    Ok(shells_out.into())
+/// One row per open shell, in the order they are cycled through
+pub(crate) fn shell_rows(shell_manager: &ShellManager, tag: &Tag) -> Vec<Value> {
+    let current = shell_manager.current_shell();
+
+    shell_manager
+        .shells
+        .lock()
+        .iter()
+        .enumerate()
+        .map(|(index, shell)| {
+            TaggedDictBuilder::build(tag, |row| {
+                row.insert_untagged("index", UntaggedValue::int(index as i64));
+                row.insert_untagged("active", index == current);
+                row.insert_untagged("name", shell.name());
+                row.insert_untagged("path", shell.path());
+                row.insert_untagged("inspect", shell.inspected_from().is_some());
+            })
+        })
+        .collect()
 }
 
 #[cfg(test)]
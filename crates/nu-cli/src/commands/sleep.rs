@@ -85,7 +85,7 @@ impl WholeStreamCommand for Sleep {
     }
 }
 
-struct SleepFuture {
+pub(crate) struct SleepFuture {
     shared_state: Arc<Mutex<SharedState>>,
 }
 
@@ -10,6 +10,7 @@ pub struct Source;
 #[derive(Deserialize)]
 pub struct SourceArgs {
     pub filename: Tagged<String>,
+    pub summary: bool,
 }
 
 #[async_trait]
@@ -19,11 +20,17 @@ impl WholeStreamCommand for Source {
     }
 
     fn signature(&self) -> Signature {
-        Signature::build("source").required(
-            "filename",
-            SyntaxShape::String,
-            "the filepath to the script file to source",
-        )
+        Signature::build("source")
+            .required(
+                "filename",
+                SyntaxShape::String,
+                "the filepath to the script file to source",
+            )
+            .switch(
+                "summary",
+                "output how many commands and aliases the script defined",
+                Some('s'),
+            )
     }
 
     fn usage(&self) -> &str {
@@ -40,9 +47,9 @@ impl WholeStreamCommand for Source {
 }
 
 pub async fn source(args: CommandArgs) -> Result<OutputStream, ShellError> {
-    let (SourceArgs { filename }, _) = args.process().await?;
+    let (SourceArgs { filename, summary }, _) = args.process().await?;
 
     Ok(OutputStream::one(ReturnSuccess::action(
-        CommandAction::SourceScript(filename),
+        CommandAction::SourceScript { filename, summary },
     )))
 }
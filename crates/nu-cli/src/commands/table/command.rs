@@ -185,7 +185,10 @@ async fn table(
 
     let mut delay_slot = None;
 
-    let term_width = args.host.lock().width();
+    let term_width = args
+        .scope
+        .render_width()
+        .unwrap_or_else(|| args.host.lock().width());
 
     while !finished {
         let mut new_input: VecDeque<Value> = VecDeque::new();
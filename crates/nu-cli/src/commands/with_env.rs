@@ -105,7 +105,10 @@ async fn with_env(raw_args: CommandArgs) -> Result<OutputStream, ShellError> {
     };
 
     context.scope.enter_scope();
-    context.scope.add_env(env);
+    if let Err(err) = context.scope.add_env(env) {
+        context.scope.exit_scope();
+        return Err(err);
+    }
     context.scope.add_vars(&block.captured.entries);
 
     let result = run_block(&block.block, &context, input).await;
@@ -12,12 +12,28 @@ pub trait Host: Debug + Send {
     fn stdout(&mut self, out: &str);
     fn stderr(&mut self, out: &str);
 
+    /// Write to stdout without adding a newline. Hosts that can't do that fall back to `stdout`.
+    fn stdout_raw(&mut self, out: &str) {
+        self.stdout(out)
+    }
+
     fn vars(&mut self) -> Vec<(String, String)>;
     fn env_get(&mut self, key: OsString) -> Option<OsString>;
     fn env_set(&mut self, k: OsString, v: OsString);
     fn env_rm(&mut self, k: OsString);
 
     fn width(&self) -> usize;
+
+    /// Whether stdout is a terminal. Hosts that can't tell say it isn't.
+    fn stdout_is_tty(&self) -> bool {
+        false
+    }
+
+    /// Show the prompt and read a line of input, or None once there is no more.
+    /// Hosts that don't take input have none.
+    fn read_line(&mut self, _prompt: &str) -> Option<String> {
+        None
+    }
 }
 
 impl Host for Box<dyn Host> {
@@ -29,6 +45,10 @@ impl Host for Box<dyn Host> {
         (**self).stderr(out)
     }
 
+    fn stdout_raw(&mut self, out: &str) {
+        (**self).stdout_raw(out)
+    }
+
     fn vars(&mut self) -> Vec<(String, String)> {
         (**self).vars()
     }
@@ -56,6 +76,14 @@ impl Host for Box<dyn Host> {
     fn width(&self) -> usize {
         (**self).width()
     }
+
+    fn stdout_is_tty(&self) -> bool {
+        (**self).stdout_is_tty()
+    }
+
+    fn read_line(&mut self, prompt: &str) -> Option<String> {
+        (**self).read_line(prompt)
+    }
 }
 
 #[derive(Debug)]
@@ -76,6 +104,11 @@ impl Host for BasicHost {
         }
     }
 
+    fn stdout_raw(&mut self, out: &str) {
+        out!("{}", out);
+        let _ = std::io::Write::flush(&mut std::io::stdout());
+    }
+
     #[allow(unused_variables)]
     fn vars(&mut self) -> Vec<(String, String)> {
         #[cfg(not(target_arch = "wasm32"))]
@@ -130,6 +163,19 @@ impl Host for BasicHost {
         term_width -= 1;
         term_width
     }
+
+    fn stdout_is_tty(&self) -> bool {
+        atty::is(atty::Stream::Stdout)
+    }
+
+    fn read_line(&mut self, prompt: &str) -> Option<String> {
+        self.stdout_raw(prompt);
+        let mut line = String::new();
+        match std::io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -159,6 +205,10 @@ impl Host for FakeHost {
         self.line_written = out.to_string();
     }
 
+    fn stdout_raw(&mut self, out: &str) {
+        self.line_written = out.to_string();
+    }
+
     fn vars(&mut self) -> Vec<(String, String)> {
         self.env_vars
             .iter()
@@ -198,6 +248,14 @@ impl Host for FakeHost {
     fn width(&self) -> usize {
         1
     }
+
+    fn stdout_is_tty(&self) -> bool {
+        false
+    }
+
+    fn read_line(&mut self, _prompt: &str) -> Option<String> {
+        None
+    }
 }
 
 // pub(crate) fn handle_unexpected<T>(
@@ -221,7 +221,7 @@ fn evaluate_literal(literal: &hir::Literal, span: Span) -> Value {
 
 fn evaluate_reference(name: &str, ctx: &EvaluationContext, tag: Tag) -> Result<Value, ShellError> {
     match name {
-        "$nu" => crate::evaluate::variables::nu(&ctx.scope.get_env_vars(), tag),
+        "$nu" => crate::evaluate::variables::nu(&ctx.scope.get_visible_env_vars(), tag),
 
         "$true" => Ok(Value {
             value: UntaggedValue::boolean(true),
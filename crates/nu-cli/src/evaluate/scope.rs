@@ -1,12 +1,337 @@
 use crate::prelude::*;
 use crate::{commands::Command, whole_stream_command};
+use indexmap::IndexSet;
 use nu_parser::ParserScope;
-use nu_protocol::{hir::Block, Value};
-use nu_source::Spanned;
+use nu_protocol::{
+    hir::{Block, ClassifiedCommand, Expression, Literal, Number},
+    ColumnPath, FromValue, PositionalType, TaggedDictBuilder, UntaggedValue, Value,
+};
+use nu_source::{Span, Spanned};
+use std::ffi::OsString;
+use std::sync::atomic::AtomicU64;
+
+/// The separator used between entries of PATH-like environment variables
+#[cfg(windows)]
+pub const ENV_PATH_SEPARATOR: &str = ";";
+#[cfg(not(windows))]
+pub const ENV_PATH_SEPARATOR: &str = ":";
+
+/// Env vars named with this prefix are nu's own bookkeeping, hidden when the env is displayed
+pub const INTERNAL_ENV_PREFIX: &str = "NU_INTERNAL_";
+
+/// The env var holding the exit code of the last external command, for scripts to branch on
+pub const LAST_EXIT_CODE: &str = "LAST_EXIT_CODE";
+
+/// The env vars carried into a frame that isolates the env
+pub const ISOLATED_ENV_ALLOWLIST: &[&str] = &["PATH", "HOME"];
+
+/// The frames whose env is visible: those from the innermost env-isolating frame inwards
+fn env_frames(frames: &[ScopeFrame]) -> &[ScopeFrame] {
+    let start = frames
+        .iter()
+        .rposition(|frame| frame.isolated_env)
+        .unwrap_or(0);
+    &frames[start..]
+}
+
+/// The env var set by a `set-env NAME = value` command whose value is a literal, or an error for
+/// any other command
+fn env_assignment(command: &ClassifiedCommand) -> Result<(String, String), ShellError> {
+    let internal = match command {
+        ClassifiedCommand::Internal(internal) if internal.name == "set-env" => internal,
+        ClassifiedCommand::Internal(internal) => {
+            return Err(ShellError::labeled_error(
+                format!("'{}' can't be run from an env block", internal.name),
+                "only set-env is allowed here",
+                internal.name_span,
+            ))
+        }
+        ClassifiedCommand::Dynamic(call) => {
+            return Err(ShellError::labeled_error(
+                "Only set-env can be run from an env block",
+                "not allowed here",
+                call.span,
+            ))
+        }
+        ClassifiedCommand::Expr(expr) => {
+            return Err(ShellError::labeled_error(
+                "Only set-env can be run from an env block",
+                "not allowed here",
+                expr.span,
+            ))
+        }
+        ClassifiedCommand::Error(err) => return Err(err.clone().into()),
+    };
+
+    let not_literal = || {
+        ShellError::labeled_error(
+            "Env block values have to be written out literally",
+            "expected a literal value",
+            internal.args.span,
+        )
+    };
+    let positional = internal.args.positional.as_deref().unwrap_or_default();
+    let (name, value) = match positional {
+        [name, _, value] => (name, value),
+        _ => return Err(not_literal()),
+    };
+    let name = match &name.expr {
+        Expression::Literal(Literal::String(name)) => name.clone(),
+        _ => return Err(not_literal()),
+    };
+    let value = match &value.expr {
+        Expression::Block(block) => match block.block.as_slice() {
+            [group] => match group.pipelines.as_slice() {
+                [pipeline] => match pipeline.list.as_slice() {
+                    [ClassifiedCommand::Expr(expr)] => &expr.expr,
+                    _ => return Err(not_literal()),
+                },
+                _ => return Err(not_literal()),
+            },
+            _ => return Err(not_literal()),
+        },
+        _ => return Err(not_literal()),
+    };
+    let value = match value {
+        Expression::Literal(Literal::String(text)) | Expression::Literal(Literal::Bare(text)) => {
+            text.clone()
+        }
+        Expression::Literal(Literal::Number(Number::Int(int))) => int.to_string(),
+        Expression::Literal(Literal::Number(Number::Decimal(decimal))) => decimal.to_string(),
+        _ => return Err(not_literal()),
+    };
+
+    Ok((name, value))
+}
+
+/// How many aliases an alias may expand through before it's rejected
+const MAX_ALIAS_DEPTH: usize = 64;
+
+/// How environment variable names are compared when they are set, read or removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvVarCase {
+    Sensitive,
+    /// Names are folded for comparison, but keep the casing they were first set with
+    Insensitive,
+}
+
+impl Default for EnvVarCase {
+    fn default() -> Self {
+        if cfg!(windows) {
+            EnvVarCase::Insensitive
+        } else {
+            EnvVarCase::Sensitive
+        }
+    }
+}
+
+impl EnvVarCase {
+    fn find_key<V>(self, env: &IndexMap<String, V>, name: &str) -> Option<String> {
+        match self {
+            EnvVarCase::Sensitive => env.get_key_value(name).map(|(key, _)| key.clone()),
+            EnvVarCase::Insensitive => env
+                .keys()
+                .find(|key| key.eq_ignore_ascii_case(name))
+                .cloned(),
+        }
+    }
+
+    fn has_prefix(self, name: &str, prefix: &str) -> bool {
+        match self {
+            EnvVarCase::Sensitive => name.starts_with(prefix),
+            EnvVarCase::Insensitive => matches!(
+                name.get(..prefix.len()),
+                Some(start) if start.eq_ignore_ascii_case(prefix)
+            ),
+        }
+    }
+
+    fn insert<V>(self, env: &mut IndexMap<String, V>, name: String, value: V) {
+        let key = self.find_key(env, &name).unwrap_or(name);
+        env.insert(key, value);
+    }
+}
+
+/// An env var's value, either set directly or recomputed every time it is read
+#[derive(Clone)]
+pub enum EnvValue {
+    Literal(String),
+    Computed(Arc<dyn Fn() -> String + Send + Sync>),
+    /// A string where each `($NAME)` is replaced by the env var `NAME` when read
+    Template(String),
+}
+
+impl std::fmt::Debug for EnvValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnvValue::Literal(value) => write!(f, "{:?}", value),
+            EnvValue::Computed(_) => write!(f, "<computed>"),
+            EnvValue::Template(template) => write!(f, "<template {:?}>", template),
+        }
+    }
+}
+
+/// The names of the entries of one kind that were added, removed, or given a different value
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EntryDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl EntryDiff {
+    fn between<V>(
+        before: &IndexMap<String, V>,
+        after: &IndexMap<String, V>,
+        same: impl Fn(&V, &V) -> bool,
+    ) -> EntryDiff {
+        let mut diff = EntryDiff::default();
+        for (name, value) in after {
+            match before.get(name) {
+                None => diff.added.push(name.clone()),
+                Some(old) if !same(old, value) => diff.changed.push(name.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.removed = before
+            .keys()
+            .filter(|name| !after.contains_key(*name))
+            .cloned()
+            .collect();
+
+        diff
+    }
+
+    fn to_value(&self, tag: &Tag) -> Value {
+        let names = |names: &[String]| {
+            UntaggedValue::table(
+                &names
+                    .iter()
+                    .map(|name| UntaggedValue::string(name).into_value(tag))
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        TaggedDictBuilder::build(tag, |row| {
+            row.insert_untagged("added", names(&self.added));
+            row.insert_untagged("removed", names(&self.removed));
+            row.insert_untagged("changed", names(&self.changed));
+        })
+    }
+}
+
+/// What differs between the outermost frames of two scopes
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScopeDiff {
+    pub vars: EntryDiff,
+    pub env: EntryDiff,
+    pub commands: EntryDiff,
+    pub custom_commands: EntryDiff,
+    pub aliases: EntryDiff,
+}
+
+impl ScopeDiff {
+    pub fn to_value(&self, tag: impl Into<Tag>) -> Value {
+        let tag = tag.into();
+        TaggedDictBuilder::build(&tag, |row| {
+            row.insert_value("vars", self.vars.to_value(&tag));
+            row.insert_value("env", self.env.to_value(&tag));
+            row.insert_value("commands", self.commands.to_value(&tag));
+            row.insert_value("custom_commands", self.custom_commands.to_value(&tag));
+            row.insert_value("aliases", self.aliases.to_value(&tag));
+        })
+    }
+}
+
+/// How many of each kind of item a frame, or the scope as a whole, holds
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScopeCounts {
+    pub commands: usize,
+    pub custom_commands: usize,
+    pub aliases: usize,
+    pub vars: usize,
+    pub env_vars: usize,
+}
+
+/// Counts for each frame, outermost first, and for what is visible once shadowing is applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScopeStats {
+    pub frames: Vec<ScopeCounts>,
+    pub visible: ScopeCounts,
+}
+
+/// Checks a value before it is stored in an env var, returning why it was rejected
+pub type EnvValidator = Arc<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
+#[derive(Clone, Default)]
+struct EnvValidators(IndexMap<String, EnvValidator>);
+
+impl std::fmt::Debug for EnvValidators {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.0.keys()).finish()
+    }
+}
+
+/// Validate a PATH-like value, where every entry has to be an existing directory
+fn validate_directory_list(value: &str) -> Result<(), String> {
+    match value
+        .split(ENV_PATH_SEPARATOR)
+        .filter(|entry| !entry.is_empty())
+        .find(|entry| !std::path::Path::new(entry).is_dir())
+    {
+        Some(missing) => Err(format!("'{}' is not a directory", missing)),
+        None => Ok(()),
+    }
+}
+
+/// The vars and env vars visible at the point a closure was defined, with shadowing already applied
+#[derive(Debug, Clone, Default)]
+pub struct CapturedScope {
+    pub vars: IndexMap<String, Value>,
+    pub env: IndexMap<String, String>,
+}
+
+/// What a name refers to, as found by `Scope::resolve`
+#[derive(Debug, Clone)]
+pub enum Resolution {
+    Alias(Vec<Spanned<String>>),
+    Command(Command),
+    Variable(Value),
+    Env(String),
+}
+
+/// The definitions, vars and env vars a module's frame marked as exported
+#[derive(Debug, Clone, Default)]
+pub struct ModuleExports {
+    pub definitions: Vec<Block>,
+    pub vars: IndexMap<String, Value>,
+    pub env: IndexMap<String, String>,
+}
+
+/// The env merged across frames, and the env generation it was merged at
+type EnvCache = (u64, IndexMap<String, EnvValue>);
 
 #[derive(Debug, Clone)]
 pub struct Scope {
     frames: Arc<parking_lot::Mutex<Vec<ScopeFrame>>>,
+    // Memoized command lookups. Cleared whenever the set of visible commands may change.
+    command_cache: Arc<parking_lot::Mutex<IndexMap<String, Command>>>,
+    env_var_case: EnvVarCase,
+    // Bumped whenever the env changes, so callers can tell whether an env they built is stale
+    env_generation: Arc<AtomicU64>,
+    env_validators: Arc<parking_lot::Mutex<EnvValidators>>,
+    env_cache: Arc<parking_lot::Mutex<Option<EnvCache>>>,
+    // Width to render output at instead of the host's. Kept here as every command is given the scope.
+    render_width: Arc<parking_lot::Mutex<Option<usize>>>,
+}
+
+/// Pops the frame it was made for when dropped, so temporary frames don't outlive a panic
+struct FrameGuard<'a>(&'a Scope);
+
+impl Drop for FrameGuard<'_> {
+    fn drop(&mut self) {
+        self.0.exit_scope();
+    }
 }
 
 impl Default for Scope {
@@ -17,13 +342,44 @@ impl Default for Scope {
 
 impl Scope {
     pub fn new() -> Scope {
+        Scope::with_env_var_case(EnvVarCase::default())
+    }
+
+    pub fn with_env_var_case(env_var_case: EnvVarCase) -> Scope {
         Scope {
             frames: Arc::new(parking_lot::Mutex::new(vec![ScopeFrame::new()])),
+            command_cache: Arc::new(parking_lot::Mutex::new(IndexMap::new())),
+            env_var_case,
+            env_generation: Arc::new(AtomicU64::new(0)),
+            env_cache: Arc::new(parking_lot::Mutex::new(None)),
+            env_validators: Arc::new(parking_lot::Mutex::new(EnvValidators(indexmap! {
+                "NU_LIB_DIRS".to_string() => Arc::new(validate_directory_list) as EnvValidator,
+            }))),
+            render_width: Arc::new(parking_lot::Mutex::new(None)),
         }
     }
+
+    /// Render output at this width for the rest of the session, or at the host's width again with None
+    pub fn set_render_width(&self, width: Option<usize>) {
+        *self.render_width.lock() = width;
+    }
+
+    /// The width set to render output at, if any
+    pub fn render_width(&self) -> Option<usize> {
+        *self.render_width.lock()
+    }
     pub fn get_command(&self, name: &str) -> Option<Command> {
-        for frame in self.frames.lock().iter().rev() {
+        if let Some(command) = self.command_cache.lock().get(name) {
+            return Some(command.clone());
+        }
+
+        // Hold the frames lock while filling the cache so an invalidation can't slip in between
+        let frames = self.frames.lock();
+        for frame in frames.iter().rev() {
             if let Some(command) = frame.get_command(name) {
+                self.command_cache
+                    .lock()
+                    .insert(name.to_string(), command.clone());
                 return Some(command);
             }
         }
@@ -31,11 +387,123 @@ impl Scope {
         None
     }
 
+    /// The command that `name` resolves to beneath its innermost definition, eg. the builtin a custom command overrides
+    pub fn get_shadowed_command(&self, name: &str) -> Option<Command> {
+        self.frames
+            .lock()
+            .iter()
+            .rev()
+            .filter_map(|frame| frame.get_command(name))
+            .nth(1)
+    }
+
     pub fn add_command(&self, name: String, command: Command) {
+        let mut frames = self.frames.lock();
         // Note: this is assumed to always be true, as there is always a global top frame
-        if let Some(frame) = self.frames.lock().last_mut() {
+        if let Some(frame) = frames.last_mut().filter(|frame| !frame.frozen) {
             frame.add_command(name, command)
         }
+        self.invalidate_command_cache();
+    }
+
+    /// Remove a command from the innermost frame that defines it, eg. when unloading a plugin,
+    /// so a command of the same name in an outer frame is visible again. Nothing is removed if
+    /// that frame is frozen.
+    pub fn remove_command(&self, name: &str) -> Option<Command> {
+        let removed = self
+            .frames
+            .lock()
+            .iter_mut()
+            .rev()
+            .find(|frame| frame.commands.contains_key(name))
+            .filter(|frame| !frame.frozen)
+            .and_then(|frame| {
+                frame.custom_commands.shift_remove(name);
+                frame.command_categories.remove(name);
+                frame.commands.shift_remove(name)
+            });
+        self.invalidate_command_cache();
+        removed
+    }
+
+    /// Make the current frame read-only: setters and removals targeting it leave it unchanged
+    pub fn freeze_current(&self) {
+        if let Some(frame) = self.frames.lock().last_mut() {
+            frame.frozen = true;
+        }
+    }
+
+    /// Mark a custom command as exported, for modules to pick up. The mark goes on the innermost
+    /// labeled frame (eg. "module greetings.nu"), so it outlives the unlabeled frame the block
+    /// running the `export` pushed; with no labeled frame it goes on the current one.
+    pub fn mark_exported(&self, name: impl Into<String>) {
+        let mut frames = self.frames.lock();
+        let index = frames
+            .iter()
+            .rposition(|frame| frame.label.is_some())
+            .unwrap_or_else(|| frames.len().saturating_sub(1));
+        if let Some(frame) = frames.get_mut(index).filter(|frame| !frame.frozen) {
+            frame.exports.insert(name.into());
+        }
+    }
+
+    pub fn get_exported_definitions(&self) -> Vec<Block> {
+        let mut blocks = vec![];
+        if let Some(frame) = self.frames.lock().last() {
+            for (name, custom_command) in &frame.custom_commands {
+                if frame.exports.contains(name) {
+                    blocks.push(custom_command.clone());
+                }
+            }
+        }
+        blocks
+    }
+
+    /// What the current frame exports. An exported name covers the custom command of that name,
+    /// the var `$name` and the env var `name`, whichever of them the frame defines. Exported
+    /// commands carry every command the frame defines, so they can still call private helpers
+    /// (and each other, unprefixed) once the frame is gone.
+    pub fn current_exports(&self) -> ModuleExports {
+        let mut exports = ModuleExports::default();
+        let mut env = vec![];
+        if let Some(frame) = self.frames.lock().last() {
+            for name in &frame.exports {
+                if let Some(block) = frame.custom_commands.get(name) {
+                    let mut block = block.clone();
+                    for (name, definition) in &frame.custom_commands {
+                        block
+                            .definitions
+                            .entry(name.clone())
+                            .or_insert_with(|| definition.clone());
+                    }
+                    exports.definitions.push(block);
+                }
+                let var = if name.starts_with('$') {
+                    name.clone()
+                } else {
+                    format!("${}", name)
+                };
+                if let Some(value) = frame.vars.get(&var) {
+                    exports.vars.insert(var, value.clone());
+                }
+                if let Some(key) = self.env_var_case.find_key(&frame.env, name) {
+                    if let Some(value) = frame.env.get(&key) {
+                        env.push((key, value.clone()));
+                    }
+                }
+            }
+        }
+
+        // Computed values may read the scope, so they are resolved once the lock is released
+        for (name, value) in env {
+            let value = self.resolve_env_value(&name, value, &mut vec![]);
+            exports.env.insert(name, value);
+        }
+        exports
+    }
+
+    fn invalidate_command_cache(&self) {
+        self.command_cache.lock().clear();
     }
 
     pub fn get_command_names(&self) -> Vec<String> {
@@ -52,6 +520,20 @@ impl Scope {
         names
     }
 
+    pub fn get_alias_names(&self) -> Vec<String> {
+        let mut names = vec![];
+
+        for frame in self.frames.lock().iter() {
+            let mut frame_alias_names = frame.get_alias_names();
+            names.append(&mut frame_alias_names);
+        }
+
+        names.sort();
+        names.dedup();
+
+        names
+    }
+
     fn has_cmd_helper(&self, name: &str, f: fn(&ScopeFrame, &str) -> bool) -> bool {
         self.frames.lock().iter().any(|frame| f(frame, name))
     }
@@ -79,246 +561,2643 @@ impl Scope {
         }
     }
 
-    pub fn get_vars(&self) -> IndexMap<String, Value> {
-        //FIXME: should this be an interator?
-        let mut output = IndexMap::new();
+    /// The block behind a custom command, from the innermost frame that defines it
+    pub fn get_custom_command(&self, name: &str) -> Option<Block> {
+        self.frames
+            .lock()
+            .iter()
+            .rev()
+            .find_map(|frame| frame.custom_commands.get(name).cloned())
+    }
+
+    /// Define a custom command, filed under the given categories, eg. `fs` or `git`
+    pub fn add_definition_with_categories(&self, block: Block, categories: Vec<String>) {
+        let name = block.params.name.clone();
+        self.add_definition(block);
+        if let Some(frame) = self.frames.lock().last_mut().filter(|frame| !frame.frozen) {
+            frame.command_categories.insert(name, categories);
+        }
+    }
 
+    /// The sorted names of the visible custom commands filed under `category`
+    pub fn commands_in_category(&self, category: &str) -> Vec<String> {
+        let mut seen = IndexSet::new();
+        let mut names = vec![];
         for frame in self.frames.lock().iter().rev() {
-            for v in frame.vars.iter() {
-                output.insert(v.0.clone(), v.1.clone());
+            for name in frame.custom_commands.keys() {
+                // An inner definition hides the categories of the ones it shadows
+                if !seen.insert(name.clone()) {
+                    continue;
+                }
+                let in_category = frame
+                    .command_categories
+                    .get(name)
+                    .filter(|categories| categories.iter().any(|c| c == category))
+                    .is_some();
+                if in_category {
+                    names.push(name.clone());
+                }
             }
         }
+        names.sort();
+        names
+    }
 
-        output
+    /// Like `expect_command`, but the error points at the given span of the invocation
+    pub fn expect_command_spanned(&self, name: &str, span: Span) -> Result<Command, ShellError> {
+        self.get_command(name).ok_or_else(|| {
+            ShellError::labeled_error(
+                format!("Missing command '{}'", name),
+                "command not found",
+                span,
+            )
+        })
     }
 
-    pub fn get_env_vars(&self) -> IndexMap<String, String> {
-        //FIXME: should this be an interator?
-        let mut output = IndexMap::new();
+    /// Enter a new frame labeled with what it is for, eg. "block for each"
+    pub fn enter_named_scope(&self, label: &str) {
+        let mut frame = ScopeFrame::new();
+        frame.label = Some(label.to_string());
+        self.frames.lock().push(frame);
+        self.invalidate_command_cache();
+    }
 
-        for frame in self.frames.lock().iter().rev() {
-            for v in frame.env.iter() {
-                output.insert(v.0.clone(), v.1.clone());
-            }
+    /// The label of each frame, outermost first
+    pub fn frame_labels(&self) -> Vec<Option<String>> {
+        self.frames
+            .lock()
+            .iter()
+            .map(|frame| frame.label.clone())
+            .collect()
+    }
+
+    /// The labeled frames, innermost first, eg. "command foo <- block for each", if any are labeled
+    pub fn backtrace(&self) -> Option<String> {
+        let labels: Vec<String> = self.frame_labels().into_iter().rev().flatten().collect();
+        if labels.is_empty() {
+            None
+        } else {
+            Some(labels.join(" <- "))
         }
+    }
 
-        output
+    /// Call `f` with each frame and its index, outermost first, holding the frames lock throughout.
+    /// `f` must not call back into the scope.
+    pub fn for_each_frame(&self, mut f: impl FnMut(usize, &ScopeFrame)) {
+        for (idx, frame) in self.frames.lock().iter().enumerate() {
+            f(idx, frame);
+        }
     }
 
-    pub fn get_var(&self, name: &str) -> Option<Value> {
-        for frame in self.frames.lock().iter().rev() {
-            if let Some(v) = frame.vars.get(name) {
-                return Some(v.clone());
+    pub fn stats(&self) -> ScopeStats {
+        let frames = self.frames.lock();
+
+        let mut commands = IndexSet::new();
+        let mut custom_commands = IndexSet::new();
+        let mut aliases = IndexSet::new();
+        let mut vars = IndexSet::new();
+        let mut env_vars = IndexMap::new();
+
+        for frame in frames.iter() {
+            commands.extend(frame.commands.keys());
+            custom_commands.extend(frame.custom_commands.keys());
+            aliases.extend(frame.aliases.keys());
+            vars.extend(frame.vars.keys());
+        }
+        for frame in env_frames(&frames) {
+            for name in frame.env.keys() {
+                self.env_var_case.insert(&mut env_vars, name.clone(), ());
             }
         }
 
-        None
+        ScopeStats {
+            frames: frames
+                .iter()
+                .map(|frame| ScopeCounts {
+                    commands: frame.commands.len(),
+                    custom_commands: frame.custom_commands.len(),
+                    aliases: frame.aliases.len(),
+                    vars: frame.vars.len(),
+                    env_vars: frame.env.len(),
+                })
+                .collect(),
+            visible: ScopeCounts {
+                commands: commands.len(),
+                custom_commands: custom_commands.len(),
+                aliases: aliases.len(),
+                vars: vars.len(),
+                env_vars: env_vars.len(),
+            },
+        }
     }
 
-    pub fn add_var(&self, name: impl Into<String>, value: Value) {
-        if let Some(frame) = self.frames.lock().last_mut() {
-            frame.vars.insert(name.into(), value);
-        }
+    /// Describe each frame, outermost first, as a row of its vars, env vars, and the names of its commands and aliases
+    pub fn dump(&self, tag: impl Into<Tag>) -> Value {
+        let tag = tag.into();
+        let frames = self.frames.lock().clone();
+
+        let names = |names: Vec<&String>| {
+            UntaggedValue::table(
+                &names
+                    .into_iter()
+                    .map(|name| UntaggedValue::string(name).into_value(&tag))
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        let rows = frames
+            .into_iter()
+            .map(|frame| {
+                TaggedDictBuilder::build(&tag, |row| {
+                    row.insert_value(
+                        "vars",
+                        TaggedDictBuilder::build(&tag, |vars| {
+                            for (name, value) in &frame.vars {
+                                vars.insert_value(name, value.clone());
+                            }
+                        }),
+                    );
+                    row.insert_value(
+                        "env",
+                        TaggedDictBuilder::build(&tag, |env| {
+                            for (name, value) in &frame.env {
+                                let value =
+                                    self.resolve_env_value(name, value.clone(), &mut vec![]);
+                                env.insert_untagged(name, UntaggedValue::string(value));
+                            }
+                        }),
+                    );
+                    row.insert_untagged("commands", names(frame.commands.keys().collect()));
+                    row.insert_untagged(
+                        "custom_commands",
+                        names(frame.custom_commands.keys().collect()),
+                    );
+                    row.insert_untagged("aliases", names(frame.aliases.keys().collect()));
+                })
+            })
+            .collect::<Vec<_>>();
+
+        UntaggedValue::table(&rows).into_value(tag)
     }
 
-    pub fn add_vars(&self, vars: &IndexMap<String, Value>) {
-        if let Some(frame) = self.frames.lock().last_mut() {
-            frame
-                .vars
-                .extend(vars.iter().map(|(s, v)| (s.clone(), v.clone())))
+    /// Take a copy of the visible vars and env vars, unaffected by later changes to the scope
+    pub fn capture(&self) -> CapturedScope {
+        let mut vars = IndexMap::new();
+        for frame in self.frames.lock().iter() {
+            vars.extend(
+                frame
+                    .vars
+                    .iter()
+                    .map(|(name, value)| (name.clone(), value.clone())),
+            );
         }
-    }
 
-    pub fn add_env_var(&self, name: impl Into<String>, value: String) {
-        if let Some(frame) = self.frames.lock().last_mut() {
-            frame.env.insert(name.into(), value);
+        CapturedScope {
+            vars,
+            env: self.get_env_vars(),
         }
     }
 
-    pub fn add_env(&self, env_vars: IndexMap<String, String>) {
-        if let Some(frame) = self.frames.lock().last_mut() {
-            frame.env.extend(env_vars)
+    /// Enter a new frame seeded with a capture, to run a closure's block in the scope it was defined in.
+    /// The frame is left with `exit_scope` as usual. Nothing is entered if the captured env is rejected.
+    pub fn enter_captured(&self, captured: &CapturedScope) -> Result<(), ShellError> {
+        self.enter_scope();
+        self.add_vars(&captured.vars);
+        if let Err(err) = self.add_env(captured.env.clone()) {
+            self.exit_scope();
+            return Err(err);
         }
+        Ok(())
     }
-}
 
-impl ParserScope for Scope {
-    fn get_signature(&self, name: &str) -> Option<nu_protocol::Signature> {
-        self.get_command(name).map(|x| x.signature())
+    /// A copy of the outermost frame, for inspecting the base environment without being able to change it
+    pub fn global_frame_snapshot(&self) -> ScopeFrame {
+        self.frames
+            .lock()
+            .first()
+            .cloned()
+            .unwrap_or_else(ScopeFrame::new)
     }
 
-    fn has_signature(&self, name: &str) -> bool {
-        self.get_command(name).is_some()
-    }
+    /// What changed going from this scope's outermost frame to the other's, eg. after loading config
+    pub fn diff(&self, other: &Scope) -> ScopeDiff {
+        let before = self.global_frame_snapshot();
+        let after = other.global_frame_snapshot();
 
-    fn add_definition(&self, block: Block) {
-        if let Some(frame) = self.frames.lock().last_mut() {
-            let name = block.params.name.clone();
-            frame.custom_commands.insert(name.clone(), block.clone());
-            frame.commands.insert(name, whole_stream_command(block));
+        let resolved = |scope: &Scope, frame: &ScopeFrame| -> IndexMap<String, String> {
+            frame
+                .env
+                .iter()
+                .map(|(name, value)| {
+                    let value = scope.resolve_env_value(name, value.clone(), &mut vec![]);
+                    (name.clone(), value)
+                })
+                .collect()
+        };
+        let alias_items = |frame: &ScopeFrame| -> IndexMap<String, Vec<String>> {
+            frame
+                .aliases
+                .iter()
+                .map(|(name, replacement)| {
+                    let items = replacement.iter().map(|token| token.item.clone()).collect();
+                    (name.clone(), items)
+                })
+                .collect()
+        };
+
+        ScopeDiff {
+            vars: EntryDiff::between(&before.vars, &after.vars, |a, b| a == b),
+            env: EntryDiff::between(
+                &resolved(self, &before),
+                &resolved(other, &after),
+                |a, b| a == b,
+            ),
+            commands: EntryDiff::between(&before.commands, &after.commands, |a, b| {
+                a.signature() == b.signature()
+            }),
+            custom_commands: EntryDiff::between(
+                &before.custom_commands,
+                &after.custom_commands,
+                |a, b| a == b,
+            ),
+            aliases: EntryDiff::between(&alias_items(&before), &alias_items(&after), |a, b| a == b),
         }
     }
 
-    fn get_definitions(&self) -> Vec<Block> {
-        let mut blocks = vec![];
-        if let Some(frame) = self.frames.lock().last() {
-            for (_, custom_command) in &frame.custom_commands {
-                blocks.push(custom_command.clone());
-            }
-        }
-        blocks
+    /// Which env vars were added, removed or changed since a capture was taken, eg. around sourcing a script
+    pub fn env_diff(&self, snapshot: &CapturedScope) -> EntryDiff {
+        EntryDiff::between(&snapshot.env, &self.get_env_vars(), |a, b| a == b)
     }
 
-    fn get_alias(&self, name: &str) -> Option<Vec<Spanned<String>>> {
+    pub fn get_vars(&self) -> IndexMap<String, Value> {
+        //FIXME: should this be an interator?
+        let mut output = IndexMap::new();
+
         for frame in self.frames.lock().iter().rev() {
-            if let Some(x) = frame.aliases.get(name) {
-                return Some(x.clone());
+            for v in frame.vars.iter() {
+                output.insert(v.0.clone(), v.1.clone());
             }
         }
-        None
+
+        output
     }
 
-    fn add_alias(&self, name: &str, replacement: Vec<Spanned<String>>) {
-        // Note: this is assumed to always be true, as there is always a global top frame
-        if let Some(frame) = self.frames.lock().last_mut() {
-            frame.aliases.insert(name.to_string(), replacement);
+    /// The names of the visible variables starting with `prefix`, sorted
+    pub fn vars_matching(&self, prefix: &str) -> Vec<String> {
+        let mut names = IndexSet::new();
+        for frame in self.frames.lock().iter() {
+            names.extend(
+                frame
+                    .vars
+                    .keys()
+                    .filter(|name| name.starts_with(prefix))
+                    .cloned(),
+            );
+        }
+
+        let mut names: Vec<_> = names.into_iter().collect();
+        names.sort();
+        names
+    }
+
+    /// The names of the visible env vars starting with `prefix`, sorted
+    pub fn env_matching(&self, prefix: &str) -> Vec<String> {
+        let mut names = IndexMap::new();
+        for frame in env_frames(&self.frames.lock()) {
+            for name in frame.env.keys() {
+                if self.env_var_case.has_prefix(name, prefix) {
+                    self.env_var_case.insert(&mut names, name.clone(), ());
+                }
+            }
         }
+
+        let mut names: Vec<_> = names.into_iter().map(|(name, _)| name).collect();
+        names.sort();
+        names
     }
 
-    fn enter_scope(&self) {
-        self.frames.lock().push(ScopeFrame::new());
+    pub fn get_env_vars(&self) -> IndexMap<String, String> {
+        // Only compute the values that are visible, and each of those only once
+        self.merged_env()
+            .into_iter()
+            .map(|(name, value)| {
+                let value = self.resolve_env_value(&name, value, &mut vec![]);
+                (name, value)
+            })
+            .collect()
     }
 
-    fn exit_scope(&self) {
-        self.frames.lock().pop();
+    /// The env vars to hand to a spawned process, in the form `std::process::Command::envs` takes
+    pub fn env_for_process(&self) -> Vec<(OsString, OsString)> {
+        self.get_env_vars()
+            .into_iter()
+            .map(|(name, value)| (OsString::from(name), OsString::from(value)))
+            .collect()
     }
-}
 
-/// An evaluation scope. Scopes map variable names to Values and aid in evaluating blocks and expressions.
-#[derive(Debug, Clone)]
-pub struct ScopeFrame {
-    pub vars: IndexMap<String, Value>,
-    pub env: IndexMap<String, String>,
-    pub commands: IndexMap<String, Command>,
-    pub custom_commands: IndexMap<String, Block>,
-    pub aliases: IndexMap<String, Vec<Spanned<String>>>,
-}
+    /// Apply a directory's env block, like a `.nu-env` file, to the current frame. The block may only
+    /// set env vars to literal values with `set-env`; otherwise nothing is set.
+    pub fn apply_env_block(&self, block: &Block) -> Result<(), ShellError> {
+        let mut vars = IndexMap::new();
+        for pipeline in block.block.iter().flat_map(|group| &group.pipelines) {
+            for command in &pipeline.list {
+                let (name, value) = env_assignment(command)?;
+                vars.insert(name, value);
+            }
+        }
 
-impl ScopeFrame {
-    pub fn has_command(&self, name: &str) -> bool {
-        self.commands.contains_key(name)
+        self.add_env(vars)
     }
 
-    pub fn has_custom_command(&self, name: &str) -> bool {
-        self.custom_commands.contains_key(name)
+    /// The env vars for which `predicate(name, value)` holds
+    pub fn get_env_vars_filtered(
+        &self,
+        predicate: impl Fn(&str, &str) -> bool,
+    ) -> IndexMap<String, String> {
+        self.get_env_vars()
+            .into_iter()
+            .filter(|(name, value)| predicate(name, value))
+            .collect()
     }
 
-    pub fn has_alias(&self, name: &str) -> bool {
-        self.aliases.contains_key(name)
+    /// The env vars to show, leaving out those named with the `INTERNAL_ENV_PREFIX`
+    pub fn get_visible_env_vars(&self) -> IndexMap<String, String> {
+        self.get_env_vars_filtered(|name, _| !name.starts_with(INTERNAL_ENV_PREFIX))
     }
 
-    pub fn get_command_names(&self) -> Vec<String> {
+    // The env across all frames, reused until the env generation moves on
+    fn merged_env(&self) -> IndexMap<String, EnvValue> {
+        let frames = self.frames.lock();
+        let generation = self.env_generation();
+
+        let mut cache = self.env_cache.lock();
+        if let Some((cached_generation, env)) = &*cache {
+            if *cached_generation == generation {
+                return env.clone();
+            }
+        }
+
+        // Outermost first, so a var shadowed in an inner frame ends up with the inner value
+        let mut output = IndexMap::new();
+        for frame in env_frames(&frames) {
+            for v in frame.env.iter() {
+                self.env_var_case
+                    .insert(&mut output, v.0.clone(), v.1.clone());
+            }
+        }
+
+        *cache = Some((generation, output.clone()));
+        output
+    }
+
+    /// Define an alias in the outermost frame, so it outlives the block it was defined in
+    pub fn add_global_alias(&self, name: &str, replacement: Vec<Spanned<String>>) {
+        if let Some(frame) = self.frames.lock().first_mut().filter(|frame| !frame.frozen) {
+            frame.aliases.insert(name.to_string(), replacement);
+        }
+    }
+
+    /// Remove an alias from the innermost frame that defines it, unless that frame is frozen
+    pub fn remove_alias(&self, name: &str) -> bool {
+        self.frames
+            .lock()
+            .iter_mut()
+            .rev()
+            .find(|frame| frame.aliases.contains_key(name))
+            .filter(|frame| !frame.frozen)
+            .and_then(|frame| frame.aliases.shift_remove(name))
+            .is_some()
+    }
+
+    /// Expand an alias, following aliases that refer to other aliases, into the tokens it finally stands for
+    pub fn resolve_alias_chain(&self, name: &str) -> Result<Vec<Spanned<String>>, ShellError> {
+        let mut chain = vec![name.to_string()];
+        let mut expansion = match self.get_alias(name) {
+            Some(replacement) => replacement,
+            None => return Ok(vec![name.to_string().spanned_unknown()]),
+        };
+
+        while let Some(head) = expansion.first().cloned() {
+            // `alias ls = ls -a` expands to the command the alias shadows
+            if chain.last() == Some(&head.item) {
+                break;
+            }
+            let replacement = match self.get_alias(&head.item) {
+                Some(replacement) => replacement,
+                None => break,
+            };
+
+            let cyclic = chain.contains(&head.item);
+            chain.push(head.item.clone());
+            if cyclic {
+                return Err(ShellError::labeled_error(
+                    format!("alias cycle detected: {}", chain.join(" -> ")),
+                    "alias refers back to itself",
+                    head.span,
+                ));
+            }
+            if chain.len() > MAX_ALIAS_DEPTH {
+                return Err(ShellError::labeled_error(
+                    format!("alias '{}' expands through too many aliases", name),
+                    "alias nested too deeply",
+                    head.span,
+                ));
+            }
+
+            expansion.splice(0..1, replacement);
+        }
+
+        Ok(expansion)
+    }
+
+    /// The command an alias finally runs, eg. "git" for `alias g = git status`, following aliases
+    /// that refer to other aliases. None if `name` isn't an alias or its chain is cyclic.
+    pub fn alias_target_command(&self, name: &str) -> Option<String> {
+        self.get_alias(name)?;
+        let expansion = self.resolve_alias_chain(name).ok()?;
+        let head = expansion.first()?;
+        head.item.split_whitespace().next().map(String::from)
+    }
+
+    /// The text an alias finally expands to, following any aliases it refers to, without running anything
+    pub fn preview_alias(&self, name: &str) -> Result<String, ShellError> {
+        if self.get_alias(name).is_none() {
+            return Err(ShellError::untagged_runtime_error(format!(
+                "'{}' is not an alias",
+                name
+            )));
+        }
+
+        let expansion = self.resolve_alias_chain(name)?;
+        Ok(expansion
+            .iter()
+            .map(|token| token.item.as_str())
+            .collect::<Vec<_>>()
+            .join(" "))
+    }
+
+    pub fn get_env_var(&self, name: &str) -> Option<String> {
+        self.get_env_value(name)
+            .map(|value| self.resolve_env_value(name, value, &mut vec![]))
+    }
+
+    /// The visible value of an env var, or `default` if it isn't set
+    pub fn get_env_var_or(&self, name: &str, default: &str) -> String {
+        self.get_env_var(name)
+            .unwrap_or_else(|| default.to_string())
+    }
+
+    fn get_env_value(&self, name: &str) -> Option<EnvValue> {
+        env_frames(&self.frames.lock())
+            .iter()
+            .rev()
+            .find_map(|frame| {
+                self.env_var_case
+                    .find_key(&frame.env, name)
+                    .and_then(|key| frame.env.get(&key).cloned())
+            })
+    }
+
+    // Values are resolved without holding the frames lock, as computed values and templates may read the scope.
+    // `resolving` holds the templates being rendered, so that cyclic references render as empty.
+    fn resolve_env_value(
+        &self,
+        name: &str,
+        value: EnvValue,
+        resolving: &mut Vec<String>,
+    ) -> String {
+        match value {
+            EnvValue::Literal(value) => value,
+            EnvValue::Computed(compute) => compute(),
+            EnvValue::Template(template) => {
+                resolving.push(name.to_string());
+                let rendered = self.render_template(&template, resolving);
+                resolving.pop();
+                rendered
+            }
+        }
+    }
+
+    fn render_template(&self, template: &str, resolving: &mut Vec<String>) -> String {
+        let mut output = String::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find("($") {
+            let end = match rest[start..].find(')') {
+                Some(end) => start + end,
+                None => break,
+            };
+
+            output.push_str(&rest[..start]);
+            let name = &rest[start + 2..end];
+            if !resolving.iter().any(|outer| outer == name) {
+                if let Some(value) = self.get_env_value(name) {
+                    output.push_str(&self.resolve_env_value(name, value, resolving));
+                }
+            }
+            rest = &rest[end + 1..];
+        }
+
+        output.push_str(rest);
+        output
+    }
+
+    /// Replace each `($NAME)` in the template with the current value of the env var `NAME`
+    pub fn render_env_template(&self, template: &str) -> String {
+        self.render_template(template, &mut vec![])
+    }
+
+    /// Every definition of an env var, innermost frame first, paired with its frame index
+    pub fn env_var_history(&self, name: &str) -> Vec<(usize, String)> {
+        let values = self
+            .frames
+            .lock()
+            .iter()
+            .enumerate()
+            .rev()
+            .filter_map(|(idx, frame)| {
+                self.env_var_case
+                    .find_key(&frame.env, name)
+                    .and_then(|key| frame.env.get(&key).cloned())
+                    .map(|value| (idx, value))
+            })
+            .collect::<Vec<_>>();
+
+        values
+            .into_iter()
+            .map(|(idx, value)| (idx, self.resolve_env_value(name, value, &mut vec![])))
+            .collect()
+    }
+
+    pub fn get_var(&self, name: &str) -> Option<Value> {
+        for frame in self.frames.lock().iter().rev() {
+            if let Some(v) = frame.vars.get(name) {
+                return Some(v.clone());
+            }
+        }
+
+        None
+    }
+
+    /// Find what `name` refers to. Namespaces are tried in the order a bare word is
+    /// understood on the command line: aliases, then commands, then variables (looked up
+    /// as `name` and as `$name`), then env vars. Within each namespace the innermost frame
+    /// wins, so an inner var does not hide an outer command of the same name.
+    pub fn resolve(&self, name: &str) -> Option<Resolution> {
+        if let Some(replacement) = self.get_alias(name) {
+            return Some(Resolution::Alias(replacement));
+        }
+        if let Some(command) = self.get_command(name) {
+            return Some(Resolution::Command(command));
+        }
+
+        let var = self.get_var(name).or_else(|| {
+            if name.starts_with('$') {
+                None
+            } else {
+                self.get_var(&format!("${}", name))
+            }
+        });
+        if let Some(value) = var {
+            return Some(Resolution::Variable(value));
+        }
+
+        self.get_env_var(name).map(Resolution::Env)
+    }
+
+    /// The value of a var read as a `T`, eg. an `i64`, or a type error if it holds something else
+    pub fn get_var_as<T: FromValue>(&self, name: &str) -> Result<Option<T>, ShellError> {
+        self.get_var(name)
+            .map(|value| T::from_value(&value))
+            .transpose()
+    }
+
+    /// The value at a path starting with a variable, eg. `$config.theme`, or None if any part of it is missing
+    pub fn get_var_path(&self, path: &ColumnPath) -> Option<Value> {
+        self.expect_var_path(path).ok()
+    }
+
+    /// Like `get_var_path`, but a missing variable or column is an error naming what is missing
+    pub fn expect_var_path(&self, path: &ColumnPath) -> Result<Value, ShellError> {
+        let (base, rest) = match path.members().split_first() {
+            Some(split) => split,
+            None => {
+                return Err(ShellError::untagged_runtime_error(
+                    "Expected a variable name",
+                ))
+            }
+        };
+
+        let name = base.as_string();
+        match self.get_var(&name) {
+            Some(value) => value.get_data_by_column_path(
+                &ColumnPath::new(rest.to_vec()),
+                Box::new(|_, _, error| error),
+            ),
+            None => Err(ShellError::untagged_runtime_error(format!(
+                "Missing variable '{}'",
+                name
+            ))),
+        }
+    }
+
+    /// The index of the frame (0 being the outermost) that `get_var` would resolve the name in
+    pub fn depth_of_var(&self, name: &str) -> Option<usize> {
+        self.frames
+            .lock()
+            .iter()
+            .rposition(|frame| frame.vars.contains_key(name))
+    }
+
+    pub fn add_var(&self, name: impl Into<String>, value: Value) {
+        if let Some(frame) = self.frames.lock().last_mut().filter(|frame| !frame.frozen) {
+            frame.vars.insert(name.into(), value);
+        }
+    }
+
+    pub fn add_vars(&self, vars: &IndexMap<String, Value>) {
+        if let Some(frame) = self.frames.lock().last_mut().filter(|frame| !frame.frozen) {
+            frame
+                .vars
+                .extend(vars.iter().map(|(s, v)| (s.clone(), v.clone())))
+        }
+    }
+
+    pub fn rename_var(&self, from: &str, to: &str) -> Result<(), ShellError> {
+        for frame in self.frames.lock().iter_mut().rev() {
+            if frame.frozen && frame.vars.contains_key(from) {
+                return Err(ShellError::untagged_runtime_error(format!(
+                    "Cannot rename variable '{}' in a frozen scope",
+                    from
+                )));
+            }
+            if let Some(value) = frame.vars.shift_remove(from) {
+                if frame.vars.contains_key(to) {
+                    log::warn!("renaming '{}' overwrites existing variable '{}'", from, to);
+                }
+                frame.vars.insert(to.to_string(), value);
+                return Ok(());
+            }
+        }
+
+        Err(ShellError::untagged_runtime_error(format!(
+            "Missing variable '{}'",
+            from
+        )))
+    }
+
+    /// Move a variable from the innermost frame out to the frame enclosing it
+    pub fn promote_var(&self, name: &str) -> Result<(), ShellError> {
+        let mut frames = self.frames.lock();
+        let count = frames.len();
+        if count < 2 {
+            return Err(ShellError::untagged_runtime_error(format!(
+                "Cannot promote variable '{}' out of the outermost scope",
+                name
+            )));
+        }
+
+        let (outer, inner) = frames.split_at_mut(count - 1);
+        let parent = &mut outer[count - 2];
+        if parent.frozen || inner[0].frozen {
+            return Err(ShellError::untagged_runtime_error(format!(
+                "Cannot promote variable '{}' to or from a frozen scope",
+                name
+            )));
+        }
+
+        match inner[0].vars.shift_remove(name) {
+            Some(value) => {
+                parent.vars.insert(name.to_string(), value);
+                Ok(())
+            }
+            None => Err(ShellError::untagged_runtime_error(format!(
+                "Missing variable '{}' in the current scope",
+                name
+            ))),
+        }
+    }
+
+    /// A counter that changes whenever an env var is set or removed
+    pub fn env_generation(&self) -> u64 {
+        self.env_generation.load(Ordering::SeqCst)
+    }
+
+    fn bump_env_generation(&self) {
+        self.env_generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Check values set for the env var `name`, replacing any earlier validator
+    pub fn register_env_validator(
+        &self,
+        name: impl Into<String>,
+        validator: impl Fn(&str) -> Result<(), String> + Send + Sync + 'static,
+    ) {
+        let mut validators = self.env_validators.lock();
+        self.env_var_case
+            .insert(&mut validators.0, name.into(), Arc::new(validator));
+    }
+
+    fn validate_env_var(&self, name: &str, value: &str) -> Result<(), ShellError> {
+        let validator = {
+            let validators = self.env_validators.lock();
+            self.env_var_case
+                .find_key(&validators.0, name)
+                .and_then(|key| validators.0.get(&key).cloned())
+        };
+
+        match validator {
+            Some(validator) => validator(value).map_err(|reason| {
+                ShellError::untagged_runtime_error(format!(
+                    "Invalid value for environment variable {}: {}",
+                    name, reason
+                ))
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Set an env var in the current frame, unless its validator rejects the value
+    pub fn add_env_var(&self, name: impl Into<String>, value: String) -> Result<(), ShellError> {
+        let mut vars = IndexMap::new();
+        vars.insert(name.into(), value);
+        self.add_env(vars)
+    }
+
+    /// Add an env var to the current frame whose value is computed afresh each time it is read
+    pub fn add_computed_env_var(
+        &self,
+        name: impl Into<String>,
+        compute: impl Fn() -> String + Send + Sync + 'static,
+    ) {
+        if let Some(frame) = self.frames.lock().last_mut().filter(|frame| !frame.frozen) {
+            self.env_var_case.insert(
+                &mut frame.env,
+                name.into(),
+                EnvValue::Computed(Arc::new(compute)),
+            );
+            self.bump_env_generation();
+        }
+    }
+
+    /// Add an env var to the current frame that is rendered from the template each time it is read,
+    /// so that it follows changes to the env vars it refers to
+    pub fn add_env_template(&self, name: impl Into<String>, template: String) {
+        if let Some(frame) = self.frames.lock().last_mut().filter(|frame| !frame.frozen) {
+            self.env_var_case
+                .insert(&mut frame.env, name.into(), EnvValue::Template(template));
+            self.bump_env_generation();
+        }
+    }
+
+    /// Append to the visible value of an env var, storing the result in the current frame.
+    /// If the var isn't set yet, it is set to the value as-is.
+    pub fn append_env_var(
+        &self,
+        name: &str,
+        value: &str,
+        separator: &str,
+    ) -> Result<(), ShellError> {
+        let appended = match self.get_env_var(name) {
+            Some(current) => format!("{}{}{}", current, separator, value),
+            None => value.to_string(),
+        };
+
+        self.add_env_var(name, appended)
+    }
+
+    /// Set env vars in the current frame. Nothing is set if a validator rejects any of the values.
+    pub fn add_env(&self, env_vars: IndexMap<String, String>) -> Result<(), ShellError> {
+        for (name, value) in &env_vars {
+            self.validate_env_var(name, value)?;
+        }
+
+        if let Some(frame) = self.frames.lock().last_mut().filter(|frame| !frame.frozen) {
+            for (name, value) in env_vars {
+                self.env_var_case
+                    .insert(&mut frame.env, name, EnvValue::Literal(value));
+            }
+            self.bump_env_generation();
+        }
+        Ok(())
+    }
+
+    /// Add an env var to the outermost frame, so it outlives the block it was set in
+    pub fn add_global_env_var(
+        &self,
+        name: impl Into<String>,
+        value: String,
+    ) -> Result<(), ShellError> {
+        let name = name.into();
+        self.validate_env_var(&name, &value)?;
+
+        if let Some(frame) = self.frames.lock().first_mut().filter(|frame| !frame.frozen) {
+            self.env_var_case
+                .insert(&mut frame.env, name, EnvValue::Literal(value));
+            self.bump_env_generation();
+        }
+        Ok(())
+    }
+
+    /// Add env vars to the current frame with each name prefixed, eg. `HOME` as `HOST_HOME`
+    pub fn add_env_prefixed(
+        &self,
+        prefix: &str,
+        vars: IndexMap<String, String>,
+    ) -> Result<(), ShellError> {
+        self.add_env(
+            vars.into_iter()
+                .map(|(name, value)| (format!("{}{}", prefix, name), value))
+                .collect(),
+        )
+    }
+
+    /// Run `f` in a new frame with the given env overrides, which shadow the outer env until `f` returns.
+    /// The frame is popped even if `f` panics. `f` isn't run if a validator rejects an override.
+    pub fn with_temp_env<T>(
+        &self,
+        overrides: IndexMap<String, String>,
+        f: impl FnOnce() -> T,
+    ) -> Result<T, ShellError> {
+        self.enter_scope();
+        let _guard = FrameGuard(self);
+        self.add_env(overrides)?;
+
+        Ok(f())
+    }
+
+    /// Enter a new frame binding each of the block's positional parameters, as `$name`, to the
+    /// arg in the same position. Nothing is entered if the number of args doesn't fit the params.
+    /// Args past the declared params are accepted, unbound, when the block takes a rest param.
+    /// Arity errors are labeled at `call_span`, the call that passed the args.
+    pub fn bind_params(
+        &self,
+        block: &Block,
+        args: &[Value],
+        call_span: Span,
+    ) -> Result<(), ShellError> {
+        let params = &block.params.positional;
+        let required = params
+            .iter()
+            .filter(|(param, _)| matches!(param, PositionalType::Mandatory(..)))
+            .count();
+
+        if args.len() < required {
+            return Err(ShellError::labeled_error(
+                format!(
+                    "{} expects at least {} arguments, but got {}",
+                    block.params.name,
+                    required,
+                    args.len()
+                ),
+                "too few arguments",
+                call_span,
+            ));
+        }
+        if args.len() > params.len() && block.params.rest_positional.is_none() {
+            return Err(ShellError::labeled_error(
+                format!(
+                    "{} expects at most {} arguments, but got {}",
+                    block.params.name,
+                    params.len(),
+                    args.len()
+                ),
+                "too many arguments",
+                call_span,
+            ));
+        }
+
+        self.enter_named_scope(&format!("command {}", block.params.name));
+        for ((param, _), arg) in params.iter().zip(args) {
+            let name = param.name();
+            if name.starts_with('$') {
+                self.add_var(name, arg.clone());
+            } else {
+                self.add_var(format!("${}", name), arg.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Run `f` in a new frame with the given vars bound, which go away once `f` returns.
+    /// The frame is popped even if `f` panics.
+    pub fn with_vars<T>(&self, vars: IndexMap<String, Value>, f: impl FnOnce() -> T) -> T {
+        self.enter_scope();
+        let _guard = FrameGuard(self);
+        self.add_vars(&vars);
+
+        f()
+    }
+
+    /// Enter a new frame that hides the env outside it, except for the allowlisted env vars,
+    /// which are copied in with their current values
+    pub fn enter_isolated_env_scope(&self, allowlist: &[&str]) {
+        self.push_isolated_frame(allowlist, None);
+    }
+
+    /// Like `enter_isolated_env_scope`, but the frame is labeled so `exit_labeled_scope` can find it
+    pub fn enter_isolated_named_scope(&self, label: &str, allowlist: &[&str]) {
+        self.push_isolated_frame(allowlist, Some(label.to_string()));
+    }
+
+    fn push_isolated_frame(&self, allowlist: &[&str], label: Option<String>) {
+        let inherited: IndexMap<String, String> = allowlist
+            .iter()
+            .filter_map(|name| {
+                self.get_env_var(name)
+                    .map(|value| (name.to_string(), value))
+            })
+            .collect();
+
+        let mut frame = ScopeFrame::new();
+        frame.isolated_env = true;
+        frame.label = label;
+        for (name, value) in inherited {
+            self.env_var_case
+                .insert(&mut frame.env, name, EnvValue::Literal(value));
+        }
+
+        self.frames.lock().push(frame);
+        self.bump_env_generation();
+        self.invalidate_command_cache();
+    }
+
+    /// Leave the innermost frame, refusing to remove the global frame
+    pub fn try_exit_scope(&self, span: Span) -> Result<(), ShellError> {
+        if self.frames.lock().len() < 2 {
+            return Err(ShellError::labeled_error(
+                "Can not leave the global scope",
+                "no scope to leave",
+                span,
+            ));
+        }
+
+        self.exit_scope();
+        Ok(())
+    }
+
+    /// Leave the innermost frame, moving the env vars set in it out to the frame that becomes innermost.
+    /// Nothing is left if that frame is frozen and there is env to move, as the env would be lost.
+    pub fn exit_scope_keeping_env(&self, span: Span) -> Result<(), ShellError> {
+        let innermost = self.frames.lock().len().saturating_sub(1);
+        self.remove_frame(innermost, true, span)
+    }
+
+    /// Leave the innermost frame with the given label, wherever it is in the stack. With `keep_env`,
+    /// its env vars are moved out to the frame below it. Returns whether there was such a frame to leave.
+    pub fn exit_labeled_scope(
+        &self,
+        label: &str,
+        keep_env: bool,
+        span: Span,
+    ) -> Result<bool, ShellError> {
+        let index = self
+            .frames
+            .lock()
+            .iter()
+            .rposition(|frame| frame.label.as_deref() == Some(label));
+        match index {
+            Some(index) => self.remove_frame(index, keep_env, span).map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    fn remove_frame(&self, index: usize, keep_env: bool, span: Span) -> Result<(), ShellError> {
+        let mut frames = self.frames.lock();
+        if index == 0 || index >= frames.len() {
+            return Err(ShellError::labeled_error(
+                "Can not leave the global scope",
+                "no scope to leave",
+                span,
+            ));
+        }
+
+        if keep_env && !frames[index].env.is_empty() && frames[index - 1].frozen {
+            return Err(ShellError::labeled_error(
+                "Can not keep the env in a read-only scope",
+                "the scope this returns to is frozen",
+                span,
+            ));
+        }
+
+        let leaving = frames.remove(index);
+        if keep_env {
+            for (name, value) in leaving.env {
+                self.env_var_case
+                    .insert(&mut frames[index - 1].env, name, value);
+            }
+        }
+        drop(frames);
+        self.bump_env_generation();
+        self.invalidate_command_cache();
+        Ok(())
+    }
+
+    /// Remove an env var from the innermost frame that sets it, unless that frame is frozen
+    pub fn remove_env_var(&self, name: &str) -> Option<String> {
+        let mut frames = self.frames.lock();
+        let start = frames.len() - env_frames(&frames).len();
+        let removed = frames[start..].iter_mut().rev().find_map(|frame| {
+            let key = self.env_var_case.find_key(&frame.env, name)?;
+            Some((frame, key))
+        });
+        let removed = match removed {
+            Some((frame, key)) if !frame.frozen => frame.env.shift_remove(&key),
+            _ => None,
+        };
+        drop(frames);
+        if removed.is_some() {
+            self.bump_env_generation();
+        }
+
+        removed.map(|value| self.resolve_env_value(name, value, &mut vec![]))
+    }
+}
+
+impl ParserScope for Scope {
+    fn get_signature(&self, name: &str) -> Option<nu_protocol::Signature> {
+        self.get_command(name).map(|x| x.signature())
+    }
+
+    fn has_signature(&self, name: &str) -> bool {
+        self.get_command(name).is_some()
+    }
+
+    fn add_definition(&self, block: Block) {
+        let mut frames = self.frames.lock();
+        if let Some(frame) = frames.last_mut().filter(|frame| !frame.frozen) {
+            let name = block.params.name.clone();
+            frame.custom_commands.insert(name.clone(), block.clone());
+            frame.command_categories.remove(&name);
+            frame.commands.insert(name, whole_stream_command(block));
+        }
+        self.invalidate_command_cache();
+    }
+
+    fn get_definitions(&self) -> Vec<Block> {
+        let mut blocks = vec![];
+        if let Some(frame) = self.frames.lock().last() {
+            for (_, custom_command) in &frame.custom_commands {
+                blocks.push(custom_command.clone());
+            }
+        }
+        blocks
+    }
+
+    fn get_alias(&self, name: &str) -> Option<Vec<Spanned<String>>> {
+        for frame in self.frames.lock().iter().rev() {
+            if let Some(x) = frame.aliases.get(name) {
+                return Some(x.clone());
+            }
+        }
+        None
+    }
+
+    fn add_alias(&self, name: &str, replacement: Vec<Spanned<String>>) {
+        // Note: this is assumed to always be true, as there is always a global top frame
+        if let Some(frame) = self.frames.lock().last_mut().filter(|frame| !frame.frozen) {
+            frame.aliases.insert(name.to_string(), replacement);
+        }
+    }
+
+    fn enter_scope(&self) {
+        let mut frames = self.frames.lock();
+        frames.push(ScopeFrame::new());
+        self.invalidate_command_cache();
+    }
+
+    fn exit_scope(&self) {
+        let mut frames = self.frames.lock();
+        if frames
+            .pop()
+            .filter(|frame| !frame.env.is_empty() || frame.isolated_env)
+            .is_some()
+        {
+            self.bump_env_generation();
+        }
+        self.invalidate_command_cache();
+    }
+}
+
+/// An evaluation scope. Scopes map variable names to Values and aid in evaluating blocks and expressions.
+#[derive(Debug, Clone)]
+pub struct ScopeFrame {
+    pub vars: IndexMap<String, Value>,
+    pub env: IndexMap<String, EnvValue>,
+    pub commands: IndexMap<String, Command>,
+    pub custom_commands: IndexMap<String, Block>,
+    pub command_categories: IndexMap<String, Vec<String>>,
+    pub aliases: IndexMap<String, Vec<Spanned<String>>>,
+    pub exports: IndexSet<String>,
+    pub frozen: bool,
+    /// Hides the env of the frames outside this one
+    pub isolated_env: bool,
+    /// What the frame was entered for, eg. "command foo", shown in scope backtraces
+    pub label: Option<String>,
+}
+
+impl ScopeFrame {
+    pub fn has_command(&self, name: &str) -> bool {
+        self.commands.contains_key(name)
+    }
+
+    pub fn has_custom_command(&self, name: &str) -> bool {
+        self.custom_commands.contains_key(name)
+    }
+
+    pub fn has_alias(&self, name: &str) -> bool {
+        self.aliases.contains_key(name)
+    }
+
+    pub fn get_command_names(&self) -> Vec<String> {
         self.commands.keys().map(|x| x.to_string()).collect()
     }
 
-    pub fn add_command(&mut self, name: String, command: Command) {
-        self.commands.insert(name, command);
+    pub fn get_alias_names(&self) -> Vec<String> {
+        self.aliases.keys().map(|x| x.to_string()).collect()
+    }
+
+    pub fn add_command(&mut self, name: String, command: Command) {
+        self.commands.insert(name, command);
+    }
+
+    pub fn get_command(&self, name: &str) -> Option<Command> {
+        self.commands.get(name).cloned()
+    }
+
+    pub fn new() -> ScopeFrame {
+        ScopeFrame {
+            vars: IndexMap::new(),
+            env: IndexMap::new(),
+            commands: IndexMap::new(),
+            custom_commands: IndexMap::new(),
+            command_categories: IndexMap::new(),
+            aliases: IndexMap::new(),
+            exports: IndexSet::new(),
+            frozen: false,
+            isolated_env: false,
+            label: None,
+        }
+    }
+}
+
+// impl Scope {
+//     pub fn vars(&self) -> IndexMap<String, Value> {
+//         //FIXME: should this be an interator?
+
+//         let mut output = IndexMap::new();
+
+//         for v in &self.vars {
+//             output.insert(v.0.clone(), v.1.clone());
+//         }
+
+//         if let Some(parent) = &self.parent {
+//             for v in parent.vars() {
+//                 if !output.contains_key(&v.0) {
+//                     output.insert(v.0.clone(), v.1.clone());
+//                 }
+//             }
+//         }
+
+//         output
+//     }
+
+//     pub fn env(&self) -> IndexMap<String, String> {
+//         //FIXME: should this be an interator?
+
+//         let mut output = IndexMap::new();
+
+//         for v in &self.env {
+//             output.insert(v.0.clone(), v.1.clone());
+//         }
+
+//         if let Some(parent) = &self.parent {
+//             for v in parent.env() {
+//                 if !output.contains_key(&v.0) {
+//                     output.insert(v.0.clone(), v.1.clone());
+//                 }
+//             }
+//         }
+
+//         output
+//     }
+
+//     pub fn var(&self, name: &str) -> Option<Value> {
+//         if let Some(value) = self.vars().get(name) {
+//             Some(value.clone())
+//         } else {
+//             None
+//         }
+//     }
+
+//     pub fn append_var(this: Arc<Self>, name: impl Into<String>, value: Value) -> Arc<Scope> {
+//         let mut vars = IndexMap::new();
+//         vars.insert(name.into(), value);
+//         Arc::new(Scope {
+//             vars,
+//             env: IndexMap::new(),
+//             commands: IndexMap::new(),
+//             aliases: IndexMap::new(),
+//             parent: Some(this),
+//         })
+//     }
+
+//     pub fn append_vars(this: Arc<Self>, vars: IndexMap<String, Value>) -> Arc<Scope> {
+//         Arc::new(Scope {
+//             vars,
+//             env: IndexMap::new(),
+//             commands: IndexMap::new(),
+//             aliases: IndexMap::new(),
+//             parent: Some(this),
+//         })
+//     }
+
+//     pub fn append_env(this: Arc<Self>, env: IndexMap<String, String>) -> Arc<Scope> {
+//         Arc::new(Scope {
+//             vars: IndexMap::new(),
+//             env,
+//             commands: IndexMap::new(),
+//             aliases: IndexMap::new(),
+//             parent: Some(this),
+//         })
+//     }
+
+// }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        EntryDiff, EnvVarCase, Resolution, Scope, ScopeCounts, INTERNAL_ENV_PREFIX, LAST_EXIT_CODE,
+    };
+    use crate::commands::{whole_stream_command, WholeStreamCommand};
+    use crate::prelude::*;
+    use indexmap::indexmap;
+    use nu_protocol::hir::Block;
+    use nu_protocol::{ColumnPath, PathMember, SyntaxShape, UntaggedValue, Value};
+    use std::ffi::OsString;
+
+    #[test]
+    fn rename_var_keeps_value_and_frame() {
+        let scope = Scope::new();
+        scope.enter_scope();
+        scope.add_var("$old", UntaggedValue::int(5).into_untagged_value());
+
+        scope
+            .rename_var("$old", "$new")
+            .expect("rename should succeed");
+
+        assert!(scope.get_var("$old").is_none());
+        assert_eq!(
+            scope.get_var("$new"),
+            Some(UntaggedValue::int(5).into_untagged_value())
+        );
+
+        scope.exit_scope();
+        assert!(scope.get_var("$new").is_none());
+    }
+
+    #[test]
+    fn rename_var_errors_on_missing_source() {
+        let scope = Scope::new();
+
+        assert!(scope.rename_var("$missing", "$new").is_err());
+    }
+
+    #[test]
+    fn matching_merges_frames_and_filters_by_prefix() {
+        let scope = Scope::new();
+        scope.add_var("$path", UntaggedValue::int(1).into_untagged_value());
+        scope.add_var("$other", UntaggedValue::int(2).into_untagged_value());
+        scope
+            .add_env_var("PATH", "/bin".to_string())
+            .expect("env var should be accepted");
+        scope.enter_scope();
+        scope.add_var("$path", UntaggedValue::int(3).into_untagged_value());
+        scope.add_var("$pager", UntaggedValue::int(4).into_untagged_value());
+        scope
+            .add_env_var("PAGER", "less".to_string())
+            .expect("env var should be accepted");
+        scope
+            .add_env_var("PATH", "/usr/bin".to_string())
+            .expect("env var should be accepted");
+
+        assert_eq!(
+            scope.vars_matching("$pa"),
+            vec!["$pager".to_string(), "$path".to_string()]
+        );
+        assert_eq!(
+            scope.env_matching("PA"),
+            vec!["PAGER".to_string(), "PATH".to_string()]
+        );
+        assert!(scope.env_matching("HOME").is_empty());
+    }
+
+    #[test]
+    fn env_generation_changes_only_on_env_mutation() {
+        let scope = Scope::new();
+        let start = scope.env_generation();
+
+        scope.add_var("$x", UntaggedValue::int(1).into_untagged_value());
+        scope.get_env_var("EDITOR");
+        assert_eq!(scope.env_generation(), start);
+
+        scope
+            .add_env_var("EDITOR", "vim".to_string())
+            .expect("env var should be accepted");
+        let after_add = scope.env_generation();
+        assert!(after_add > start);
+
+        scope.remove_env_var("MISSING");
+        assert_eq!(scope.env_generation(), after_add);
+
+        scope.remove_env_var("EDITOR");
+        assert!(scope.env_generation() > after_add);
+    }
+
+    #[test]
+    fn captured_scope_keeps_values_from_when_it_was_taken() {
+        let scope = Scope::new();
+        scope.add_var("$count", UntaggedValue::int(1).into_untagged_value());
+        scope.enter_scope();
+        scope.add_var("$count", UntaggedValue::int(2).into_untagged_value());
+        scope
+            .add_env_var("MODE", "captured".to_string())
+            .expect("env var should be accepted");
+
+        let captured = scope.capture();
+        scope.add_var("$count", UntaggedValue::int(3).into_untagged_value());
+        scope
+            .add_env_var("MODE", "changed".to_string())
+            .expect("env var should be accepted");
+
+        scope
+            .enter_captured(&captured)
+            .expect("capture should be entered");
+        assert_eq!(
+            scope.get_var("$count"),
+            Some(UntaggedValue::int(2).into_untagged_value())
+        );
+        assert_eq!(scope.get_env_var("MODE"), Some("captured".to_string()));
+        scope.exit_scope();
+
+        assert_eq!(
+            scope.get_var("$count"),
+            Some(UntaggedValue::int(3).into_untagged_value())
+        );
+    }
+
+    #[test]
+    fn validated_env_var_rejects_missing_directory() {
+        let scope = Scope::new();
+        let valid = std::env::temp_dir().display().to_string();
+        let missing = std::env::temp_dir()
+            .join("nu_missing_lib_dir")
+            .display()
+            .to_string();
+
+        scope
+            .add_env_var("NU_LIB_DIRS", valid.clone())
+            .expect("an existing directory should be accepted");
+        assert!(scope.add_env_var("NU_LIB_DIRS", missing).is_err());
+
+        assert_eq!(scope.get_env_var("NU_LIB_DIRS"), Some(valid));
+    }
+
+    #[test]
+    fn every_env_setter_is_validated() {
+        let scope = Scope::new();
+        scope.register_env_validator("LEVEL", |value| match value.parse::<u8>() {
+            Ok(_) => Ok(()),
+            Err(_) => Err("expected a number".to_string()),
+        });
+
+        let mut vars = IndexMap::new();
+        vars.insert("EDITOR".to_string(), "vim".to_string());
+        vars.insert("LEVEL".to_string(), "high".to_string());
+        assert!(scope.add_env(vars).is_err());
+        assert_eq!(scope.get_env_var("EDITOR"), None);
+
+        scope
+            .add_env_var("LEVEL", "3".to_string())
+            .expect("env var should be accepted");
+        assert!(scope.append_env_var("LEVEL", "high", ":").is_err());
+        assert_eq!(scope.get_env_var("LEVEL"), Some("3".to_string()));
+    }
+
+    #[test]
+    fn registered_validator_is_consulted() {
+        let scope = Scope::new();
+        scope.register_env_validator("LEVEL", |value| match value.parse::<u8>() {
+            Ok(_) => Ok(()),
+            Err(_) => Err("expected a number".to_string()),
+        });
+
+        scope
+            .add_env_var("LEVEL", "3".to_string())
+            .expect("env var should be accepted");
+        assert!(scope.add_env_var("LEVEL", "high".to_string()).is_err());
+
+        assert_eq!(scope.get_env_var("LEVEL"), Some("3".to_string()));
+    }
+
+    #[test]
+    fn env_block_sets_only_literal_env_vars() {
+        let context =
+            crate::create_default_context(false).expect("could not create the default context");
+        let scope = &context.scope;
+        let parse = |source: &str| {
+            let (block, err) = nu_parser::parse(source, 0, scope);
+            assert!(err.is_none());
+            block
+        };
+
+        scope
+            .apply_env_block(&parse("set-env PROJECT = nu; set-env JOBS = 4"))
+            .expect("env block should apply");
+        assert_eq!(scope.get_env_var("PROJECT"), Some("nu".to_string()));
+        assert_eq!(scope.get_env_var("JOBS"), Some("4".to_string()));
+
+        let err = scope
+            .apply_env_block(&parse("set-env MODE = dev; rm -r stuff"))
+            .expect_err("running a command should be refused");
+        let diagnostic = err.into_diagnostic().expect("no diagnostic");
+        assert_eq!(diagnostic.message, "'rm' can't be run from an env block");
+        assert_eq!(scope.get_env_var("MODE"), None);
+    }
+
+    #[test]
+    fn env_for_process_matches_the_env_vars() {
+        let scope = Scope::new();
+        scope
+            .add_env_var("EDITOR", "vim".to_string())
+            .expect("env var should be accepted");
+        scope.enter_scope();
+        scope
+            .add_env_var("PAGER", "less".to_string())
+            .expect("env var should be accepted");
+
+        let expected: Vec<_> = scope
+            .get_env_vars()
+            .into_iter()
+            .map(|(name, value)| (OsString::from(name), OsString::from(value)))
+            .collect();
+
+        assert_eq!(scope.env_for_process(), expected);
+        assert_eq!(expected.len(), 2);
+    }
+
+    #[test]
+    fn env_for_process_sees_values_shadowed_in_inner_frames() {
+        let scope = Scope::new();
+        scope
+            .add_env_var("EDITOR", "vim".to_string())
+            .expect("env var should be accepted");
+
+        let mut overrides = IndexMap::new();
+        overrides.insert("EDITOR".to_string(), "nano".to_string());
+
+        let (merged, spawned) = scope
+            .with_temp_env(overrides, || {
+                (scope.get_env_vars(), scope.env_for_process())
+            })
+            .expect("overrides were rejected");
+
+        assert_eq!(merged.get("EDITOR"), Some(&"nano".to_string()));
+        assert_eq!(
+            spawned,
+            vec![(OsString::from("EDITOR"), OsString::from("nano"))]
+        );
+        assert_eq!(scope.get_env_vars().get("EDITOR"), Some(&"vim".to_string()));
+    }
+
+    #[test]
+    fn visible_env_vars_leave_out_internal_ones() {
+        let scope = Scope::new();
+        scope
+            .add_env_var("EDITOR", "vim".to_string())
+            .expect("env var should be accepted");
+        scope
+            .add_env_var(
+                format!("{}LAST_DIR", INTERNAL_ENV_PREFIX),
+                "/tmp".to_string(),
+            )
+            .expect("env var should be accepted");
+
+        let visible = scope.get_visible_env_vars();
+        assert_eq!(visible.len(), 1);
+        assert_eq!(visible.get("EDITOR"), Some(&"vim".to_string()));
+        assert_eq!(scope.get_env_vars().len(), 2);
+        assert_eq!(
+            scope
+                .get_env_vars_filtered(|_, value| value.starts_with('/'))
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn merged_env_is_reused_until_env_changes() {
+        let scope = Scope::new();
+        scope
+            .add_env_var("EDITOR", "vim".to_string())
+            .expect("env var should be accepted");
+        let generation = scope.env_generation();
+
+        let first = scope.get_env_vars();
+        let second = scope.get_env_vars();
+
+        assert_eq!(first, second);
+        assert_eq!(scope.env_generation(), generation);
+        assert_eq!(
+            scope.env_cache.lock().as_ref().map(|(cached, _)| *cached),
+            Some(generation)
+        );
+
+        scope.enter_scope();
+        scope
+            .add_env_var("PAGER", "less".to_string())
+            .expect("env var should be accepted");
+        assert_eq!(scope.get_env_vars().get("PAGER"), Some(&"less".to_string()));
+
+        scope.exit_scope();
+        assert_eq!(scope.get_env_vars().get("PAGER"), None);
+        assert_eq!(scope.get_env_vars().get("EDITOR"), Some(&"vim".to_string()));
+    }
+
+    #[test]
+    fn exit_scope_keeping_env_refreshes_the_merged_env() {
+        let scope = Scope::new();
+        scope
+            .add_env_var("EDITOR", "vim".to_string())
+            .expect("env var should be accepted");
+        scope.enter_isolated_env_scope(&[]);
+        scope
+            .add_env_var("PAGER", "less".to_string())
+            .expect("env var should be accepted");
+        assert_eq!(scope.get_env_vars().get("EDITOR"), None);
+
+        assert!(scope.exit_scope_keeping_env(Span::unknown()).is_ok());
+
+        let env = scope.get_env_vars();
+        assert_eq!(env.get("EDITOR"), Some(&"vim".to_string()));
+        assert_eq!(env.get("PAGER"), Some(&"less".to_string()));
+    }
+
+    #[test]
+    fn exit_scope_keeping_env_refuses_to_drop_env_on_a_frozen_frame() {
+        let scope = Scope::new();
+        scope.enter_scope();
+        scope.freeze_current();
+        scope.enter_scope();
+        scope
+            .add_env_var("PAGER", "less".to_string())
+            .expect("env var should be accepted");
+
+        assert!(scope.exit_scope_keeping_env(Span::unknown()).is_err());
+        assert_eq!(scope.get_env_var("PAGER"), Some("less".to_string()));
+        assert_eq!(scope.frames.lock().len(), 3);
+    }
+
+    #[test]
+    fn env_diff_reports_changes_since_a_capture() {
+        let scope = Scope::new();
+        scope
+            .add_env_var("EDITOR", "vim".to_string())
+            .expect("env var should be accepted");
+        scope
+            .add_env_var("PAGER", "more".to_string())
+            .expect("env var should be accepted");
+        scope
+            .add_env_var("TERM", "xterm".to_string())
+            .expect("env var should be accepted");
+        let snapshot = scope.capture();
+
+        scope
+            .add_env_var("PAGER", "less".to_string())
+            .expect("env var should be accepted");
+        scope.remove_env_var("TERM");
+        scope
+            .add_env_var("VISUAL", "code".to_string())
+            .expect("env var should be accepted");
+
+        assert_eq!(
+            scope.env_diff(&snapshot),
+            EntryDiff {
+                added: vec!["VISUAL".to_string()],
+                removed: vec!["TERM".to_string()],
+                changed: vec!["PAGER".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn diff_lists_added_removed_and_changed_entries() {
+        let before = Scope::new();
+        before.add_var(
+            "$theme",
+            UntaggedValue::string("dark").into_untagged_value(),
+        );
+        before.add_var("$old", UntaggedValue::int(1).into_untagged_value());
+        before
+            .add_env_var("EDITOR", "vim".to_string())
+            .expect("env var should be accepted");
+        before.add_alias("ll", vec!["ls".to_string().spanned_unknown()]);
+
+        let after = Scope::new();
+        after.add_var(
+            "$theme",
+            UntaggedValue::string("light").into_untagged_value(),
+        );
+        after
+            .add_env_var("EDITOR", "vim".to_string())
+            .expect("env var should be accepted");
+        after
+            .add_env_var("PAGER", "less".to_string())
+            .expect("env var should be accepted");
+        after.add_alias("ll", vec!["ls".to_string().spanned_unknown()]);
+        after.add_command("cmd".to_string(), whole_stream_command(Named("cmd")));
+
+        let diff = before.diff(&after);
+
+        assert_eq!(
+            diff.vars,
+            EntryDiff {
+                added: vec![],
+                removed: vec!["$old".to_string()],
+                changed: vec!["$theme".to_string()],
+            }
+        );
+        assert_eq!(diff.env.added, vec!["PAGER".to_string()]);
+        assert!(diff.env.changed.is_empty());
+        assert_eq!(diff.commands.added, vec!["cmd".to_string()]);
+        assert_eq!(diff.custom_commands, EntryDiff::default());
+        assert_eq!(diff.aliases, EntryDiff::default());
+
+        let value = diff.to_value(Tag::unknown());
+        let added = value
+            .get_data_by_key("env".spanned_unknown())
+            .and_then(|env| env.get_data_by_key("added".spanned_unknown()))
+            .expect("diff value should list added env vars");
+        assert_eq!(
+            added
+                .table_entries()
+                .next()
+                .map(|name| name.as_string().ok()),
+            Some(Some("PAGER".to_string()))
+        );
+    }
+
+    #[test]
+    fn try_exit_scope_keeps_the_global_frame() {
+        let scope = Scope::new();
+        scope.enter_scope();
+
+        assert!(scope.try_exit_scope(Span::unknown()).is_ok());
+        assert!(scope.try_exit_scope(Span::unknown()).is_err());
+        assert_eq!(scope.stats().frames.len(), 1);
+    }
+
+    #[test]
+    fn get_env_var_or_falls_back_to_default() {
+        let scope = Scope::new();
+        scope
+            .add_env_var("EDITOR", "vim".to_string())
+            .expect("env var should be accepted");
+
+        assert_eq!(scope.get_env_var_or("EDITOR", "nano"), "vim");
+        assert_eq!(scope.get_env_var_or("PAGER", "less"), "less");
+    }
+
+    #[test]
+    fn promote_var_moves_binding_to_parent() {
+        let scope = Scope::new();
+        scope.enter_scope();
+        scope.add_var("$total", UntaggedValue::int(3).into_untagged_value());
+
+        scope
+            .promote_var("$total")
+            .expect("promotion should succeed");
+        scope.exit_scope();
+
+        assert_eq!(
+            scope.get_var("$total"),
+            Some(UntaggedValue::int(3).into_untagged_value())
+        );
     }
 
-    pub fn get_command(&self, name: &str) -> Option<Command> {
-        self.commands.get(name).cloned()
+    #[test]
+    fn promote_var_errors_without_parent() {
+        let scope = Scope::new();
+        scope.add_var("$total", UntaggedValue::int(3).into_untagged_value());
+
+        assert!(scope.promote_var("$total").is_err());
+        assert!(scope.get_var("$total").is_some());
     }
 
-    pub fn new() -> ScopeFrame {
-        ScopeFrame {
-            vars: IndexMap::new(),
-            env: IndexMap::new(),
-            commands: IndexMap::new(),
-            custom_commands: IndexMap::new(),
-            aliases: IndexMap::new(),
+    fn path(members: &[&str]) -> ColumnPath {
+        ColumnPath::new(
+            members
+                .iter()
+                .map(|member| PathMember::string(*member, Span::unknown()))
+                .collect(),
+        )
+    }
+
+    fn config_scope() -> Scope {
+        let scope = Scope::new();
+        let theme = UntaggedValue::row(indexmap! {
+            "color".to_string() => UntaggedValue::string("dark").into_untagged_value(),
+        })
+        .into_untagged_value();
+        scope.add_var(
+            "$config",
+            UntaggedValue::row(indexmap! { "theme".to_string() => theme }).into_untagged_value(),
+        );
+        scope
+    }
+
+    #[test]
+    fn get_var_path_drills_into_nested_records() {
+        let scope = config_scope();
+
+        assert_eq!(
+            scope.get_var_path(&path(&["$config", "theme", "color"])),
+            Some(UntaggedValue::string("dark").into_untagged_value())
+        );
+    }
+
+    #[test]
+    fn get_var_path_returns_none_for_missing_leaf() {
+        let scope = config_scope();
+
+        assert!(scope
+            .get_var_path(&path(&["$config", "theme", "font"]))
+            .is_none());
+        assert!(scope
+            .expect_var_path(&path(&["$config", "theme", "font"]))
+            .is_err());
+        assert!(scope.get_var_path(&path(&["$missing", "theme"])).is_none());
+    }
+
+    struct Named(&'static str);
+
+    #[async_trait]
+    impl WholeStreamCommand for Named {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn usage(&self) -> &str {
+            ""
+        }
+
+        async fn run(&self, _args: CommandArgs) -> Result<OutputStream, ShellError> {
+            Ok(OutputStream::empty())
         }
     }
-}
 
-// impl Scope {
-//     pub fn vars(&self) -> IndexMap<String, Value> {
-//         //FIXME: should this be an interator?
+    #[test]
+    fn get_command_reuses_cached_lookups() {
+        let scope = Scope::new();
+        scope.add_command("cached".to_string(), whole_stream_command(Named("cached")));
 
-//         let mut output = IndexMap::new();
+        assert!(scope.get_command("cached").is_some());
 
-//         for v in &self.vars {
-//             output.insert(v.0.clone(), v.1.clone());
-//         }
+        // Remove the command behind the cache's back: a re-scan would no longer find it
+        scope.frames.lock()[0].commands.clear();
 
-//         if let Some(parent) = &self.parent {
-//             for v in parent.vars() {
-//                 if !output.contains_key(&v.0) {
-//                     output.insert(v.0.clone(), v.1.clone());
-//                 }
-//             }
-//         }
+        assert!(scope.get_command("cached").is_some());
+    }
 
-//         output
-//     }
+    #[test]
+    fn command_cache_is_invalidated_by_shadowing() {
+        let scope = Scope::new();
+        scope.add_command("cmd".to_string(), whole_stream_command(Named("outer")));
+        assert_eq!(
+            scope.get_command("cmd").map(|c| c.name().to_string()),
+            Some("outer".to_string())
+        );
 
-//     pub fn env(&self) -> IndexMap<String, String> {
-//         //FIXME: should this be an interator?
+        scope.enter_scope();
+        scope.add_command("cmd".to_string(), whole_stream_command(Named("inner")));
+        assert_eq!(
+            scope.get_command("cmd").map(|c| c.name().to_string()),
+            Some("inner".to_string())
+        );
 
-//         let mut output = IndexMap::new();
+        scope.exit_scope();
+        assert_eq!(
+            scope.get_command("cmd").map(|c| c.name().to_string()),
+            Some("outer".to_string())
+        );
+    }
 
-//         for v in &self.env {
-//             output.insert(v.0.clone(), v.1.clone());
-//         }
+    #[test]
+    fn env_vars_fold_case_when_insensitive() {
+        let scope = Scope::with_env_var_case(EnvVarCase::Insensitive);
+        scope
+            .add_env_var("Path", "/bin".to_string())
+            .expect("env var should be accepted");
+        scope
+            .add_env_var("PATH", "/usr/bin".to_string())
+            .expect("env var should be accepted");
 
-//         if let Some(parent) = &self.parent {
-//             for v in parent.env() {
-//                 if !output.contains_key(&v.0) {
-//                     output.insert(v.0.clone(), v.1.clone());
-//                 }
-//             }
-//         }
+        assert_eq!(scope.get_env_var("PATH"), Some("/usr/bin".to_string()));
 
-//         output
-//     }
+        let env = scope.get_env_vars();
+        assert_eq!(env.len(), 1);
+        assert_eq!(env.get("Path"), Some(&"/usr/bin".to_string()));
 
-//     pub fn var(&self, name: &str) -> Option<Value> {
-//         if let Some(value) = self.vars().get(name) {
-//             Some(value.clone())
-//         } else {
-//             None
-//         }
-//     }
+        assert_eq!(scope.remove_env_var("path"), Some("/usr/bin".to_string()));
+        assert!(scope.get_env_vars().is_empty());
+    }
 
-//     pub fn append_var(this: Arc<Self>, name: impl Into<String>, value: Value) -> Arc<Scope> {
-//         let mut vars = IndexMap::new();
-//         vars.insert(name.into(), value);
-//         Arc::new(Scope {
-//             vars,
-//             env: IndexMap::new(),
-//             commands: IndexMap::new(),
-//             aliases: IndexMap::new(),
-//             parent: Some(this),
-//         })
-//     }
+    #[test]
+    fn env_vars_keep_case_when_sensitive() {
+        let scope = Scope::with_env_var_case(EnvVarCase::Sensitive);
+        scope
+            .add_env_var("Path", "/bin".to_string())
+            .expect("env var should be accepted");
+        scope
+            .add_env_var("PATH", "/usr/bin".to_string())
+            .expect("env var should be accepted");
 
-//     pub fn append_vars(this: Arc<Self>, vars: IndexMap<String, Value>) -> Arc<Scope> {
-//         Arc::new(Scope {
-//             vars,
-//             env: IndexMap::new(),
-//             commands: IndexMap::new(),
-//             aliases: IndexMap::new(),
-//             parent: Some(this),
-//         })
-//     }
+        assert_eq!(scope.get_env_var("PATH"), Some("/usr/bin".to_string()));
+        assert_eq!(scope.get_env_vars().len(), 2);
+        assert_eq!(scope.remove_env_var("path"), None);
+    }
 
-//     pub fn append_env(this: Arc<Self>, env: IndexMap<String, String>) -> Arc<Scope> {
-//         Arc::new(Scope {
-//             vars: IndexMap::new(),
-//             env,
-//             commands: IndexMap::new(),
-//             aliases: IndexMap::new(),
-//             parent: Some(this),
-//         })
-//     }
+    #[test]
+    fn env_var_history_lists_shadowed_values_innermost_first() {
+        let scope = Scope::new();
+        scope
+            .add_env_var("PATH", "/bin".to_string())
+            .expect("env var should be accepted");
+        scope.enter_scope();
+        scope.enter_scope();
+        scope
+            .add_env_var("PATH", "/usr/bin".to_string())
+            .expect("env var should be accepted");
 
-// }
+        assert_eq!(
+            scope.env_var_history("PATH"),
+            vec![(2, "/usr/bin".to_string()), (0, "/bin".to_string())]
+        );
+        assert!(scope.env_var_history("HOME").is_empty());
+    }
+
+    #[test]
+    fn depth_of_var_reports_resolving_frame() {
+        let scope = Scope::new();
+        scope.add_var("$global", UntaggedValue::int(1).into_untagged_value());
+        scope.enter_scope();
+        scope.add_var("$local", UntaggedValue::int(2).into_untagged_value());
+
+        assert_eq!(scope.depth_of_var("$global"), Some(0));
+        assert_eq!(scope.depth_of_var("$local"), Some(1));
+        assert_eq!(scope.depth_of_var("$unbound"), None);
+    }
+
+    #[test]
+    fn frozen_frame_ignores_setters() {
+        let scope = Scope::new();
+        scope.add_var("$outer", UntaggedValue::int(1).into_untagged_value());
+        scope.enter_scope();
+        scope.add_var("$inner", UntaggedValue::int(2).into_untagged_value());
+        scope.freeze_current();
+
+        scope.add_var("$inner", UntaggedValue::int(3).into_untagged_value());
+        scope.add_var("$new", UntaggedValue::int(4).into_untagged_value());
+        scope
+            .add_env_var("FROZEN", "yes".to_string())
+            .expect("env var should be accepted");
+
+        assert_eq!(
+            scope.get_var("$inner"),
+            Some(UntaggedValue::int(2).into_untagged_value())
+        );
+        assert_eq!(scope.get_var("$new"), None);
+        assert_eq!(scope.get_env_var("FROZEN"), None);
+        assert_eq!(
+            scope.get_var("$outer"),
+            Some(UntaggedValue::int(1).into_untagged_value())
+        );
+    }
+
+    #[test]
+    fn frozen_frame_ignores_removals() {
+        let scope = Scope::new();
+        scope.add_var("$kept", UntaggedValue::int(1).into_untagged_value());
+        scope
+            .add_env_var("KEPT", "yes".to_string())
+            .expect("env var should be accepted");
+        scope.add_alias("ll", vec!["ls".to_string().spanned_unknown()]);
+        scope.add_definition(definition("kept"));
+        scope.freeze_current();
+
+        assert_eq!(scope.remove_env_var("KEPT"), None);
+        assert!(!scope.remove_alias("ll"));
+        assert!(scope.remove_command("kept").is_none());
+        assert!(scope.rename_var("$kept", "$moved").is_err());
+
+        assert_eq!(scope.get_env_var("KEPT"), Some("yes".to_string()));
+        assert!(scope.get_alias("ll").is_some());
+        assert!(scope.get_command("kept").is_some());
+        assert!(scope.get_var("$kept").is_some());
+    }
+
+    fn definition(name: &str) -> Block {
+        let mut block = Block::basic();
+        block.params.name = name.to_string();
+        block
+    }
+
+    #[test]
+    fn diff_reports_definitions() {
+        let before = Scope::new();
+        before.add_definition(definition("kept"));
+        before.add_definition(definition("dropped"));
+        before.add_definition(definition("redefined"));
+
+        let after = Scope::new();
+        after.add_definition(definition("kept"));
+        after.add_definition(definition("added"));
+        let mut redefined = definition("redefined");
+        redefined.span = Span::new(0, 10);
+        after.add_definition(redefined);
+
+        assert_eq!(
+            before.diff(&after).custom_commands,
+            EntryDiff {
+                added: vec!["added".to_string()],
+                removed: vec!["dropped".to_string()],
+                changed: vec!["redefined".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn resolve_prefers_commands_over_vars() {
+        let scope = Scope::new();
+        scope.add_command("ls".to_string(), whole_stream_command(Named("builtin ls")));
+        scope.enter_scope();
+        scope.add_var("$ls", UntaggedValue::int(1).into_untagged_value());
+        scope
+            .add_env_var("EDITOR", "vi".to_string())
+            .expect("env var should be accepted");
+
+        assert!(matches!(scope.resolve("ls"), Some(Resolution::Command(_))));
+        assert!(matches!(
+            scope.resolve("$ls"),
+            Some(Resolution::Variable(value)) if value.value == UntaggedValue::int(1)
+        ));
+        assert!(matches!(scope.resolve("EDITOR"), Some(Resolution::Env(value)) if value == "vi"));
+
+        scope.add_alias("ls", vec!["ls -a".to_string().spanned_unknown()]);
+        assert!(matches!(scope.resolve("ls"), Some(Resolution::Alias(_))));
+        assert!(scope.resolve("missing").is_none());
+    }
+
+    #[test]
+    fn removed_command_uncovers_the_outer_one() {
+        let scope = Scope::new();
+        scope.add_command("cmd".to_string(), whole_stream_command(Named("outer")));
+        scope.enter_scope();
+        scope.add_command("cmd".to_string(), whole_stream_command(Named("plugin")));
+        assert_eq!(
+            scope.get_command("cmd").map(|c| c.name().to_string()),
+            Some("plugin".to_string())
+        );
+
+        let removed = scope.remove_command("cmd");
+
+        assert_eq!(
+            removed.map(|c| c.name().to_string()),
+            Some("plugin".to_string())
+        );
+        assert_eq!(
+            scope.get_command("cmd").map(|c| c.name().to_string()),
+            Some("outer".to_string())
+        );
+        assert!(scope.remove_command("cmd").is_some());
+        assert!(scope.remove_command("cmd").is_none());
+    }
+
+    #[test]
+    fn command_lookup_skips_aliases() {
+        let scope = Scope::new();
+        scope.add_command("ls".to_string(), whole_stream_command(Named("builtin ls")));
+        scope.add_alias("ls", vec!["echo".to_string().spanned_unknown()]);
+        scope.add_alias("ll", vec!["ls".to_string().spanned_unknown()]);
+        scope.add_definition(definition("mkcd"));
+
+        assert!(matches!(scope.resolve("ls"), Some(Resolution::Alias(_))));
+        assert_eq!(
+            scope.get_command("ls").map(|c| c.name().to_string()),
+            Some("builtin ls".to_string())
+        );
+        assert!(scope.get_command("mkcd").is_some());
+        assert!(scope.get_command("ll").is_none());
+    }
+
+    #[test]
+    fn commands_are_filtered_by_category() {
+        let scope = Scope::new();
+        scope.add_definition_with_categories(definition("mkcd"), vec!["fs".to_string()]);
+        scope.add_definition_with_categories(
+            definition("backup"),
+            vec!["fs".to_string(), "git".to_string()],
+        );
+        scope.add_definition_with_categories(definition("gco"), vec!["git".to_string()]);
+        scope.add_definition(definition("plain"));
+
+        assert_eq!(scope.commands_in_category("fs"), vec!["backup", "mkcd"]);
+        assert_eq!(scope.commands_in_category("git"), vec!["backup", "gco"]);
+        assert!(scope.commands_in_category("net").is_empty());
+
+        scope.enter_scope();
+        scope.add_definition(definition("mkcd"));
+        assert_eq!(scope.commands_in_category("fs"), vec!["backup"]);
+    }
+
+    #[test]
+    fn shadowed_builtin_is_reachable_from_its_override() {
+        let scope = Scope::new();
+        scope.add_command("ls".to_string(), whole_stream_command(Named("builtin ls")));
+        assert!(scope.get_shadowed_command("ls").is_none());
+
+        scope.enter_scope();
+        scope.add_definition(definition("ls"));
+
+        assert_eq!(
+            scope.get_command("ls").map(|c| c.name().to_string()),
+            Some("ls".to_string())
+        );
+        assert_eq!(
+            scope
+                .get_shadowed_command("ls")
+                .map(|c| c.name().to_string()),
+            Some("builtin ls".to_string())
+        );
+    }
+
+    #[test]
+    fn only_exported_definitions_are_returned() {
+        let scope = Scope::new();
+        scope.add_definition(definition("public"));
+        scope.add_definition(definition("private"));
+        scope.mark_exported("public");
+
+        let exported = scope.get_exported_definitions();
+
+        assert_eq!(exported.len(), 1);
+        assert_eq!(exported[0].params.name, "public");
+        assert_eq!(scope.get_definitions().len(), 2);
+    }
+
+    #[test]
+    fn add_env_prefixed_only_adds_prefixed_names() {
+        let scope = Scope::new();
+        let mut vars = IndexMap::new();
+        vars.insert("HOME".to_string(), "/home/nu".to_string());
+        vars.insert("SHELL".to_string(), "/bin/nu".to_string());
+
+        scope
+            .add_env_prefixed("HOST_", vars)
+            .expect("env vars should be accepted");
+
+        assert_eq!(scope.get_env_var("HOST_HOME"), Some("/home/nu".to_string()));
+        assert_eq!(scope.get_env_var("HOST_SHELL"), Some("/bin/nu".to_string()));
+        assert_eq!(scope.get_env_var("HOME"), None);
+        assert_eq!(scope.get_env_var("SHELL"), None);
+    }
+
+    #[test]
+    fn with_temp_env_restores_outer_value() {
+        let scope = Scope::new();
+        scope
+            .add_env_var("FOO", "outer".to_string())
+            .expect("env var should be accepted");
+
+        let mut overrides = IndexMap::new();
+        overrides.insert("FOO".to_string(), "inner".to_string());
+
+        let (seen, merged) = scope
+            .with_temp_env(overrides.clone(), || {
+                (scope.get_env_var("FOO"), scope.env_for_process())
+            })
+            .expect("overrides were rejected");
+        assert_eq!(seen, Some("inner".to_string()));
+        // What an external started inside the closure would be given
+        assert_eq!(
+            merged,
+            vec![(OsString::from("FOO"), OsString::from("inner"))]
+        );
+        assert_eq!(scope.get_env_var("FOO"), Some("outer".to_string()));
+
+        let unwound = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            scope.with_temp_env(overrides, || panic!("inside temp env"))
+        }));
+        assert!(unwound.is_err());
+        assert_eq!(scope.get_env_var("FOO"), Some("outer".to_string()));
+    }
+
+    #[test]
+    fn with_vars_binds_only_for_the_closure() {
+        let scope = Scope::new();
+
+        let mut vars = IndexMap::new();
+        vars.insert(
+            "$x".to_string(),
+            UntaggedValue::int(1).into_untagged_value(),
+        );
+
+        let seen = scope.with_vars(vars.clone(), || scope.get_var("$x"));
+        assert_eq!(seen.map(|value| value.value), Some(UntaggedValue::int(1)));
+        assert!(scope.get_var("$x").is_none());
+
+        let unwound = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            scope.with_vars(vars, || panic!("inside with_vars"))
+        }));
+        assert!(unwound.is_err());
+        assert!(scope.get_var("$x").is_none());
+    }
+
+    #[test]
+    fn expect_command_spanned_points_at_invocation() {
+        let scope = Scope::new();
+
+        let err = scope
+            .expect_command_spanned("missing", Span::new(4, 11))
+            .expect_err("command should be missing");
+        let diagnostic = err.into_diagnostic().expect("no diagnostic");
+
+        assert_eq!(diagnostic.labels.len(), 1);
+        assert_eq!(diagnostic.labels[0].range, 4..11);
+    }
+
+    #[test]
+    fn global_alias_outlives_the_frame_it_was_defined_in() {
+        let scope = Scope::new();
+        scope.enter_scope();
+        scope.add_global_alias("ll", vec!["ls".to_string().spanned_unknown()]);
+        scope.exit_scope();
+
+        assert_eq!(
+            scope.get_alias("ll"),
+            Some(vec!["ls".to_string().spanned_unknown()])
+        );
+    }
+
+    #[test]
+    fn global_env_var_outlives_the_frame_it_was_set_in() {
+        let scope = Scope::new();
+        scope.enter_scope();
+        scope
+            .add_global_env_var(LAST_EXIT_CODE, "1".to_string())
+            .expect("env var should be accepted");
+        scope.exit_scope();
+
+        assert_eq!(scope.get_env_var(LAST_EXIT_CODE), Some("1".to_string()));
+    }
+
+    #[test]
+    fn alias_names_are_sorted_and_deduped() {
+        let scope = Scope::new();
+        scope.add_alias("ll", vec!["ls".to_string().spanned_unknown()]);
+        scope.add_alias("g", vec!["git".to_string().spanned_unknown()]);
+        scope.enter_scope();
+        scope.add_alias("ll", vec!["ls -l".to_string().spanned_unknown()]);
+        scope.add_alias("c", vec!["clear".to_string().spanned_unknown()]);
+
+        assert_eq!(
+            scope.get_alias_names(),
+            vec!["c".to_string(), "g".to_string(), "ll".to_string()]
+        );
+    }
+
+    #[test]
+    fn resolve_alias_chain_follows_aliases() {
+        let scope = Scope::new();
+        scope.add_alias("ll", vec!["l".to_string().spanned_unknown()]);
+        scope.add_alias(
+            "l",
+            vec![
+                "ls".to_string().spanned_unknown(),
+                "-l".to_string().spanned_unknown(),
+            ],
+        );
+
+        let expansion = scope
+            .resolve_alias_chain("ll")
+            .expect("alias should resolve");
+        let items: Vec<_> = expansion.into_iter().map(|token| token.item).collect();
+
+        assert_eq!(items, vec!["ls".to_string(), "-l".to_string()]);
+    }
+
+    #[test]
+    fn alias_target_command_follows_the_chain_to_the_command() {
+        let scope = Scope::new();
+        scope.add_alias(
+            "g",
+            vec![
+                "git".to_string().spanned_unknown(),
+                "status".to_string().spanned_unknown(),
+            ],
+        );
+        scope.add_alias("gg", vec!["g".to_string().spanned_unknown()]);
+        scope.add_alias("a", vec!["b".to_string().spanned_unknown()]);
+        scope.add_alias("b", vec!["a".to_string().spanned_unknown()]);
+
+        assert_eq!(scope.alias_target_command("g"), Some("git".to_string()));
+        assert_eq!(scope.alias_target_command("gg"), Some("git".to_string()));
+        assert_eq!(scope.alias_target_command("a"), None);
+        assert_eq!(scope.alias_target_command("git"), None);
+    }
+
+    #[test]
+    fn preview_alias_shows_the_full_expansion() {
+        let scope = Scope::new();
+        scope.add_alias(
+            "g",
+            vec![
+                "git".to_string().spanned_unknown(),
+                "status".to_string().spanned_unknown(),
+            ],
+        );
+        scope.add_alias(
+            "gs",
+            vec![
+                "g".to_string().spanned_unknown(),
+                "--short".to_string().spanned_unknown(),
+            ],
+        );
+        scope.add_alias("a", vec!["b".to_string().spanned_unknown()]);
+        scope.add_alias("b", vec!["a".to_string().spanned_unknown()]);
+
+        assert_eq!(
+            scope.preview_alias("g").ok(),
+            Some("git status".to_string())
+        );
+        assert_eq!(
+            scope.preview_alias("gs").ok(),
+            Some("git status --short".to_string())
+        );
+        assert!(scope.preview_alias("a").is_err());
+        assert!(scope.preview_alias("git").is_err());
+    }
+
+    #[test]
+    fn resolve_alias_chain_detects_direct_cycle() {
+        let scope = Scope::new();
+        scope.add_alias("a", vec!["b".to_string().spanned_unknown()]);
+        scope.add_alias("b", vec!["a".to_string().spanned_unknown()]);
+
+        let err = scope
+            .resolve_alias_chain("a")
+            .expect_err("cycle should be rejected");
+        let diagnostic = err.into_diagnostic().expect("no diagnostic");
+
+        assert_eq!(diagnostic.message, "alias cycle detected: a -> b -> a");
+    }
+
+    #[test]
+    fn resolve_alias_chain_stops_at_an_alias_naming_itself() {
+        let scope = Scope::new();
+        scope.add_alias(
+            "ls",
+            vec![
+                "ls".to_string().spanned_unknown(),
+                "-a".to_string().spanned_unknown(),
+            ],
+        );
+        scope.add_alias("l", vec!["ls".to_string().spanned_unknown()]);
+
+        let items = |name: &str| -> Vec<String> {
+            scope
+                .resolve_alias_chain(name)
+                .expect("alias should resolve")
+                .into_iter()
+                .map(|token| token.item)
+                .collect()
+        };
+
+        assert_eq!(items("ls"), vec!["ls".to_string(), "-a".to_string()]);
+        assert_eq!(items("l"), vec!["ls".to_string(), "-a".to_string()]);
+        assert_eq!(scope.alias_target_command("ls"), Some("ls".to_string()));
+        assert_eq!(scope.preview_alias("l").ok(), Some("ls -a".to_string()));
+    }
+
+    #[test]
+    fn resolve_alias_chain_detects_indirect_cycle() {
+        let scope = Scope::new();
+        scope.add_alias("a", vec!["b".to_string().spanned_unknown()]);
+        scope.add_alias("b", vec!["c".to_string().spanned_unknown()]);
+        scope.add_alias("c", vec!["a".to_string().spanned_unknown()]);
+
+        let err = scope
+            .resolve_alias_chain("a")
+            .expect_err("cycle should be rejected");
+        let diagnostic = err.into_diagnostic().expect("no diagnostic");
+
+        assert_eq!(diagnostic.message, "alias cycle detected: a -> b -> c -> a");
+    }
+
+    #[test]
+    fn computed_env_var_is_recomputed_on_each_read() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let scope = Scope::new();
+        let branch = Arc::new(AtomicUsize::new(1));
+        let current = branch.clone();
+        scope.add_computed_env_var("GIT_BRANCH", move || {
+            format!("branch-{}", current.load(Ordering::SeqCst))
+        });
+
+        assert_eq!(
+            scope.get_env_vars().get("GIT_BRANCH"),
+            Some(&"branch-1".to_string())
+        );
+
+        branch.store(2, Ordering::SeqCst);
+        assert_eq!(
+            scope.get_env_var("GIT_BRANCH"),
+            Some("branch-2".to_string())
+        );
+    }
+
+    fn two_param_definition() -> Block {
+        let mut block = definition("greet");
+        block.params = block
+            .params
+            .required("name", SyntaxShape::String, "who to greet")
+            .optional("greeting", SyntaxShape::String, "what to say");
+        block
+    }
+
+    #[test]
+    fn bind_params_binds_args_in_order() {
+        let scope = Scope::new();
+        let block = two_param_definition();
+
+        scope
+            .bind_params(
+                &block,
+                &[UntaggedValue::string("nu").into_untagged_value()],
+                Span::unknown(),
+            )
+            .expect("one argument should be enough");
+
+        assert_eq!(
+            scope.get_var("$name").map(|value| value.value),
+            Some(UntaggedValue::string("nu"))
+        );
+        assert!(scope.get_var("$greeting").is_none());
+
+        scope.exit_scope();
+        assert!(scope.get_var("$name").is_none());
+    }
+
+    #[test]
+    fn bind_params_rejects_wrong_arity() {
+        let scope = Scope::new();
+        let block = two_param_definition();
+        let arg = || UntaggedValue::string("nu").into_untagged_value();
+
+        let call_span = Span::new(20, 30);
+
+        let too_few = scope
+            .bind_params(&block, &[], call_span)
+            .expect_err("no arguments should be too few");
+        assert!(scope
+            .bind_params(&block, &[arg(), arg(), arg()], call_span)
+            .is_err());
+        assert_eq!(scope.stats().frames.len(), 1);
+
+        let diagnostic = too_few.into_diagnostic().expect("no diagnostic");
+        assert_eq!(diagnostic.labels[0].range, 20..30);
+    }
+
+    #[test]
+    fn get_var_as_reads_typed_values() {
+        let scope = Scope::new();
+        scope.add_var("$count", UntaggedValue::int(3).into_untagged_value());
+        scope.add_var("$name", UntaggedValue::string("nu").into_untagged_value());
+
+        assert_eq!(scope.get_var_as::<i64>("$count").ok(), Some(Some(3)));
+        assert!(scope.get_var_as::<i64>("$name").is_err());
+        assert_eq!(scope.get_var_as::<i64>("$missing").ok(), Some(None));
+    }
+
+    #[test]
+    fn named_frames_show_up_in_the_backtrace() {
+        let scope = Scope::new();
+        assert_eq!(scope.backtrace(), None);
+
+        scope.enter_named_scope("command outer");
+        scope.enter_scope();
+        scope.enter_named_scope("block for each");
+
+        assert_eq!(
+            scope.frame_labels(),
+            vec![
+                None,
+                Some("command outer".to_string()),
+                None,
+                Some("block for each".to_string())
+            ]
+        );
+        assert_eq!(
+            scope.backtrace(),
+            Some("block for each <- command outer".to_string())
+        );
+    }
+
+    #[test]
+    fn for_each_frame_visits_outermost_first() {
+        let scope = Scope::new();
+        scope.add_var("$a", UntaggedValue::int(1).into_untagged_value());
+        scope.enter_scope();
+        scope.enter_scope();
+        scope.add_var("$b", UntaggedValue::int(2).into_untagged_value());
+        scope.add_var("$c", UntaggedValue::int(3).into_untagged_value());
+
+        let mut var_counts = vec![];
+        scope.for_each_frame(|idx, frame| var_counts.push((idx, frame.vars.len())));
+
+        assert_eq!(var_counts, vec![(0, 1), (1, 0), (2, 2)]);
+    }
+
+    #[test]
+    fn stats_count_per_frame_and_visible_items() {
+        let scope = Scope::new();
+        scope.add_command("outer".to_string(), whole_stream_command(Named("outer")));
+        scope.add_var("$x", UntaggedValue::int(1).into_untagged_value());
+        scope
+            .add_env_var("HOME", "/home".to_string())
+            .expect("env var should be accepted");
+
+        scope.enter_scope();
+        scope.add_definition(definition("custom"));
+        scope.add_alias("l", vec!["ls".to_string().spanned_unknown()]);
+        scope.add_var("$x", UntaggedValue::int(2).into_untagged_value());
+        scope.add_var("$y", UntaggedValue::int(3).into_untagged_value());
+
+        let stats = scope.stats();
+
+        assert_eq!(stats.frames.len(), 2);
+        assert_eq!(stats.frames[1].vars, 2);
+        assert_eq!(stats.frames[1].custom_commands, 1);
+        assert_eq!(
+            stats.visible,
+            ScopeCounts {
+                // definitions are registered as commands too
+                commands: 2,
+                custom_commands: 1,
+                aliases: 1,
+                vars: 2,
+                env_vars: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn global_frame_snapshot_is_detached_from_scope() {
+        let scope = Scope::new();
+        scope.add_command("global".to_string(), whole_stream_command(Named("global")));
+        scope.add_var("$global", UntaggedValue::int(1).into_untagged_value());
+
+        scope.enter_scope();
+        scope.add_var("$inner", UntaggedValue::int(2).into_untagged_value());
+
+        let mut snapshot = scope.global_frame_snapshot();
+        assert!(snapshot.has_command("global"));
+        assert!(snapshot.vars.contains_key("$global"));
+        assert!(!snapshot.vars.contains_key("$inner"));
+
+        snapshot.vars.clear();
+        snapshot.commands.clear();
+
+        assert!(scope.has_command("global"));
+        assert_eq!(
+            scope.get_var("$global"),
+            Some(UntaggedValue::int(1).into_untagged_value())
+        );
+    }
+
+    #[test]
+    fn get_custom_command_finds_outer_definitions() {
+        let scope = Scope::new();
+        let mut block = definition("greet");
+        block.span = Span::new(10, 42);
+        scope.add_definition(block);
+
+        scope.enter_scope();
+
+        let found = scope
+            .get_custom_command("greet")
+            .expect("custom command not found");
+        assert_eq!(found.params.name, "greet");
+        assert_eq!(found.span, Span::new(10, 42));
+        assert!(scope.get_custom_command("missing").is_none());
+    }
+
+    #[test]
+    fn env_templates_ignore_cyclic_references() {
+        let scope = Scope::new();
+        scope.add_env_template("A", "a[($B)]".to_string());
+        scope.add_env_template("B", "b[($A)]".to_string());
+
+        assert_eq!(scope.get_env_var("A"), Some("a[b[]]".to_string()));
+        assert_eq!(scope.get_env_vars().get("B"), Some(&"b[a[]]".to_string()));
+    }
+
+    #[test]
+    fn env_templates_render_lazily_unlike_plain_values() {
+        let scope = Scope::new();
+        scope
+            .add_env_var("Y", "1".to_string())
+            .expect("env var should be accepted");
+
+        let eager = scope.render_env_template("prefix ($Y)");
+        scope
+            .add_env_var("EAGER", eager)
+            .expect("env var should be accepted");
+        scope.add_env_template("LAZY", "prefix ($Y)".to_string());
+        scope
+            .add_env_var("Y", "2".to_string())
+            .expect("env var should be accepted");
+
+        assert_eq!(scope.get_env_var("EAGER"), Some("prefix 1".to_string()));
+        assert_eq!(scope.get_env_var("LAZY"), Some("prefix 2".to_string()));
+    }
+
+    #[test]
+    fn add_vars_binds_every_name() {
+        let scope = Scope::new();
+        let vars = (0..100)
+            .map(|i| {
+                (
+                    format!("$var{}", i),
+                    UntaggedValue::int(i).into_untagged_value(),
+                )
+            })
+            .collect();
+
+        scope.add_vars(&vars);
+
+        for i in 0..100 {
+            assert_eq!(
+                scope.get_var(&format!("$var{}", i)),
+                Some(UntaggedValue::int(i).into_untagged_value())
+            );
+        }
+    }
+
+    #[test]
+    fn dump_lists_every_frame() {
+        let scope = Scope::new();
+        scope.add_var("$outer", UntaggedValue::int(1).into_untagged_value());
+        scope.enter_scope();
+        scope
+            .add_env_var("INNER", "yes".to_string())
+            .expect("env var should be accepted");
+        scope.add_alias("l", vec!["ls".to_string().spanned_unknown()]);
+        scope.add_command("cmd".to_string(), whole_stream_command(Named("cmd")));
+
+        let frames = scope
+            .dump(Tag::unknown())
+            .table_entries()
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(frames.len(), 2);
+
+        let field = |frame: &Value, name: &str| {
+            frame
+                .get_data_by_key(name.spanned_unknown())
+                .expect("missing field")
+        };
+        let names = |value: Value| {
+            value
+                .table_entries()
+                .map(|name| name.as_string().expect("not a name"))
+                .collect::<Vec<_>>()
+        };
+
+        assert!(field(&frames[0], "vars")
+            .get_data_by_key("$outer".spanned_unknown())
+            .is_some());
+        assert_eq!(
+            names(field(&frames[1], "commands")),
+            vec!["cmd".to_string()]
+        );
+        assert_eq!(
+            field(&frames[1], "env")
+                .get_data_by_key("INNER".spanned_unknown())
+                .map(|value| value.as_string().expect("not a string")),
+            Some("yes".to_string())
+        );
+        assert_eq!(names(field(&frames[1], "aliases")), vec!["l".to_string()]);
+    }
+}
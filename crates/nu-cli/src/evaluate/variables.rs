@@ -16,7 +16,7 @@ pub fn nu(env: &IndexMap<String, String>, tag: impl Into<Tag>) -> Result<Value,
     }
     nu_dict.insert_value("env", dict.into_value());
 
-    let config = nu_data::config::read(&tag, &None)?;
+    let config = nu_data::config::config(&tag)?;
     nu_dict.insert_value("config", UntaggedValue::row(config).into_value(&tag));
 
     let mut table = vec![];
@@ -1,14 +1,40 @@
 use crate::commands::{command::CommandArgs, Command, UnevaluatedCallInfo};
 use crate::env::host::Host;
+use crate::evaluate::scope::{ENV_PATH_SEPARATOR, ISOLATED_ENV_ALLOWLIST};
 use crate::prelude::*;
 use crate::shell::shell_manager::ShellManager;
-use nu_protocol::hir;
-use nu_source::{Tag, Text};
+use codespan_reporting::diagnostic::LabelStyle;
+use indexmap::IndexSet;
+use lazy_static::lazy_static;
+use nu_protocol::{
+    hir, ColumnPath, RedirectTarget, ShellTypeName, UntaggedValue, Value, Verbosity,
+};
+use nu_source::{Span, Tag, Text};
 use nu_stream::{InputStream, OutputStream};
+use nu_value_ext::ValueExt;
 use parking_lot::Mutex;
 use std::error::Error;
-use std::sync::atomic::AtomicBool;
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+
+pub type Profile = Vec<(String, Duration)>;
+
+/// Called with the old and new paths after `ChangePath` moves the current shell
+pub type ChangePathHook = Arc<dyn Fn(&str, &str) -> Result<(), ShellError> + Send + Sync>;
+
+/// Columns left free when rendering debug values, unless changed with `SetRenderMargin`
+pub(crate) const DEFAULT_RENDER_MARGIN: usize = 5;
+
+lazy_static! {
+    /// External commands running in the background, keyed by job id. The table is shared by every
+    /// context, as commands run in contexts of their own.
+    pub(crate) static ref JOBS: Mutex<IndexMap<usize, Child>> = Mutex::new(IndexMap::new());
+}
+
+static NEXT_JOB_ID: AtomicUsize = AtomicUsize::new(1);
 
 #[derive(Clone)]
 pub struct EvaluationContext {
@@ -17,55 +43,311 @@ pub struct EvaluationContext {
     pub current_errors: Arc<Mutex<Vec<ShellError>>>,
     pub ctrl_c: Arc<AtomicBool>,
     pub user_recently_used_autoenv_untrust: Arc<AtomicBool>,
+    /// When set, side-effecting command actions are described instead of performed
+    pub dry_run: Arc<AtomicBool>,
+    /// When set, errors are turned into structured values in the pipeline rather than reported
+    pub catch_errors: Arc<AtomicBool>,
+    /// When cleared, opened files are passed on as-is rather than converted by their extension
+    pub auto_convert: Arc<AtomicBool>,
+    /// When set, the output of each internal command is collected in full before it is passed on,
+    /// so it can't interleave with the output of background jobs
+    pub buffer_output: Arc<AtomicBool>,
+    /// When set, the first input value of each internal command is checked against the input
+    /// type in its signature before the command is run
+    pub check_input_types: Arc<AtomicBool>,
+    /// When set, as when nu is embedded in another program, exiting only records the request
+    /// (see `take_exit_request`) rather than ending the process
+    pub no_exit: Arc<AtomicBool>,
     pub(crate) shell_manager: ShellManager,
 
+    /// When set, the values going into each internal command are written to stderr
+    pub(crate) trace: Arc<AtomicBool>,
+
+    /// How much detail errors are given when they are reported
+    pub(crate) error_verbosity: Arc<Mutex<Verbosity>>,
+
+    /// Directories of the scripts currently being sourced, innermost last
+    pub(crate) script_dirs: Arc<Mutex<Vec<PathBuf>>>,
+
+    /// Per-command execution times, collected only while profiling is enabled
+    pub(crate) profile: Arc<Mutex<Option<Profile>>>,
+
+    /// Prompt set from a script, taking precedence over the configured `prompt`
+    pub(crate) prompt: Arc<Mutex<Option<Value>>>,
+
+    /// Columns left free at the right edge when rendering debug values
+    pub(crate) render_margin: Arc<AtomicUsize>,
+
+    /// Where the output of pipelines is printed
+    pub(crate) redirect: Arc<Mutex<RedirectTarget>>,
+
+    /// Set when a command asks for the session to be restarted
+    pub(crate) restart_requested: Arc<AtomicBool>,
+
+    /// Set when a command asks to exit while `no_exit` is set
+    pub(crate) exit_requested: Arc<AtomicBool>,
+
+    /// Names of the deprecated commands already warned about this session
+    pub(crate) deprecations_warned: Arc<Mutex<IndexSet<String>>>,
+
+    /// The last line run at the prompt, which `ReexecLast` runs again
+    pub(crate) last_pipeline: Arc<Mutex<Option<String>>>,
+
+    /// Set while the last line is being re-run, so it isn't re-run again from within itself
+    pub(crate) reexecuting: Arc<AtomicBool>,
+
+    /// Set once the prompt's current line has re-run the last one, so it isn't remembered in its place
+    pub(crate) reexecuted_last: Arc<AtomicBool>,
+
+    /// Hooks run after each change of directory, eg. to load a directory's `.nu-env`
+    pub(crate) change_path_hooks: Arc<Mutex<Vec<ChangePathHook>>>,
+
+    /// The REPL's history file and the line being run, which isn't saved until the line finishes
+    pub(crate) pending_history: Arc<Mutex<Option<(PathBuf, String)>>>,
+
     /// Windows-specific: keep track of previous cwd on each drive
     pub windows_drives_previous_cwd: Arc<Mutex<std::collections::HashMap<String, String>>>,
 }
 
+/// The state every context of a session shares: its switches, what it has recorded, and what it
+/// has been asked to do. Commands are given it with their args, so the contexts they make to run
+/// blocks and custom commands carry on with it rather than starting afresh.
+#[derive(Clone)]
+pub struct Session {
+    pub(crate) dry_run: Arc<AtomicBool>,
+    pub(crate) auto_convert: Arc<AtomicBool>,
+    pub(crate) buffer_output: Arc<AtomicBool>,
+    pub(crate) check_input_types: Arc<AtomicBool>,
+    pub(crate) no_exit: Arc<AtomicBool>,
+    pub(crate) catch_errors: Arc<AtomicBool>,
+    pub(crate) trace: Arc<AtomicBool>,
+    pub(crate) error_verbosity: Arc<Mutex<Verbosity>>,
+    pub(crate) script_dirs: Arc<Mutex<Vec<PathBuf>>>,
+    pub(crate) profile: Arc<Mutex<Option<Profile>>>,
+    pub(crate) prompt: Arc<Mutex<Option<Value>>>,
+    pub(crate) render_margin: Arc<AtomicUsize>,
+    pub(crate) redirect: Arc<Mutex<RedirectTarget>>,
+    pub(crate) restart_requested: Arc<AtomicBool>,
+    pub(crate) exit_requested: Arc<AtomicBool>,
+    pub(crate) deprecations_warned: Arc<Mutex<IndexSet<String>>>,
+    pub(crate) last_pipeline: Arc<Mutex<Option<String>>>,
+    pub(crate) reexecuting: Arc<AtomicBool>,
+    pub(crate) reexecuted_last: Arc<AtomicBool>,
+    pub(crate) change_path_hooks: Arc<Mutex<Vec<ChangePathHook>>>,
+    pub(crate) pending_history: Arc<Mutex<Option<(PathBuf, String)>>>,
+}
+
+impl Session {
+    pub(crate) fn new() -> Session {
+        Session {
+            dry_run: Arc::new(AtomicBool::new(false)),
+            auto_convert: Arc::new(AtomicBool::new(true)),
+            buffer_output: Arc::new(AtomicBool::new(false)),
+            check_input_types: Arc::new(AtomicBool::new(false)),
+            no_exit: Arc::new(AtomicBool::new(false)),
+            catch_errors: Arc::new(AtomicBool::new(false)),
+            trace: Arc::new(AtomicBool::new(false)),
+            error_verbosity: Arc::new(Mutex::new(Verbosity::Normal)),
+            script_dirs: Arc::new(Mutex::new(vec![])),
+            profile: Arc::new(Mutex::new(None)),
+            prompt: Arc::new(Mutex::new(None)),
+            render_margin: Arc::new(AtomicUsize::new(DEFAULT_RENDER_MARGIN)),
+            redirect: Arc::new(Mutex::new(RedirectTarget::Stdout)),
+            restart_requested: Arc::new(AtomicBool::new(false)),
+            exit_requested: Arc::new(AtomicBool::new(false)),
+            deprecations_warned: Arc::new(Mutex::new(IndexSet::new())),
+            last_pipeline: Arc::new(Mutex::new(None)),
+            reexecuting: Arc::new(AtomicBool::new(false)),
+            reexecuted_last: Arc::new(AtomicBool::new(false)),
+            change_path_hooks: Arc::new(Mutex::new(vec![])),
+            pending_history: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+/// The label of the scope frame given to the shell with this id
+fn shell_frame_label(id: usize) -> String {
+    format!("shell {}", id)
+}
+
+/// Trim an error down to its message and primary label, or spell out which labeled scopes it was
+/// raised in. Where its labels point is added once the source is known, by `with_source_lines`.
+fn with_verbosity(error: ShellError, verbosity: Verbosity, scope: &Scope) -> ShellError {
+    if verbosity == Verbosity::Normal {
+        return error;
+    }
+
+    let mut diagnostic = match error.clone().into_diagnostic() {
+        Some(diagnostic) => diagnostic,
+        None => return error,
+    };
+
+    match verbosity {
+        Verbosity::Quiet => {
+            diagnostic
+                .labels
+                .retain(|label| label.style == LabelStyle::Primary);
+            diagnostic.notes.clear();
+        }
+        Verbosity::Verbose => {
+            if let Some(backtrace) = scope.backtrace() {
+                diagnostic
+                    .notes
+                    .push(format!("scope backtrace: {}", backtrace));
+            }
+        }
+        Verbosity::Normal => {}
+    }
+
+    ShellError::diagnostic(diagnostic)
+}
+
+/// Quote the line of `source` each of the error's labels points at, or give its byte range
+/// when it points past the end of `source`
+fn with_source_lines(error: ShellError, source: &str) -> ShellError {
+    let mut diagnostic = match error.clone().into_diagnostic() {
+        Some(diagnostic) => diagnostic,
+        None => return error,
+    };
+
+    let locations: Vec<String> = diagnostic
+        .labels
+        .iter()
+        .map(|label| match source.get(..label.range.start) {
+            Some(before) if label.range.end <= source.len() => {
+                let number = before.matches('\n').count();
+                let line = source.lines().nth(number).unwrap_or_default();
+                format!(
+                    "'{}' points at line {}: {}",
+                    label.message,
+                    number + 1,
+                    line.trim()
+                )
+            }
+            _ => format!(
+                "'{}' points at {}..{}",
+                label.message, label.range.start, label.range.end
+            ),
+        })
+        .collect();
+    diagnostic.notes.extend(locations);
+
+    ShellError::diagnostic(diagnostic)
+}
+
 impl EvaluationContext {
     pub(crate) fn from_raw(raw_args: &CommandArgs) -> EvaluationContext {
-        EvaluationContext {
-            scope: raw_args.scope.clone(),
-            host: raw_args.host.clone(),
-            current_errors: raw_args.current_errors.clone(),
-            ctrl_c: raw_args.ctrl_c.clone(),
-            shell_manager: raw_args.shell_manager.clone(),
-            user_recently_used_autoenv_untrust: Arc::new(AtomicBool::new(false)),
-            windows_drives_previous_cwd: Arc::new(Mutex::new(std::collections::HashMap::new())),
-        }
+        EvaluationContext::in_session(
+            raw_args.scope.clone(),
+            raw_args.host.clone(),
+            raw_args.current_errors.clone(),
+            raw_args.ctrl_c.clone(),
+            raw_args.shell_manager.clone(),
+            raw_args.session.clone(),
+        )
     }
 
     pub(crate) fn from_args(args: &CommandArgs) -> EvaluationContext {
-        EvaluationContext {
-            scope: args.scope.clone(),
-            host: args.host.clone(),
-            current_errors: args.current_errors.clone(),
-            ctrl_c: args.ctrl_c.clone(),
-            shell_manager: args.shell_manager.clone(),
-            user_recently_used_autoenv_untrust: Arc::new(AtomicBool::new(false)),
-            windows_drives_previous_cwd: Arc::new(Mutex::new(std::collections::HashMap::new())),
-        }
+        EvaluationContext::in_session(
+            args.scope.clone(),
+            args.host.clone(),
+            args.current_errors.clone(),
+            args.ctrl_c.clone(),
+            args.shell_manager.clone(),
+            args.session.clone(),
+        )
     }
 
     pub fn basic() -> Result<EvaluationContext, Box<dyn Error>> {
-        Ok(EvaluationContext {
-            scope: Scope::new(),
-            host: Arc::new(parking_lot::Mutex::new(Box::new(
+        Ok(EvaluationContext::in_session(
+            Scope::new(),
+            Arc::new(parking_lot::Mutex::new(Box::new(
                 crate::env::host::BasicHost,
             ))),
-            current_errors: Arc::new(Mutex::new(vec![])),
-            ctrl_c: Arc::new(AtomicBool::new(false)),
+            Arc::new(Mutex::new(vec![])),
+            Arc::new(AtomicBool::new(false)),
+            ShellManager::basic()?,
+            Session::new(),
+        ))
+    }
+
+    /// A context carrying on with the given session
+    pub(crate) fn in_session(
+        scope: Scope,
+        host: Arc<parking_lot::Mutex<Box<dyn Host>>>,
+        current_errors: Arc<Mutex<Vec<ShellError>>>,
+        ctrl_c: Arc<AtomicBool>,
+        shell_manager: ShellManager,
+        session: Session,
+    ) -> EvaluationContext {
+        EvaluationContext {
+            scope,
+            host,
+            current_errors,
+            ctrl_c,
+            shell_manager,
             user_recently_used_autoenv_untrust: Arc::new(AtomicBool::new(false)),
-            shell_manager: ShellManager::basic()?,
+            dry_run: session.dry_run,
+            auto_convert: session.auto_convert,
+            buffer_output: session.buffer_output,
+            check_input_types: session.check_input_types,
+            no_exit: session.no_exit,
+            catch_errors: session.catch_errors,
+            trace: session.trace,
+            error_verbosity: session.error_verbosity,
+            script_dirs: session.script_dirs,
+            profile: session.profile,
+            prompt: session.prompt,
+            render_margin: session.render_margin,
+            redirect: session.redirect,
+            restart_requested: session.restart_requested,
+            exit_requested: session.exit_requested,
+            deprecations_warned: session.deprecations_warned,
+            last_pipeline: session.last_pipeline,
+            reexecuting: session.reexecuting,
+            reexecuted_last: session.reexecuted_last,
+            change_path_hooks: session.change_path_hooks,
+            pending_history: session.pending_history,
             windows_drives_previous_cwd: Arc::new(Mutex::new(std::collections::HashMap::new())),
-        })
+        }
+    }
+
+    /// The session this context is part of, for the contexts derived from it to share
+    pub(crate) fn session(&self) -> Session {
+        Session {
+            dry_run: self.dry_run.clone(),
+            auto_convert: self.auto_convert.clone(),
+            buffer_output: self.buffer_output.clone(),
+            check_input_types: self.check_input_types.clone(),
+            no_exit: self.no_exit.clone(),
+            catch_errors: self.catch_errors.clone(),
+            trace: self.trace.clone(),
+            error_verbosity: self.error_verbosity.clone(),
+            script_dirs: self.script_dirs.clone(),
+            profile: self.profile.clone(),
+            prompt: self.prompt.clone(),
+            render_margin: self.render_margin.clone(),
+            redirect: self.redirect.clone(),
+            restart_requested: self.restart_requested.clone(),
+            exit_requested: self.exit_requested.clone(),
+            deprecations_warned: self.deprecations_warned.clone(),
+            last_pipeline: self.last_pipeline.clone(),
+            reexecuting: self.reexecuting.clone(),
+            reexecuted_last: self.reexecuted_last.clone(),
+            change_path_hooks: self.change_path_hooks.clone(),
+            pending_history: self.pending_history.clone(),
+        }
     }
 
     pub(crate) fn error(&self, error: ShellError) {
+        let error = with_verbosity(error, *self.error_verbosity.lock(), &self.scope);
         self.with_errors(|errors| errors.push(error))
     }
 
+    pub(crate) fn set_error_verbosity(&self, verbosity: Verbosity) {
+        *self.error_verbosity.lock() = verbosity;
+    }
+
     pub(crate) fn clear_errors(&self) {
         self.current_errors.lock().clear()
     }
@@ -74,12 +356,21 @@ impl EvaluationContext {
         self.current_errors.lock().clone()
     }
 
+    /// The error as it is printed for `source`, quoting the lines it points at when verbose
+    pub(crate) fn error_for_source(&self, error: ShellError, source: &Text) -> ShellError {
+        if *self.error_verbosity.lock() == Verbosity::Verbose {
+            with_source_lines(error, source.as_ref())
+        } else {
+            error
+        }
+    }
+
     pub(crate) fn maybe_print_errors(&self, source: Text) -> bool {
         let errors = self.current_errors.clone();
         let mut errors = errors.lock();
 
         if errors.len() > 0 {
-            let error = errors[0].clone();
+            let error = self.error_for_source(errors[0].clone(), &source);
             *errors = vec![];
 
             crate::script::print_err(error, &source);
@@ -109,6 +400,313 @@ impl EvaluationContext {
         block(&mut *errors)
     }
 
+    /// Give the newly entered, current shell a scope frame of its own. The frame is labeled with
+    /// the shell's id, so leaving the shell leaves its frame whichever order shells are left in.
+    pub(crate) fn enter_shell_frame(&self) {
+        self.scope
+            .enter_named_scope(&shell_frame_label(self.shell_manager.current_id()));
+    }
+
+    /// Like `enter_shell_frame`, but the shell only sees the allowlisted env vars
+    pub(crate) fn enter_isolated_shell_frame(&self) {
+        self.scope.enter_isolated_named_scope(
+            &shell_frame_label(self.shell_manager.current_id()),
+            ISOLATED_ENV_ALLOWLIST,
+        );
+    }
+
+    /// Leave the current shell's frame, if it was given one
+    pub(crate) fn exit_shell_frame(&self, keep_env: bool, span: Span) -> Result<(), ShellError> {
+        self.scope
+            .exit_labeled_scope(
+                &shell_frame_label(self.shell_manager.current_id()),
+                keep_env,
+                span,
+            )
+            .map(|_| ())
+    }
+
+    /// Resolve a script filename against the directory of the script being sourced, if any.
+    /// Outside of a script, relative names are left as-is and so resolve against the cwd.
+    pub(crate) fn resolve_script_path(&self, filename: &str) -> PathBuf {
+        let path = PathBuf::from(filename);
+        match self.script_dirs.lock().last() {
+            Some(dir) if path.is_relative() => dir.join(path),
+            _ => path,
+        }
+    }
+
+    /// Find the script to source for `filename`. A relative name that isn't found directly is
+    /// looked for in each of the `NU_LIB_DIRS` directories, as given and then with a `.nu`
+    /// extension. Gives back every path tried when none of them exist.
+    pub(crate) fn locate_script(&self, filename: &str) -> Result<PathBuf, Vec<PathBuf>> {
+        let direct = self.resolve_script_path(filename);
+        if direct.is_file() || !Path::new(filename).is_relative() {
+            return Ok(direct);
+        }
+
+        let mut names = vec![PathBuf::from(filename)];
+        if Path::new(filename).extension().is_none() {
+            names.push(PathBuf::from(format!("{}.nu", filename)));
+        }
+
+        let lib_dirs = self.scope.get_env_var("NU_LIB_DIRS").unwrap_or_default();
+        let mut tried = vec![direct];
+        for dir in lib_dirs
+            .split(ENV_PATH_SEPARATOR)
+            .filter(|dir| !dir.is_empty())
+        {
+            for name in &names {
+                let candidate = Path::new(dir).join(name);
+                if candidate.is_file() {
+                    return Ok(candidate);
+                }
+                tried.push(candidate);
+            }
+        }
+
+        Err(tried)
+    }
+
+    /// Make `path`'s directory the one scripts it sources are resolved against. The directory is
+    /// made absolute, so moving to another directory while the script runs doesn't change it.
+    pub(crate) fn enter_script(&self, path: &Path) {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        self.script_dirs.lock().push(dir);
+    }
+
+    pub(crate) fn exit_script(&self) {
+        self.script_dirs.lock().pop();
+    }
+
+    pub(crate) fn set_prompt(&self, prompt: Value) {
+        *self.prompt.lock() = Some(prompt);
+    }
+
+    pub(crate) fn prompt(&self) -> Option<Value> {
+        self.prompt.lock().clone()
+    }
+
+    /// The width to render output at, falling back to the host's width when none was set
+    pub(crate) fn render_width(&self) -> usize {
+        match self.scope.render_width() {
+            Some(width) => width,
+            None => self.with_host(|host| host.width()),
+        }
+    }
+
+    pub(crate) fn set_redirect(&self, target: RedirectTarget) {
+        *self.redirect.lock() = target;
+    }
+
+    pub(crate) fn redirect(&self) -> RedirectTarget {
+        self.redirect.lock().clone()
+    }
+
+    pub(crate) fn request_restart(&self) {
+        self.restart_requested.store(true, Ordering::SeqCst);
+    }
+
+    /// Set the config value at `path` for the rest of the session, also writing it to the config
+    /// file if `persist` is set. A value already there can only be replaced by one of the same type.
+    pub(crate) fn set_config_value(
+        &self,
+        path: &ColumnPath,
+        value: Value,
+        persist: bool,
+        span: Span,
+    ) -> Result<(), ShellError> {
+        if path.members().is_empty() {
+            return Err(ShellError::labeled_error(
+                "Can't set the config",
+                "expected a path into the config",
+                span,
+            ));
+        }
+
+        let configuration = UntaggedValue::row(nu_data::config::config(Tag::unknown_anchor(span))?)
+            .into_value(span);
+        if let Ok(existing) = configuration.get_data_by_column_path(path, Box::new(|_, _, e| e)) {
+            if !existing.value.is_none() && existing.type_name() != value.type_name() {
+                return Err(ShellError::labeled_error(
+                    format!(
+                        "Can't set config {} to a {}",
+                        path.iter()
+                            .map(|member| member.as_string())
+                            .collect::<Vec<_>>()
+                            .join("."),
+                        value.type_name()
+                    ),
+                    format!("expected a {}", existing.type_name()),
+                    span,
+                ));
+            }
+        }
+        // Check the edit applies before it's kept, as it's laid over every later read
+        configuration.forgiving_insert_data_at_column_path(path, value.clone())?;
+
+        if persist {
+            // Only what's in the file is written back, not other values set for the session
+            let saved =
+                UntaggedValue::row(nu_data::config::read(Tag::unknown_anchor(span), &None)?)
+                    .into_value(span);
+            match saved.forgiving_insert_data_at_column_path(path, value.clone())? {
+                Value {
+                    value: UntaggedValue::Row(changes),
+                    ..
+                } => nu_data::config::write(&changes.entries, &None)?,
+                _ => {
+                    return Err(ShellError::labeled_error(
+                        "Can't set the config",
+                        "the config has to stay a row",
+                        span,
+                    ))
+                }
+            }
+        }
+        nu_data::config::set_for_session(path.clone(), value);
+        Ok(())
+    }
+
+    /// Whether a restart was requested since the last call, clearing the request
+    pub(crate) fn take_restart_request(&self) -> bool {
+        self.restart_requested.swap(false, Ordering::SeqCst)
+    }
+
+    /// Exit the process, or with `no_exit` set, record that an exit was asked for
+    pub(crate) fn exit(&self) {
+        if self.no_exit.load(Ordering::SeqCst) {
+            self.exit_requested.store(true, Ordering::SeqCst);
+        } else {
+            std::process::exit(0); // TODO: save history.txt
+        }
+    }
+
+    /// Whether an exit was asked for since the last call while `no_exit` is set, clearing the request
+    pub fn take_exit_request(&self) -> bool {
+        self.exit_requested.swap(false, Ordering::SeqCst)
+    }
+
+    /// Record that a deprecation warning is being given for `name`, returning false if one already was
+    pub(crate) fn mark_deprecation_warned(&self, name: &str) -> bool {
+        self.deprecations_warned.lock().insert(name.to_string())
+    }
+
+    pub(crate) fn last_pipeline(&self) -> Option<String> {
+        self.last_pipeline.lock().clone()
+    }
+
+    /// Remember a line run at the prompt, unless it was the one re-running the last line
+    pub(crate) fn record_pipeline(&self, line: &str) {
+        if !self.reexecuted_last.swap(false, Ordering::SeqCst) && !line.trim().is_empty() {
+            *self.last_pipeline.lock() = Some(line.to_string());
+        }
+    }
+
+    pub(crate) fn set_render_margin(&self, margin: usize) {
+        self.render_margin.store(margin, Ordering::SeqCst);
+    }
+
+    /// The width to render debug values at, which is kept at one column or more however narrow the output
+    pub(crate) fn debug_render_width(&self) -> usize {
+        self.render_width()
+            .saturating_sub(self.render_margin.load(Ordering::SeqCst))
+            .max(1)
+    }
+
+    /// Run `hook` after every `ChangePath`
+    pub fn on_change_path(&self, hook: ChangePathHook) {
+        self.change_path_hooks.lock().push(hook);
+    }
+
+    /// Change the current shell's path, then run the change path hooks if it moved. A failing
+    /// hook is reported, but the path stays changed.
+    pub(crate) fn change_path(&self, path: String) -> Result<(), ShellError> {
+        let old = self.shell_manager.path();
+        self.shell_manager.try_set_path(path)?;
+        let new = self.shell_manager.path();
+        if new == old {
+            return Ok(());
+        }
+
+        let hooks = self.change_path_hooks.lock().clone();
+        for hook in hooks {
+            if let Err(err) = hook(&old, &new) {
+                self.error(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Track a background process, returning the job id it was given. Jobs that have finished
+    /// are reaped first, so they don't linger as zombies. Ids aren't reused.
+    pub(crate) fn add_job(&self, child: Child) -> usize {
+        let mut jobs = JOBS.lock();
+        jobs.retain(|_, job| matches!(job.try_wait(), Ok(None)));
+
+        let id = NEXT_JOB_ID.fetch_add(1, Ordering::SeqCst);
+        jobs.insert(id, child);
+        id
+    }
+
+    pub(crate) fn set_pending_history(&self, history_path: PathBuf, line: String) {
+        *self.pending_history.lock() = Some((history_path, line));
+    }
+
+    /// Append the line being run to the history file now, rather than once it has finished
+    pub(crate) fn flush_history(&self) -> Result<(), ShellError> {
+        use std::io::Write;
+
+        let (history_path, line) = match self.pending_history.lock().take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+
+        let is_new = !history_path.exists();
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&history_path)?;
+
+        // Match the escaping rustyline uses when it saves the history itself
+        if is_new {
+            writeln!(file, "#V2")?;
+        }
+        writeln!(file, "{}", line.replace('\\', "\\\\").replace('\n', "\\n"))?;
+
+        Ok(())
+    }
+
+    pub fn enable_profiling(&self) {
+        let mut profile = self.profile.lock();
+        if profile.is_none() {
+            *profile = Some(vec![]);
+        }
+    }
+
+    pub fn disable_profiling(&self) {
+        *self.profile.lock() = None;
+    }
+
+    pub(crate) fn is_profiling(&self) -> bool {
+        self.profile.lock().is_some()
+    }
+
+    pub(crate) fn record_timing(&self, name: String, duration: Duration) {
+        if let Some(profile) = self.profile.lock().as_mut() {
+            profile.push((name, duration));
+        }
+    }
+
+    /// Take every timing recorded so far, leaving profiling enabled
+    pub fn drain_profile(&self) -> Profile {
+        match self.profile.lock().as_mut() {
+            Some(profile) => std::mem::take(profile),
+            None => vec![],
+        }
+    }
+
     pub fn add_commands(&self, commands: Vec<Command>) {
         for command in commands {
             self.scope.add_command(command.name().to_string(), command);
@@ -147,6 +745,7 @@ impl EvaluationContext {
             shell_manager: self.shell_manager.clone(),
             call_info: self.call_info(args, name_tag),
             scope: self.scope.clone(),
+            session: self.session(),
             input,
         }
     }
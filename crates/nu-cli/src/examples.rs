@@ -225,7 +225,10 @@ async fn evaluate_block(
     let env = ctx.get_env();
 
     ctx.scope.enter_scope();
-    ctx.scope.add_env(env);
+    if let Err(err) = ctx.scope.add_env(env) {
+        ctx.scope.exit_scope();
+        return Err(err);
+    }
 
     let result = run_block(&block.block, ctx, input_stream).await;
 
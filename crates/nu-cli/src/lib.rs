@@ -39,9 +39,9 @@ pub mod utils;
 mod examples;
 
 #[cfg(feature = "rustyline-support")]
-pub use crate::cli::cli;
+pub use crate::cli::{cli, cli_session, SessionEnd};
 
-pub use crate::cli::{parse_and_eval, register_plugins, run_script_file};
+pub use crate::cli::{parse_and_eval, register_plugins, run_script_file, run_script_path};
 pub use crate::commands::classified::block::run_block;
 pub use crate::commands::command::{
     whole_stream_command, CommandArgs, EvaluatedWholeStreamCommandArgs, Example, WholeStreamCommand,
@@ -51,7 +51,7 @@ pub use crate::commands::default_context::create_default_context;
 pub use crate::commands::help::get_help;
 pub use crate::env::environment_syncer::EnvironmentSyncer;
 pub use crate::env::host::BasicHost;
-pub use crate::evaluation_context::EvaluationContext;
+pub use crate::evaluation_context::{ChangePathHook, EvaluationContext};
 pub use crate::prelude::ToOutputStream;
 pub use nu_data::config;
 pub use nu_data::dict::TaggedListBuilder;
@@ -69,7 +69,12 @@ pub fn build_plugin_command(
 
                         let fname = fname.to_string();
 
-                        Ok(Some(PluginCommandBuilder::new(&name, &fname, params)))
+                        Ok(Some(PluginCommandBuilder::new(
+                            &name,
+                            &fname,
+                            params,
+                            jrpc.protocol_version,
+                        )))
                     }
                     Err(e) => Err(e),
                 },
@@ -73,7 +73,7 @@ use nu_source::HasFallibleSpan;
 
 pub(crate) use crate::commands::command::{CommandArgs, RawCommandArgs, RunnableContext};
 pub(crate) use crate::commands::Example;
-pub(crate) use crate::evaluation_context::EvaluationContext;
+pub(crate) use crate::evaluation_context::{EvaluationContext, Session};
 pub(crate) use nu_data::config;
 pub(crate) use nu_data::value;
 // pub(crate) use crate::env::host::handle_unexpected;
@@ -6,7 +6,7 @@ use crate::prelude::*;
 use futures_codec::FramedRead;
 use nu_errors::ShellError;
 use nu_protocol::hir::{ClassifiedCommand, Expression, InternalCommand, Literal, NamedArguments};
-use nu_protocol::{Primitive, ReturnSuccess, UntaggedValue, Value};
+use nu_protocol::{Primitive, RedirectTarget, ReturnSuccess, UntaggedValue, Value};
 
 use log::{debug, trace};
 use std::error::Error;
@@ -21,6 +21,8 @@ pub enum LineResult {
     CtrlC,
     CtrlD,
     ClearHistory,
+    /// The line finished and asked for a fresh session
+    Restart(String),
 }
 
 fn chomp_newline(s: &str) -> &str {
@@ -55,6 +57,30 @@ pub async fn process_script(
     redirect_stdin: bool,
     span_offset: usize,
     cli_mode: bool,
+) -> LineResult {
+    if cli_mode {
+        // A re-run outside the prompt's lines mustn't keep this line from being remembered
+        ctx.reexecuted_last.store(false, Ordering::SeqCst);
+    }
+
+    let result = process_line(script_text, ctx, redirect_stdin, span_offset, cli_mode).await;
+
+    // Lines typed at the prompt are kept around so they can be re-run
+    if cli_mode {
+        if let LineResult::Success(line) | LineResult::Error(line, _) = &result {
+            ctx.record_pipeline(line);
+        }
+    }
+
+    result
+}
+
+async fn process_line(
+    script_text: &str,
+    ctx: &EvaluationContext,
+    redirect_stdin: bool,
+    span_offset: usize,
+    cli_mode: bool,
 ) -> LineResult {
     if script_text.trim() == "" {
         LineResult::Success(script_text.to_string())
@@ -182,11 +208,21 @@ pub async fn process_script(
         trace!("{:#?}", block);
         let env = ctx.get_env();
 
-        ctx.scope.add_env(env);
+        if let Err(err) = ctx.scope.add_env(env) {
+            return LineResult::Error(line.to_string(), err);
+        }
         let result = run_block(&block, ctx, input_stream).await;
 
         match result {
             Ok(input) => {
+                let target = ctx.redirect();
+                if target != RedirectTarget::Stdout {
+                    return match write_redirected(&target, input, ctx).await {
+                        Ok(()) => success_or_restart(line, ctx),
+                        Err(err) => LineResult::Error(line.to_string(), err),
+                    };
+                }
+
                 // Running a pipeline gives us back a stream that we can then
                 // work through. At the top level, we just want to pull on the
                 // values to compute them.
@@ -199,6 +235,7 @@ pub async fn process_script(
                     ctrl_c: ctx.ctrl_c.clone(),
                     current_errors: ctx.current_errors.clone(),
                     scope: ctx.scope.clone(),
+                    session: ctx.session(),
                     name: Tag::unknown(),
                 };
 
@@ -222,13 +259,64 @@ pub async fn process_script(
                     }
                 }
 
-                LineResult::Success(line.to_string())
+                success_or_restart(line, ctx)
             }
             Err(err) => LineResult::Error(line.to_string(), err),
         }
     }
 }
 
+fn success_or_restart(line: &str, ctx: &EvaluationContext) -> LineResult {
+    if ctx.take_restart_request() {
+        LineResult::Restart(line.to_string())
+    } else {
+        LineResult::Success(line.to_string())
+    }
+}
+
+/// Write each value of a pipeline's output on a line of its own to the target, instead of rendering it
+async fn write_redirected(
+    target: &RedirectTarget,
+    mut input: InputStream,
+    ctx: &EvaluationContext,
+) -> Result<(), ShellError> {
+    let mut out: Box<dyn std::io::Write + Send> = match target {
+        RedirectTarget::File(path) => Box::new(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|err| {
+                    ShellError::untagged_runtime_error(format!(
+                        "Could not open {} for redirected output: {}",
+                        path.display(),
+                        err
+                    ))
+                })?,
+        ),
+        RedirectTarget::Stderr => Box::new(std::io::stderr()),
+        RedirectTarget::Stdout => Box::new(std::io::stdout()),
+    };
+
+    while let Some(value) = input.next().await {
+        if let UntaggedValue::Error(err) = value.value {
+            return Err(err);
+        }
+        if ctx.ctrl_c.load(Ordering::SeqCst) {
+            break;
+        }
+
+        writeln!(out, "{}", value.convert_to_string()).map_err(|err| {
+            ShellError::untagged_runtime_error(format!(
+                "Could not write redirected output: {}",
+                err
+            ))
+        })?;
+    }
+
+    Ok(())
+}
+
 pub async fn run_script_standalone(
     script_text: String,
     redirect_stdin: bool,
@@ -267,6 +355,12 @@ pub async fn run_script_standalone(
             }
         }
 
+        // Leave the request for whoever is running the session, eg. when the script was sourced
+        LineResult::Restart(line) => {
+            context.request_restart();
+            context.maybe_print_errors(Text::from(line));
+        }
+
         _ => {}
     }
 
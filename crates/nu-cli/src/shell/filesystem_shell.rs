@@ -87,6 +87,10 @@ impl Shell for FilesystemShell {
         "filesystem".to_string()
     }
 
+    fn is_filesystem(&self) -> bool {
+        true
+    }
+
     fn homedir(&self) -> Option<PathBuf> {
         homedir_if_possible()
     }
@@ -706,6 +710,18 @@ impl Shell for FilesystemShell {
         self.path = path.to_string_lossy().to_string();
     }
 
+    fn try_set_path(&mut self, path: String) -> Result<(), ShellError> {
+        if let Err(err) = canonicalize(self.path(), PathBuf::from(&path).as_path()) {
+            return Err(ShellError::untagged_runtime_error(format!(
+                "Can't change to {}: {}",
+                path, err
+            )));
+        }
+
+        self.set_path(path);
+        Ok(())
+    }
+
     fn open(
         &self,
         path: &PathBuf,
@@ -29,8 +29,25 @@ pub trait Shell: std::fmt::Debug {
     fn mv(&self, args: MvArgs, name: Tag, path: &str) -> Result<OutputStream, ShellError>;
     fn rm(&self, args: RemoveArgs, name: Tag, path: &str) -> Result<OutputStream, ShellError>;
     fn path(&self) -> String;
+    /// Where the shell currently is, as shown to the user in the prompt
+    fn location(&self) -> String {
+        self.path()
+    }
+    /// For a shell pushed to inspect a value, the id of the shell it was pushed from
+    fn inspected_from(&self) -> Option<usize> {
+        None
+    }
+    /// Whether the shell's path is a directory on disk, rather than eg. a path into a value
+    fn is_filesystem(&self) -> bool {
+        false
+    }
     fn pwd(&self, args: EvaluatedWholeStreamCommandArgs) -> Result<OutputStream, ShellError>;
     fn set_path(&mut self, path: String);
+    /// Like `set_path`, but a path the shell can't go to is an error and leaves the path as it was
+    fn try_set_path(&mut self, path: String) -> Result<(), ShellError> {
+        self.set_path(path);
+        Ok(())
+    }
     fn open(
         &self,
         path: &PathBuf,
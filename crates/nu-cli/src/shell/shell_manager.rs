@@ -23,6 +23,9 @@ use std::sync::Arc;
 pub struct ShellManager {
     pub(crate) current_shell: Arc<AtomicUsize>,
     pub(crate) shells: Arc<Mutex<Vec<Box<dyn Shell + Send>>>>,
+    /// A stable id for each shell, in the same order as `shells`. Ids aren't reused.
+    ids: Arc<Mutex<Vec<usize>>>,
+    next_id: Arc<AtomicUsize>,
 }
 
 impl ShellManager {
@@ -30,11 +33,16 @@ impl ShellManager {
         Ok(ShellManager {
             current_shell: Arc::new(AtomicUsize::new(0)),
             shells: Arc::new(Mutex::new(vec![Box::new(FilesystemShell::basic()?)])),
+            ids: Arc::new(Mutex::new(vec![0])),
+            next_id: Arc::new(AtomicUsize::new(1)),
         })
     }
 
     pub fn insert_at_current(&self, shell: Box<dyn Shell + Send>) {
         self.shells.lock().push(shell);
+        self.ids
+            .lock()
+            .push(self.next_id.fetch_add(1, Ordering::SeqCst));
         self.current_shell
             .store(self.shells.lock().len() - 1, Ordering::SeqCst);
         self.set_path(self.path());
@@ -44,10 +52,21 @@ impl ShellManager {
         self.current_shell.load(Ordering::SeqCst)
     }
 
+    /// The id of the current shell, which stays the same however the shells around it move
+    pub fn current_id(&self) -> usize {
+        self.ids.lock()[self.current_shell()]
+    }
+
+    /// Where the shell with this id currently is among the shells, if it hasn't been left
+    pub fn index_of(&self, id: usize) -> Option<usize> {
+        self.ids.lock().iter().position(|shell_id| *shell_id == id)
+    }
+
     pub fn remove_at_current(&self) {
         {
             let mut shells = self.shells.lock();
             if shells.len() > 0 {
+                self.ids.lock().remove(self.current_shell());
                 if self.current_shell() == shells.len() - 1 {
                     shells.pop();
                     let new_len = shells.len();
@@ -64,14 +83,42 @@ impl ShellManager {
         self.set_path(self.path())
     }
 
+    /// If the current shell was pushed to inspect a value, the id of the shell it was pushed from
+    pub fn inspected_from(&self) -> Option<usize> {
+        self.shells
+            .lock()
+            .get(self.current_shell())
+            .and_then(|shell| shell.inspected_from())
+    }
+
     pub fn is_empty(&self) -> bool {
         self.shells.lock().is_empty()
     }
 
+    pub fn len(&self) -> usize {
+        self.shells.lock().len()
+    }
+
     pub fn path(&self) -> String {
         self.shells.lock()[self.current_shell()].path()
     }
 
+    /// The path of the current shell if it's a filesystem shell, or else of the nearest filesystem
+    /// shell before it. None if there is no filesystem shell to fall back on.
+    pub fn filesystem_path(&self) -> Option<String> {
+        let shells = self.shells.lock();
+        let current = self.current_shell().min(shells.len().checked_sub(1)?);
+        shells[..=current]
+            .iter()
+            .rev()
+            .find(|shell| shell.is_filesystem())
+            .map(|shell| shell.path())
+    }
+
+    pub fn location(&self) -> String {
+        self.shells.lock()[self.current_shell()].location()
+    }
+
     pub fn pwd(&self, args: EvaluatedWholeStreamCommandArgs) -> Result<OutputStream, ShellError> {
         let env = self.shells.lock();
 
@@ -82,6 +129,10 @@ impl ShellManager {
         self.shells.lock()[self.current_shell()].set_path(path)
     }
 
+    pub fn try_set_path(&self, path: String) -> Result<(), ShellError> {
+        self.shells.lock()[self.current_shell()].try_set_path(path)
+    }
+
     pub fn open(
         &self,
         full_path: &PathBuf,
@@ -127,6 +178,24 @@ impl ShellManager {
         self.set_path(self.path())
     }
 
+    pub fn set_current(&self, index: usize, span: Span) -> Result<(), ShellError> {
+        let shell_len = self.shells.lock().len();
+        if index >= shell_len {
+            return Err(ShellError::labeled_error(
+                format!("There is no shell {}", index),
+                format!(
+                    "expected a shell between 0 and {}",
+                    shell_len.saturating_sub(1)
+                ),
+                span,
+            ));
+        }
+
+        self.current_shell.store(index, Ordering::SeqCst);
+        self.set_path(self.path());
+        Ok(())
+    }
+
     pub fn homedir(&self) -> Option<PathBuf> {
         let env = self.shells.lock();
 
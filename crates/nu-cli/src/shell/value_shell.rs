@@ -11,18 +11,20 @@ use crate::utils::ValueStructure;
 
 use crate::commands::classified::maybe_text_codec::StringOrBinary;
 use encoding_rs::Encoding;
+use std::borrow::Cow;
 use std::ffi::OsStr;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
 use nu_errors::ShellError;
-use nu_protocol::{ReturnSuccess, ShellTypeName, UntaggedValue, Value};
-use nu_source::Tagged;
+use nu_protocol::{ColumnPath, PathMember, ReturnSuccess, ShellTypeName, UntaggedValue, Value};
+use nu_source::{Span, Tagged};
 
 #[derive(Clone)]
 pub struct ValueShell {
     pub(crate) path: String,
     pub(crate) last_path: String,
     pub(crate) value: Value,
+    pub(crate) inspected_from: Option<usize>,
 }
 
 impl std::fmt::Debug for ValueShell {
@@ -37,28 +39,52 @@ impl ValueShell {
             path: "/".to_string(),
             last_path: "/".to_string(),
             value,
+            inspected_from: None,
         }
     }
 
-    fn members_under(&self, path: &Path) -> VecDeque<Value> {
-        let mut shell_entries = VecDeque::new();
-        let full_path = path.to_path_buf();
-        let mut viewed = self.value.clone();
+    /// A value shell for a quick look at a value, which is left back to the shell with the id `from`
+    pub fn new_inspect(value: Value, from: usize) -> ValueShell {
+        ValueShell {
+            inspected_from: Some(from),
+            ..ValueShell::new(value)
+        }
+    }
+
+    /// The part of the value at `path`. Rows are stepped into by reference, so navigating a large
+    /// value doesn't copy it; only a step that gathers a column out of a table builds a new value.
+    /// Steps that don't match anything are skipped.
+    fn value_at(&self, path: &Path) -> Cow<'_, Value> {
+        let mut viewed = Cow::Borrowed(&self.value);
         let sep_string = std::path::MAIN_SEPARATOR.to_string();
         let sep = OsStr::new(&sep_string);
-        for p in full_path.iter() {
+        for p in path.iter() {
             match p {
                 x if x == sep => {}
                 step => {
                     let name: &str = &step.to_string_lossy().to_string();
-                    let value = viewed.get_data_by_key(name.spanned_unknown());
-                    if let Some(v) = value {
-                        viewed = v.clone();
-                    }
+                    viewed = match viewed {
+                        Cow::Borrowed(Value {
+                            value: UntaggedValue::Row(row),
+                            ..
+                        }) => match row.entries.get(name) {
+                            Some(v) => Cow::Borrowed(v),
+                            None => viewed,
+                        },
+                        _ => match viewed.get_data_by_key(name.spanned_unknown()) {
+                            Some(v) => Cow::Owned(v),
+                            None => viewed,
+                        },
+                    };
                 }
             }
         }
-        match viewed {
+        viewed
+    }
+
+    fn members_under(&self, path: &Path) -> VecDeque<Value> {
+        let mut shell_entries = VecDeque::new();
+        match self.value_at(path).as_ref() {
             Value {
                 value: UntaggedValue::Table(l),
                 ..
@@ -68,13 +94,36 @@ impl ValueShell {
                 }
             }
             x => {
-                shell_entries.push_back(x);
+                shell_entries.push_back(x.clone());
             }
         }
 
         shell_entries
     }
 
+    /// The current location inside the value, as the column path leading to it
+    pub fn column_path(&self) -> ColumnPath {
+        let mut members = vec![];
+
+        for component in Path::new(&self.path).components() {
+            match component {
+                Component::Normal(name) => {
+                    let name = name.to_string_lossy();
+                    members.push(match name.parse::<u64>() {
+                        Ok(row) => PathMember::int(row, Span::unknown()),
+                        Err(_) => PathMember::string(name, Span::unknown()),
+                    });
+                }
+                Component::ParentDir => {
+                    members.pop();
+                }
+                _ => {}
+            }
+        }
+
+        ColumnPath::new(members)
+    }
+
     // TODO make use of this in the new completion engine
     #[allow(dead_code)]
     fn members(&self) -> VecDeque<Value> {
@@ -96,6 +145,10 @@ impl Shell for ValueShell {
         Some(PathBuf::from("/"))
     }
 
+    fn inspected_from(&self) -> Option<usize> {
+        self.inspected_from
+    }
+
     fn ls(
         &self,
         LsArgs { path, .. }: LsArgs,
@@ -221,6 +274,20 @@ impl Shell for ValueShell {
         self.path.clone()
     }
 
+    /// The column path to the current location, eg. `dependencies.0.name`, or `/` at the top
+    fn location(&self) -> String {
+        let column_path = self.column_path();
+        if column_path.members().is_empty() {
+            return "/".to_string();
+        }
+
+        column_path
+            .iter()
+            .map(|member| member.as_string())
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+
     fn pwd(&self, args: EvaluatedWholeStreamCommandArgs) -> Result<OutputStream, ShellError> {
         let mut stream = VecDeque::new();
         stream.push_back(ReturnSuccess::value(
@@ -256,3 +323,66 @@ impl Shell for ValueShell {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ValueShell;
+    use crate::shell::shell_manager::ShellManager;
+    use nu_protocol::{TaggedDictBuilder, UntaggedValue};
+    use nu_source::Tag;
+    use std::borrow::Cow;
+    use std::path::Path;
+
+    #[test]
+    fn reports_location_of_nested_field() {
+        let package = TaggedDictBuilder::build(Tag::unknown(), |row| {
+            row.insert_value(
+                "dependencies",
+                UntaggedValue::table(&[UntaggedValue::string("serde").into_untagged_value()])
+                    .into_untagged_value(),
+            );
+        });
+
+        let shell_manager = ShellManager::basic().expect("could not create a shell manager");
+        shell_manager.insert_at_current(Box::new(ValueShell::new(package)));
+        shell_manager.set_path("/dependencies/0".to_string());
+
+        assert_eq!(shell_manager.path(), "/dependencies/0");
+        assert_eq!(shell_manager.location(), "dependencies.0");
+
+        shell_manager.set_path("/".to_string());
+        assert_eq!(shell_manager.location(), "/");
+    }
+
+    #[test]
+    fn navigating_rows_borrows_rather_than_copies() {
+        let rows: Vec<_> = (0..10_000)
+            .map(|n| UntaggedValue::int(n).into_untagged_value())
+            .collect();
+        let package = TaggedDictBuilder::build(Tag::unknown(), |row| {
+            row.insert_value(
+                "metadata",
+                TaggedDictBuilder::build(Tag::unknown(), |metadata| {
+                    metadata
+                        .insert_value("rows", UntaggedValue::table(&rows).into_untagged_value());
+                }),
+            );
+        });
+        let shell = ValueShell::new(package);
+
+        let viewed = shell.value_at(Path::new("/metadata/rows"));
+        let stored = match &shell.value.value {
+            UntaggedValue::Row(package) => match &package.entries["metadata"].value {
+                UntaggedValue::Row(metadata) => &metadata.entries["rows"],
+                _ => panic!("metadata should be a row"),
+            },
+            _ => panic!("package should be a row"),
+        };
+
+        assert!(matches!(&viewed, Cow::Borrowed(value) if std::ptr::eq(*value, stored)));
+        assert_eq!(
+            shell.members_under(Path::new("/metadata/rows")).len(),
+            10_000
+        );
+    }
+}
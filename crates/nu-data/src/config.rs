@@ -10,10 +10,12 @@ use indexmap::IndexMap;
 use log::trace;
 use nu_errors::{CoerceInto, ShellError};
 use nu_protocol::{
-    Dictionary, Primitive, ShellTypeName, TaggedDictBuilder, UnspannedPathMember, UntaggedValue,
-    Value,
+    ColumnPath, Dictionary, Primitive, ShellTypeName, TaggedDictBuilder, UnspannedPathMember,
+    UntaggedValue, Value,
 };
 use nu_source::{SpannedItem, Tag, TaggedItem};
+use nu_value_ext::ValueExt;
+use parking_lot::{const_mutex, Mutex};
 use std::fs::{self, OpenOptions};
 use std::io;
 use std::path::{Path, PathBuf};
@@ -280,8 +282,43 @@ pub fn read(
     }
 }
 
+/// Values set for the rest of the session only, laid over the config file each time it's read
+static SESSION_EDITS: Mutex<Vec<(ColumnPath, Value)>> = const_mutex(Vec::new());
+
+/// Set the value at `path` for the rest of the session, without writing it to the config file
+pub fn set_for_session(path: ColumnPath, value: Value) {
+    SESSION_EDITS.lock().push((path, value));
+}
+
+/// Apply the session's edits, in the order they were made, to a config read from the file
+pub fn with_session_edits(
+    config: IndexMap<String, Value>,
+    tag: impl Into<Tag>,
+) -> Result<IndexMap<String, Value>, ShellError> {
+    let edits = SESSION_EDITS.lock();
+    if edits.is_empty() {
+        return Ok(config);
+    }
+
+    let tag = tag.into();
+    let mut config = UntaggedValue::row(config).into_value(&tag);
+    for (path, value) in edits.iter() {
+        config = config.forgiving_insert_data_at_column_path(path, value.clone())?;
+    }
+
+    match config.value {
+        UntaggedValue::Row(Dictionary { entries }) => Ok(entries),
+        other => Err(ShellError::type_error(
+            "Dictionary",
+            other.type_name().spanned(tag.span),
+        )),
+    }
+}
+
+/// The config file's contents, with any values set for the session only
 pub fn config(tag: impl Into<Tag>) -> Result<IndexMap<String, Value>, ShellError> {
-    read(tag, &None)
+    let tag = tag.into();
+    with_session_edits(read(&tag, &None)?, tag)
 }
 
 pub fn write(config: &IndexMap<String, Value>, at: &Option<PathBuf>) -> Result<(), ShellError> {
@@ -1,4 +1,4 @@
-use crate::config::{last_modified, read, Conf, Status};
+use crate::config::{config, last_modified, read, Conf, Status};
 use indexmap::IndexMap;
 use nu_protocol::Value;
 use nu_source::Tag;
@@ -30,7 +30,7 @@ impl Conf for NuConfig {
     fn reload(&mut self) {
         let vars = &mut self.vars;
 
-        if let Ok(variables) = read(Tag::unknown(), &None) {
+        if let Ok(variables) = config(Tag::unknown()) {
             vars.extend(variables);
 
             self.modified_at = if let Ok(status) = last_modified(&None) {
@@ -66,11 +66,7 @@ impl NuConfig {
     }
 
     pub fn new() -> NuConfig {
-        let vars = if let Ok(variables) = read(Tag::unknown(), &None) {
-            variables
-        } else {
-            IndexMap::default()
-        };
+        let vars = config(Tag::unknown()).unwrap_or_default();
 
         NuConfig {
             vars,
@@ -630,6 +630,23 @@ impl ShellError {
         )
     }
 
+    /// Add a note to the end of a diagnostic error, unless it already carries that note
+    pub fn with_note(self, note: impl Into<String>) -> ShellError {
+        match self.error {
+            ProximateShellError::Diagnostic(ShellDiagnostic { mut diagnostic }) => {
+                let note = note.into();
+                if !diagnostic.notes.contains(&note) {
+                    diagnostic.notes.push(note);
+                }
+                ShellError {
+                    error: ProximateShellError::Diagnostic(ShellDiagnostic { diagnostic }),
+                    cause: self.cause,
+                }
+            }
+            _ => self,
+        }
+    }
+
     pub fn unimplemented(title: impl Into<String>) -> ShellError {
         ShellError::untagged_runtime_error(&format!("Unimplemented: {}", title.into()))
     }
@@ -1,11 +1,21 @@
 use nu_protocol::{outln, CallInfo, Value};
 use serde::{Deserialize, Serialize};
 
+/// The version of the plugin protocol spoken by this side of the connection
+pub const PLUGIN_PROTOCOL_VERSION: u32 = 1;
+
+fn original_protocol_version() -> u32 {
+    1
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JsonRpc<T> {
     jsonrpc: String,
     pub method: String,
     pub params: T,
+    /// Plugins from before the protocol was versioned leave this out, and speak the original version
+    #[serde(default = "original_protocol_version")]
+    pub protocol_version: u32,
 }
 
 impl<T> JsonRpc<T> {
@@ -14,6 +24,7 @@ impl<T> JsonRpc<T> {
             jsonrpc: "2.0".into(),
             method: method.into(),
             params,
+            protocol_version: PLUGIN_PROTOCOL_VERSION,
         }
     }
 }
@@ -13,12 +13,15 @@ pub mod value;
 
 pub use crate::call_info::{CallInfo, EvaluatedArgs};
 pub use crate::maybe_owned::MaybeOwned;
-pub use crate::return_value::{CommandAction, ReturnSuccess, ReturnValue};
+pub use crate::return_value::{
+    CommandAction, RedirectTarget, ReturnSuccess, ReturnValue, Verbosity,
+};
 pub use crate::signature::{NamedType, PositionalType, Signature};
 pub use crate::syntax_shape::SyntaxShape;
 pub use crate::type_name::{PrettyType, ShellTypeName, SpannedTypeName};
 pub use crate::type_shape::{Row as RowType, Type};
 pub use crate::value::column_path::{ColumnPath, PathMember, UnspannedPathMember};
+pub use crate::value::convert::{FromValue, ShellErrorValue};
 pub use crate::value::dict::{Dictionary, TaggedDictBuilder};
 pub use crate::value::did_you_mean::did_you_mean;
 pub use crate::value::primitive::Primitive;
@@ -1,7 +1,11 @@
+use crate::hir::Block;
+use crate::value::column_path::ColumnPath;
 use crate::value::Value;
 use nu_errors::ShellError;
-use nu_source::{b, DebugDocBuilder, PrettyDebug, Tagged};
+use nu_source::{b, DebugDocBuilder, PrettyDebug, Spanned, Tagged};
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
 
 /// The inner set of actions for the command processor. Each denotes a way to change state in the processor without changing it directly from the command itself.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -13,27 +17,151 @@ pub enum CommandAction {
     /// Display an error
     Error(ShellError),
     /// Enter a new shell at the given path
-    EnterShell(String),
+    EnterShell {
+        location: String,
+        /// Start the shell with only an allowlist of env vars, eg. PATH and HOME
+        isolate_env: bool,
+    },
     /// Convert the value given from one type to another
     AutoConvert(Value, String),
+    /// Convert the value with the named converter command, instead of picking one by extension
+    AutoConvertWith { value: Value, converter: String },
+    /// Convert the value using the first of the given extensions whose converter succeeds
+    AutoConvertCandidates(Value, Vec<String>),
+    /// Turn automatic conversion of opened files on or off
+    ToggleAutoConvert(bool),
+    /// Turn tracing of the values going into each command on or off, as `RUST_LOG` would
+    SetTrace(bool),
     /// Enter a value shell, one that allows exploring inside of a Value
     EnterValueShell(Value),
+    /// Enter a value shell to inspect a value; leaving it goes straight back to the shell it was entered from
+    PushValueOntoShellStack(Value),
+    /// Enter a new shell rooted at the given path, already positioned at a path beneath it
+    EnterShellAt { root: String, subpath: String },
+    /// Enter a new shell at the directory in the `path` or `name` column of a row, eg. from `ls`
+    EnterShellFromValue(Value),
     /// Enter the help shell, which allows exploring the help system
     EnterHelpShell(Value),
     /// Add a variable into scope
     AddVariable(String, Value),
+    /// Add several variables into scope at once
+    AddVariables(Vec<(String, Value)>),
     /// Add an environment variable into scope
     AddEnvVariable(String, String),
+    /// Bind a variable to the value parsed from an environment variable, eg. `42` as an int
+    SetVarFromEnv { var: String, env: String },
+    /// Set an environment variable to the text of a variable's value
+    SetEnvFromVar { env: String, var: String },
+    /// Set the environment variables of a `.env`-style file of `KEY=VALUE` lines
+    SetEnvFromFile(Tagged<String>),
+    /// Record the exit code of an external command in the global `LAST_EXIT_CODE` environment variable
+    SetLastExitCode(i32),
+    /// Add an environment variable that is re-rendered from a template, like `prefix ($Y)`, whenever it is read
+    AddEnvVariableTemplate(String, String),
+    /// Append to a list-like environment variable (eg. PATH), using the OS path separator unless one is given
+    AppendEnvVariable {
+        name: String,
+        value: String,
+        separator: Option<String>,
+    },
+    /// Set a config value for the rest of the session, also writing it to the config file if `persist` is set
+    SetConfigValue {
+        path: ColumnPath,
+        value: Value,
+        persist: bool,
+    },
+    /// Run a block to completion and give back how long it took, as a duration
+    Benchmark(Block),
     /// Add plugins from path given
     AddPlugins(String),
-    /// Run the given script in the current context (given filename)
-    SourceScript(Tagged<String>),
+    /// Run the given script in the current context, giving back counts of what it defined if `summary` is set
+    SourceScript {
+        filename: Tagged<String>,
+        summary: bool,
+    },
+    /// Run a nested read-eval loop against the current scope, returning to the pipeline when it's exited
+    EnterReplSubshell,
     /// Go to the previous shell in the shell ring buffer
     PreviousShell,
     /// Go to the next shell in the shell ring buffer
     NextShell,
-    /// Leave the current shell. If it's the last shell, exit out of Nu
-    LeaveShell,
+    /// Go directly to the shell at the given position in the shell ring buffer
+    GoToShell(usize),
+    /// Leave the current shell, keeping the env vars set inside it if asked to. If it's the last shell, exit out of Nu
+    LeaveShell { keep_env: bool },
+    /// Write the given text to stdout as-is, bypassing the table formatter
+    PrintRaw(String),
+    /// Define an alias in the current scope
+    DefineAlias {
+        name: String,
+        replacement: Vec<Spanned<String>>,
+    },
+    /// Remove the innermost alias with the given name
+    RemoveAlias(String),
+    /// Replace the REPL prompt with the given string or block
+    SetPrompt(Value),
+    /// Start an external command without waiting for it to finish, yielding its job id
+    RunExternalInBackground { command: String, args: Vec<String> },
+    /// Write the REPL history to disk right away, including the line being run
+    SaveHistoryNow,
+    /// Describe every frame of the scope as a table, for debugging
+    DumpScope,
+    /// Wait for the given time, stopping early if ctrl-c is pressed
+    Sleep(Duration),
+    /// Render output at the given width rather than the terminal's, or at the terminal's again if none is given
+    SetRenderWidth(Option<usize>),
+    /// Leave this many columns free at the right edge when rendering debug values
+    SetRenderMargin(usize),
+    /// Send the output of the commands that follow somewhere other than stdout
+    Redirect { target: RedirectTarget },
+    /// Start a new scope frame, so vars and env vars set after it are discarded by `PopEnvFrame`
+    PushEnvFrame,
+    /// Leave the frame started by `PushEnvFrame`
+    PopEnvFrame,
+    /// Start a fresh session, reloading config and plugins, once the current line has finished
+    Restart,
+    /// Warn that a command is deprecated, once per session for each name
+    WarnDeprecated {
+        name: String,
+        replacement: Option<String>,
+        since: Option<String>,
+    },
+    /// Run a module file in a scope of its own, then bring what it exports into the current scope.
+    /// With a prefix, its commands and vars are bound as `prefix::name`.
+    ImportModule {
+        path: String,
+        prefix: Option<String>,
+    },
+    /// Run the last line entered at the prompt again
+    ReexecLast,
+    /// List the open shells, one row each with its index, name and path, and whether it is active
+    ListShells,
+    /// Print a line to stderr as is, eg. progress that shouldn't end up in the pipeline's output
+    EchoToStderr(String),
+    /// Change how much detail reported errors carry
+    SetErrorVerbosity(Verbosity),
+}
+
+/// How much detail reported errors carry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Verbosity {
+    /// Only the message and the primary label
+    Quiet,
+    /// Errors as they were raised
+    Normal,
+    /// Errors with notes quoting the source line each label points at, and the scopes they were raised in
+    Verbose,
+}
+
+/// Where the output of a pipeline is printed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RedirectTarget {
+    /// Render the output to stdout as usual
+    Stdout,
+    /// Write each value to stderr on a line of its own
+    Stderr,
+    /// Append each value to the file on a line of its own
+    File(PathBuf),
 }
 
 impl PrettyDebug for CommandAction {
@@ -46,16 +174,110 @@ impl PrettyDebug for CommandAction {
             CommandAction::AutoConvert(_, extension) => {
                 b::typed("auto convert", b::description(extension))
             }
-            CommandAction::EnterShell(s) => b::typed("enter shell", b::description(s)),
+            CommandAction::AutoConvertWith { converter, .. } => {
+                b::typed("auto convert", b::description(converter))
+            }
+            CommandAction::AutoConvertCandidates(_, extensions) => {
+                b::typed("auto convert", b::description(extensions.join(", ")))
+            }
+            CommandAction::ToggleAutoConvert(true) => b::description("enable auto convert"),
+            CommandAction::ToggleAutoConvert(false) => b::description("disable auto convert"),
+            CommandAction::SetTrace(true) => b::description("enable trace"),
+            CommandAction::SetTrace(false) => b::description("disable trace"),
+            CommandAction::EnterShell { location, .. } => {
+                b::typed("enter shell", b::description(location))
+            }
+            CommandAction::EnterShellAt { root, subpath } => b::typed(
+                "enter shell at",
+                b::description(root) + b::space() + b::description(subpath),
+            ),
             CommandAction::EnterValueShell(v) => b::typed("enter value shell", v.pretty()),
+            CommandAction::PushValueOntoShellStack(value) => {
+                b::typed("enter inspect shell", value.pretty())
+            }
+            CommandAction::EnterShellFromValue(v) => b::typed("enter shell from value", v.pretty()),
             CommandAction::EnterHelpShell(v) => b::typed("enter help shell", v.pretty()),
             CommandAction::AddVariable(..) => b::description("add variable"),
+            CommandAction::AddVariables(..) => b::description("add variables"),
             CommandAction::AddEnvVariable(..) => b::description("add environment variable"),
-            CommandAction::SourceScript(..) => b::description("source script"),
+            CommandAction::SetVarFromEnv { var, .. } => {
+                b::typed("set variable from environment", b::description(var))
+            }
+            CommandAction::SetEnvFromVar { env, .. } => {
+                b::typed("set environment from variable", b::description(env))
+            }
+            CommandAction::SetEnvFromFile(file) => {
+                b::typed("set environment from file", b::description(&file.item))
+            }
+            CommandAction::SetLastExitCode(code) => {
+                b::typed("set last exit code", b::description(code))
+            }
+            CommandAction::AddEnvVariableTemplate(..) => {
+                b::description("add environment variable template")
+            }
+            CommandAction::AppendEnvVariable { .. } => {
+                b::description("append environment variable")
+            }
+            CommandAction::SourceScript { .. } => b::description("source script"),
+            CommandAction::SetConfigValue { path, .. } => {
+                b::typed("set config value", path.pretty())
+            }
+            CommandAction::Benchmark(..) => b::description("benchmark"),
             CommandAction::AddPlugins(..) => b::description("add plugins"),
+            CommandAction::EnterReplSubshell => b::description("enter repl subshell"),
             CommandAction::PreviousShell => b::description("previous shell"),
             CommandAction::NextShell => b::description("next shell"),
-            CommandAction::LeaveShell => b::description("leave shell"),
+            CommandAction::GoToShell(index) => {
+                b::typed("go to shell", b::description(index.to_string()))
+            }
+            CommandAction::LeaveShell { keep_env: false } => b::description("leave shell"),
+            CommandAction::LeaveShell { keep_env: true } => {
+                b::description("leave shell, keeping its env")
+            }
+            CommandAction::PrintRaw(..) => b::description("print raw"),
+            CommandAction::DefineAlias { name, .. } => {
+                b::typed("define alias", b::description(name))
+            }
+            CommandAction::RemoveAlias(name) => b::typed("remove alias", b::description(name)),
+            CommandAction::SetPrompt(v) => b::typed("set prompt", v.pretty()),
+            CommandAction::RunExternalInBackground { command, .. } => {
+                b::typed("run in background", b::description(command))
+            }
+            CommandAction::SaveHistoryNow => b::description("save history now"),
+            CommandAction::DumpScope => b::description("dump scope"),
+            CommandAction::SetRenderWidth(Some(width)) => {
+                b::typed("set render width", b::description(width.to_string()))
+            }
+            CommandAction::SetRenderWidth(None) => b::description("reset render width"),
+            CommandAction::SetRenderMargin(margin) => {
+                b::typed("set render margin", b::description(margin.to_string()))
+            }
+            CommandAction::PushEnvFrame => b::description("push env frame"),
+            CommandAction::PopEnvFrame => b::description("pop env frame"),
+            CommandAction::Restart => b::description("restart"),
+            CommandAction::WarnDeprecated { name, .. } => {
+                b::typed("warn deprecated", b::description(name))
+            }
+            CommandAction::ImportModule { path, .. } => {
+                b::typed("import module", b::description(path))
+            }
+            CommandAction::ReexecLast => b::description("reexec last"),
+            CommandAction::ListShells => b::description("list shells"),
+            CommandAction::SetErrorVerbosity(verbosity) => b::typed(
+                "set error verbosity",
+                b::description(format!("{:?}", verbosity).to_lowercase()),
+            ),
+            CommandAction::EchoToStderr(text) => b::typed("echo to stderr", b::description(text)),
+            CommandAction::Redirect { target } => match target {
+                RedirectTarget::Stdout => b::typed("redirect", b::description("stdout")),
+                RedirectTarget::Stderr => b::typed("redirect", b::description("stderr")),
+                RedirectTarget::File(path) => {
+                    b::typed("redirect", b::description(path.display().to_string()))
+                }
+            },
+            CommandAction::Sleep(duration) => {
+                b::typed("sleep", b::description(format!("{:?}", duration)))
+            }
         }
     }
 }
@@ -1,5 +1,5 @@
 pub mod column_path;
-mod convert;
+pub mod convert;
 mod debug;
 pub mod dict;
 pub mod did_you_mean;
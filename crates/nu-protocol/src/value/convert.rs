@@ -1,9 +1,9 @@
 use crate::type_name::SpannedTypeName;
-use crate::value::dict::Dictionary;
+use crate::value::dict::{Dictionary, TaggedDictBuilder};
 use crate::value::primitive::Primitive;
 use crate::value::{UntaggedValue, Value};
 use nu_errors::{CoerceInto, ShellError};
-use nu_source::TaggedItem;
+use nu_source::{HasFallibleSpan, Span, Tag, TaggedItem};
 
 impl std::convert::TryFrom<&Value> for i64 {
     type Error = ShellError;
@@ -57,3 +57,96 @@ impl<'a> std::convert::TryFrom<&'a Value> for &'a Dictionary {
         }
     }
 }
+
+/// A trait for turning a ShellError into a structured Value that scripts can inspect
+pub trait ShellErrorValue {
+    fn into_value(self, tag: Tag) -> Value;
+}
+
+impl ShellErrorValue for ShellError {
+    /// Convert the error into a record with `msg`, `span` and `labels` fields
+    fn into_value(self, tag: Tag) -> Value {
+        let span_value = |span: Option<Span>| match span {
+            Some(span) => TaggedDictBuilder::build(&tag, |row| {
+                row.insert_untagged("start", UntaggedValue::int(span.start()));
+                row.insert_untagged("end", UntaggedValue::int(span.end()));
+            }),
+            None => UntaggedValue::nothing().into_value(&tag),
+        };
+
+        let error_span = self.maybe_span();
+        let fallback = self.to_string();
+
+        let (msg, labels) = match self.into_diagnostic() {
+            Some(diagnostic) => (
+                diagnostic.message,
+                diagnostic
+                    .labels
+                    .into_iter()
+                    .map(|label| (label.message, Span::new(label.range.start, label.range.end)))
+                    .collect::<Vec<_>>(),
+            ),
+            None => (fallback, vec![]),
+        };
+
+        // Diagnostic-style errors only carry their location in the labels
+        let error_span = error_span.or_else(|| labels.first().map(|(_, span)| *span));
+
+        let labels = labels
+            .into_iter()
+            .map(|(msg, span)| {
+                TaggedDictBuilder::build(&tag, |row| {
+                    row.insert_untagged("msg", UntaggedValue::string(msg));
+                    row.insert_value("span", span_value(Some(span)));
+                })
+            })
+            .collect::<Vec<_>>();
+
+        TaggedDictBuilder::build(&tag, |row| {
+            row.insert_untagged("msg", UntaggedValue::string(msg));
+            row.insert_value("span", span_value(error_span));
+            row.insert_untagged("labels", UntaggedValue::table(&labels));
+        })
+    }
+}
+
+/// A trait for reading a Rust value out of a Value, failing with a type error on a mismatch
+pub trait FromValue: Sized {
+    fn from_value(value: &Value) -> Result<Self, ShellError>;
+}
+
+impl FromValue for Value {
+    fn from_value(value: &Value) -> Result<Self, ShellError> {
+        Ok(value.clone())
+    }
+}
+
+impl FromValue for i64 {
+    fn from_value(value: &Value) -> Result<Self, ShellError> {
+        std::convert::TryFrom::try_from(value)
+    }
+}
+
+impl FromValue for u64 {
+    fn from_value(value: &Value) -> Result<Self, ShellError> {
+        value.as_u64()
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: &Value) -> Result<Self, ShellError> {
+        value.as_bool()
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: &Value) -> Result<Self, ShellError> {
+        std::convert::TryFrom::try_from(value)
+    }
+}
+
+impl FromValue for std::path::PathBuf {
+    fn from_value(value: &Value) -> Result<Self, ShellError> {
+        value.as_path()
+    }
+}
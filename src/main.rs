@@ -3,8 +3,7 @@ use log::LevelFilter;
 use nu_cli::create_default_context;
 use nu_cli::utils::test_bins as binaries;
 use std::error::Error;
-use std::fs::File;
-use std::io::prelude::*;
+use std::path::Path;
 
 fn main() -> Result<(), Box<dyn Error>> {
     let matches = App::new("nushell")
@@ -138,12 +137,8 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     match matches.value_of("script") {
         Some(script) => {
-            let mut file = File::open(script)?;
-            let mut buffer = String::new();
-            file.read_to_string(&mut buffer)?;
-
-            futures::executor::block_on(nu_cli::run_script_file(
-                buffer,
+            futures::executor::block_on(nu_cli::run_script_path(
+                Path::new(script),
                 matches.is_present("stdin"),
             ))?;
             return Ok(());